@@ -0,0 +1,391 @@
+use crate::api_keys::{
+    ApiKey, ApiKeyError, ApiKeyInfo, ApiKeyKey, ApiKeyRequest, Caller, NewApiKey, Scope,
+    SealingKeyError, SignatureError, SignedRequest, UserApiKeyEntry, UserApiKeyKey,
+};
+use crate::prelude::*;
+use crate::utils::now_in_secs;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::{digest, hmac};
+use sled::{abort, ConflictableTransactionResult, TransactionError, Transactional};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+/// Most seconds between the timestamp of a signed request and its arrival,
+/// either way. Signatures are remembered that long to refuse replays.
+pub const REPLAY_WINDOW_SECS: u64 = 30;
+
+#[derive(Clone)]
+pub struct ApiKeyHandler {
+    keys_db: sled::Tree,
+    user_keys_db: sled::Tree,
+    /// Signatures accepted within the replay window, with their timestamp.
+    seen: Arc<Mutex<HashMap<Vec<u8>, u64>>>,
+    /// Encrypts the secrets, derived from the configured key.
+    sealing_key: Arc<LessSafeKey>,
+}
+
+impl ApiKeyHandler {
+    /// Without `encryption_key` a random one is used, which only works as
+    /// long as there are no keys sealed by an earlier run.
+    pub fn new(db: sled::Db, encryption_key: Option<&str>) -> Result<Self, SealingKeyError> {
+        let key = match encryption_key {
+            Some(key) => digest::digest(&digest::SHA256, key.as_bytes())
+                .as_ref()
+                .to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        let key = UnboundKey::new(&aead::AES_256_GCM, &key).unwrap();
+        let handler = Self {
+            keys_db: db.open_tree(<ApiKeyKey as KeyOf>::PREFIX).unwrap(),
+            user_keys_db: db.open_tree(<UserApiKeyKey as KeyOf>::PREFIX).unwrap(),
+            seen: Arc::new(Mutex::new(HashMap::new())),
+            sealing_key: Arc::new(LessSafeKey::new(key)),
+        };
+        // Every key is sealed with the same one, the first tells.
+        if let Some(entry) = handler.keys_db.iter().next() {
+            if encryption_key.is_none() {
+                return Err(SealingKeyError::Missing);
+            }
+            let (id, key) = entry.unwrap();
+            let key = ApiKey::try_from(key).unwrap();
+            let id = std::str::from_utf8(&id).unwrap();
+            if handler.open(id, &key.sealed_secret).is_none() {
+                return Err(SealingKeyError::Mismatch);
+            }
+        }
+        Ok(handler)
+    }
+
+    /// Creates a key of `owner` from a session, which passed a second
+    /// factor if `two_factor`.
+    pub async fn create(
+        &self,
+        owner: &str,
+        request: ApiKeyRequest,
+        two_factor: bool,
+    ) -> Result<NewApiKey, ApiKeyError> {
+        if request.scopes.is_empty() {
+            return Err(ApiKeyError::NoScopes);
+        }
+        if request.scopes.contains(&Scope::Withdraw) && !two_factor {
+            return Err(ApiKeyError::TwoFactorRequired);
+        }
+        let id = format!("{:032x}", rand::random::<u128>());
+        let secret = format!(
+            "{:032x}{:032x}",
+            rand::random::<u128>(),
+            rand::random::<u128>()
+        );
+        let created = now_in_secs();
+        let key = ApiKey {
+            owner: owner.to_string(),
+            sealed_secret: self.seal(&id, secret.as_bytes()),
+            label: request.label.clone(),
+            scopes: request.scopes.clone(),
+            created,
+            revoked: false,
+        };
+
+        (&self.keys_db, &self.user_keys_db)
+            .transaction(
+                |(keys, user_keys)| -> ConflictableTransactionResult<(), ()> {
+                    keys.insert_typed(&ApiKeyKey(&id), key.clone())?;
+                    user_keys.insert_typed(
+                        &UserApiKeyKey::new(owner, &id),
+                        UserApiKeyEntry { created },
+                    )?;
+                    Ok(())
+                },
+            )
+            .unwrap();
+        self.keys_db.flush_async().await.unwrap();
+
+        Ok(NewApiKey {
+            id,
+            secret,
+            label: request.label,
+            scopes: request.scopes,
+        })
+    }
+
+    /// Keys of `owner`, revoked ones included.
+    pub fn list(&self, owner: &str) -> sled::Result<Vec<ApiKeyInfo>> {
+        let prefix = user_prefix(owner);
+        let mut keys = Vec::new();
+        for entry in self.user_keys_db.scan_prefix(&prefix).keys() {
+            let index = entry?;
+            let id = String::from_utf8_lossy(&index[prefix.len()..]).into_owned();
+            if let Some(key) = self.keys_db.get_typed(&ApiKeyKey(&id))? {
+                keys.push(ApiKeyInfo::new(id, key));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Whether `owner` has an unrevoked key allowing `scope`.
+    pub fn has_scope(&self, owner: &str, scope: Scope) -> sled::Result<bool> {
+        let keys = self.list(owner)?;
+        Ok(keys
+            .iter()
+            .any(|key| !key.revoked && key.scopes.contains(&scope)))
+    }
+
+    /// Revokes a key of `owner`, it stays listed.
+    pub async fn revoke(&self, owner: &str, id: &str) -> Result<(), ApiKeyError> {
+        let result = self.keys_db.transaction(|keys| {
+            let mut key: ApiKey = match keys.get_typed(&ApiKeyKey(id))? {
+                Some(key) if key.owner == owner => key,
+                // Keys of others don't exist for the caller.
+                _ => return abort(ApiKeyError::UnknownKey),
+            };
+            key.revoked = true;
+            keys.insert_typed(&ApiKeyKey(id), key)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.keys_db.flush_async().await.unwrap();
+                Ok(())
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Checks the signature of a request made with key `id` and that the
+    /// key allows `scope`.
+    pub fn verify(
+        &self,
+        id: &str,
+        signature: &str,
+        request: &SignedRequest,
+        scope: Scope,
+    ) -> Result<Caller, SignatureError> {
+        let key = match self.keys_db.get_typed(&ApiKeyKey(id)).unwrap() {
+            Some(key) if !key.revoked => key,
+            _ => return Err(SignatureError::InvalidKey),
+        };
+        let now = now_in_secs();
+        let age = if now > request.timestamp {
+            now - request.timestamp
+        } else {
+            request.timestamp - now
+        };
+        if age > REPLAY_WINDOW_SECS {
+            return Err(SignatureError::StaleTimestamp);
+        }
+        let secret = self
+            .open(id, &key.sealed_secret)
+            .ok_or(SignatureError::InvalidKey)?;
+        let signature = from_hex(signature).ok_or(SignatureError::InvalidSignature)?;
+        let signing_key = hmac::Key::new(hmac::HMAC_SHA256, &secret);
+        hmac::verify(&signing_key, &request.message(), &signature)
+            .map_err(|_| SignatureError::InvalidSignature)?;
+        if !key.scopes.contains(&scope) {
+            return Err(SignatureError::MissingScope);
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, timestamp| *timestamp + REPLAY_WINDOW_SECS >= now);
+        if seen.insert(signature, request.timestamp).is_some() {
+            return Err(SignatureError::Replayed);
+        }
+        Ok(Caller::ApiKey {
+            id: id.to_string(),
+            owner: key.owner,
+        })
+    }
+
+    /// Encrypts the secret of key `id` under a random nonce, which leads
+    /// the result. The id is authenticated along, so secrets can't be
+    /// swapped between keys.
+    fn seal(&self, id: &str, secret: &[u8]) -> Vec<u8> {
+        let nonce = rand::random::<[u8; aead::NONCE_LEN]>();
+        let mut sealed = secret.to_vec();
+        self.sealing_key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(id.as_bytes()),
+                &mut sealed,
+            )
+            .unwrap();
+        let mut result = nonce.to_vec();
+        result.extend_from_slice(&sealed);
+        result
+    }
+
+    /// Secret of key `id` sealed by [`seal`](Self::seal), none when it was
+    /// sealed with another key of the server or for another id.
+    fn open(&self, id: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < aead::NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut in_out = ciphertext.to_vec();
+        let secret = self
+            .sealing_key
+            .open_in_place(nonce, Aad::from(id.as_bytes()), &mut in_out)
+            .ok()?;
+        Some(secret.to_vec())
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"{\"price\":1}";
+
+    fn key_handler(db: &sled::Db, encryption_key: &str) -> ApiKeyHandler {
+        ApiKeyHandler::new(db.clone(), Some(encryption_key)).unwrap()
+    }
+
+    async fn new_key(handler: &ApiKeyHandler, scopes: Vec<Scope>) -> NewApiKey {
+        let request = ApiKeyRequest {
+            label: None,
+            scopes,
+        };
+        handler.create("alice", request, true).await.unwrap()
+    }
+
+    fn signed(timestamp: u64) -> SignedRequest<'static> {
+        SignedRequest {
+            timestamp,
+            method: "POST",
+            path: "/offers",
+            body: BODY,
+        }
+    }
+
+    fn sign(secret: &str, request: &SignedRequest) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        hmac::sign(&key, &request.message())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn signatures() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = key_handler(&db, "server key");
+        let key = new_key(&handler, vec![Scope::Trade]).await;
+        let request = signed(now_in_secs());
+        let signature = sign(&key.secret, &request);
+
+        let caller = handler.verify(&key.id, &signature, &request, Scope::Trade);
+        assert_eq!(caller.unwrap().user_id(), "alice");
+        assert_eq!(
+            handler
+                .verify(&key.id, &signature, &request, Scope::Trade)
+                .err(),
+            Some(SignatureError::Replayed)
+        );
+
+        let request = signed(now_in_secs() + 1);
+        let forged = sign("another secret", &request);
+        assert_eq!(
+            handler
+                .verify(&key.id, &forged, &request, Scope::Trade)
+                .err(),
+            Some(SignatureError::InvalidSignature)
+        );
+        assert_eq!(
+            handler
+                .verify(&key.id, "not hex", &request, Scope::Trade)
+                .err(),
+            Some(SignatureError::InvalidSignature)
+        );
+        let signature = sign(&key.secret, &request);
+        assert_eq!(
+            handler
+                .verify(&key.id, &signature, &request, Scope::Withdraw)
+                .err(),
+            Some(SignatureError::MissingScope)
+        );
+
+        let stale = signed(now_in_secs() - REPLAY_WINDOW_SECS - 1);
+        let signature = sign(&key.secret, &stale);
+        assert_eq!(
+            handler
+                .verify(&key.id, &signature, &stale, Scope::Trade)
+                .err(),
+            Some(SignatureError::StaleTimestamp)
+        );
+    }
+
+    #[tokio::test]
+    async fn secrets_are_sealed() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = key_handler(&db, "server key");
+        let other = key_handler(&db, "another server key");
+        let key = new_key(&handler, vec![Scope::Read]).await;
+        let stored: ApiKey = handler
+            .keys_db
+            .get_typed(&ApiKeyKey(&key.id))
+            .unwrap()
+            .unwrap();
+        assert!(!stored
+            .sealed_secret
+            .windows(key.secret.len())
+            .any(|window| window == key.secret.as_bytes()));
+
+        let request = signed(now_in_secs());
+        let signature = sign(&key.secret, &request);
+        assert_eq!(
+            other
+                .verify(&key.id, &signature, &request, Scope::Read)
+                .err(),
+            Some(SignatureError::InvalidKey)
+        );
+        assert!(handler
+            .verify(&key.id, &signature, &request, Scope::Read)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn sealed_keys_need_their_key() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = ApiKeyHandler::new(db.clone(), None).unwrap();
+        new_key(&handler, vec![Scope::Read]).await;
+        assert_eq!(
+            ApiKeyHandler::new(db.clone(), None).err(),
+            Some(SealingKeyError::Missing)
+        );
+
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        new_key(&key_handler(&db, "server key"), vec![Scope::Read]).await;
+        assert_eq!(
+            ApiKeyHandler::new(db.clone(), Some("another server key")).err(),
+            Some(SealingKeyError::Mismatch)
+        );
+        assert!(ApiKeyHandler::new(db, Some("server key")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn withdraw_keys_need_two_factor() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = key_handler(&db, "server key");
+        let request = ApiKeyRequest {
+            label: None,
+            scopes: vec![Scope::Read, Scope::Withdraw],
+        };
+        let created = handler.create("alice", request.clone(), false).await;
+        assert_eq!(created.err(), Some(ApiKeyError::TwoFactorRequired));
+        assert!(handler.list("alice").unwrap().is_empty());
+        assert!(handler.create("alice", request, true).await.is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+mod handler;
+mod model;
+
+use crate::{
+    auth::{self, AuthorizeError},
+    utils::{bytes_body, json_body},
+    with_client_ip, with_ctx, ClientIp, Ctx,
+};
+use std::convert::Infallible;
+use warp::{
+    filters::path::FullPath,
+    http::{Method, StatusCode},
+    reply, Filter, Rejection, Reply,
+};
+
+pub use handler::{ApiKeyHandler, REPLAY_WINDOW_SECS};
+pub use model::{
+    ApiKey, ApiKeyError, ApiKeyInfo, ApiKeyKey, ApiKeyRequest, Caller, NewApiKey, Scope,
+    SealingKeyError, SignatureError, SignedRequest, UserApiKeyEntry, UserApiKeyKey,
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Seconds since the epoch.
+pub const API_TIMESTAMP_HEADER: &str = "x-api-timestamp";
+/// Hex encoded HMAC-SHA256 of [`SignedRequest::message`].
+pub const API_SIGNATURE_HEADER: &str = "x-api-signature";
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    create_key(ctx.clone())
+        .or(list_keys(ctx.clone()))
+        .or(revoke_key(ctx))
+}
+
+/// Who sent the request, by API key signature or session cookie, and its
+/// body of at most `kb_limit` KB. Keys must allow `scope`, sessions allow
+/// everything.
+pub fn with_caller(
+    ctx: Ctx,
+    scope: Scope,
+    kb_limit: u64,
+) -> impl Filter<Extract = (Result<Caller, AuthorizeError>, bytes::Bytes), Error = Rejection> + Clone
+{
+    caller(ctx, scope, bytes_body(kb_limit))
+}
+
+/// [`with_caller`] of a request without a body, such as a `GET`.
+pub fn with_bodyless_caller(
+    ctx: Ctx,
+    scope: Scope,
+) -> impl Filter<Extract = (Result<Caller, AuthorizeError>,), Error = Rejection> + Clone {
+    caller(ctx, scope, warp::any().map(bytes::Bytes::new))
+        .map(|caller: Result<Caller, AuthorizeError>, _: bytes::Bytes| caller)
+}
+
+fn caller<B>(
+    ctx: Ctx,
+    scope: Scope,
+    body: B,
+) -> impl Filter<Extract = (Result<Caller, AuthorizeError>, bytes::Bytes), Error = Rejection> + Clone
+where
+    B: Filter<Extract = (bytes::Bytes,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    let signed = warp::header::<String>(API_KEY_HEADER)
+        .and(warp::header::<u64>(API_TIMESTAMP_HEADER))
+        .and(warp::header::<String>(API_SIGNATURE_HEADER))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(body.clone())
+        .and(with_ctx(ctx.clone()))
+        .map(
+            move |id: String,
+                  timestamp: u64,
+                  signature: String,
+                  method: Method,
+                  path: FullPath,
+                  query: String,
+                  body: bytes::Bytes,
+                  ctx: Ctx| {
+                let path = if query.is_empty() {
+                    path.as_str().to_string()
+                } else {
+                    format!("{}?{}", path.as_str(), query)
+                };
+                let request = SignedRequest {
+                    timestamp,
+                    method: method.as_str(),
+                    path: &path,
+                    body: &body,
+                };
+                let caller = ctx
+                    .api_key_handler
+                    .verify(&id, &signature, &request, scope)
+                    .map_err(AuthorizeError::from);
+                (caller, body)
+            },
+        )
+        .untuple_one();
+    let session = warp::cookie(auth::JWT_COOKIE_NAME)
+        .and(with_client_ip(ctx.clone()))
+        .and(body)
+        .and(with_ctx(ctx))
+        .map(
+            |cookie: String, ip: ClientIp, body: bytes::Bytes, ctx: Ctx| {
+                let caller = ctx
+                    .auth_manager
+                    .authorize(&ip.ip, &cookie)
+                    .map(Caller::Session);
+                (caller, body)
+            },
+        )
+        .untuple_one();
+    signed.or(session).unify()
+}
+
+fn create_key(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("api_keys")
+        .and(warp::post())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<ApiKeyRequest>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        request: ApiKeyRequest,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                let key = ctx
+                    .api_key_handler
+                    .create(&claims.user_id, request, claims.two_factor)
+                    .await;
+                Ok(match key {
+                    Ok(key) => Box::new(reply::with_status(reply::json(&key), StatusCode::CREATED)),
+                    Err(e @ ApiKeyError::TwoFactorRequired) => {
+                        Box::new(reply::with_status(reply::json(&e), StatusCode::FORBIDDEN))
+                    }
+                    Err(e) => {
+                        Box::new(reply::with_status(reply::json(&e), StatusCode::BAD_REQUEST))
+                    }
+                })
+            },
+        )
+}
+
+fn list_keys(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("api_keys")
+        .and(warp::get())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                let keys = ctx.api_key_handler.list(&claims.user_id).unwrap();
+                Ok(Box::new(reply::json(&keys)))
+            },
+        )
+}
+
+fn revoke_key(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("api_keys" / String)
+        .and(warp::delete())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |id: String,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                Ok(
+                    match ctx.api_key_handler.revoke(&claims.user_id, &id).await {
+                        Ok(()) => Box::new(StatusCode::NO_CONTENT),
+                        Err(e) => {
+                            Box::new(reply::with_status(reply::json(&e), StatusCode::NOT_FOUND))
+                        }
+                    },
+                )
+            },
+        )
+}
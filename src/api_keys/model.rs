@@ -0,0 +1,183 @@
+use crate::{
+    auth::{AuthorizeError, Claims},
+    bincode_des, bincode_ser, derive_key_of,
+    typed_tree::{user_key, KeyOf},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct ApiKeyKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for ApiKeyKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Key of a program acting for a user. Its secret is kept encrypted with the
+/// key of the server, so the database alone can't sign requests.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct ApiKey {
+    pub owner: String,
+    /// Nonce followed by the AES-256-GCM ciphertext of the secret.
+    pub sealed_secret: Vec<u8>,
+    pub label: Option<String>,
+    pub scopes: Vec<Scope>,
+    /// Seconds since the epoch.
+    pub created: u64,
+    pub revoked: bool,
+}
+
+/// Index of the API keys of a user: user id, a zero byte and the key id.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserApiKeyKey(Vec<u8>);
+
+impl UserApiKeyKey {
+    pub fn new(user: &str, id: &str) -> Self {
+        UserApiKeyKey(user_key(user, id.as_bytes()))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for UserApiKeyKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserApiKeyEntry {
+    pub created: u64,
+}
+
+/// What an API key may be used for.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Scope {
+    Trade,
+    Read,
+    Withdraw,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKeyRequest {
+    pub label: Option<String>,
+    pub scopes: Vec<Scope>,
+}
+
+/// A created key, the only time its secret is shown. Requests are signed
+/// with HMAC-SHA256 keyed by the secret.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewApiKey {
+    pub id: String,
+    pub secret: String,
+    pub label: Option<String>,
+    pub scopes: Vec<Scope>,
+}
+
+/// An API key as listed to its owner.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub label: Option<String>,
+    pub scopes: Vec<Scope>,
+    pub created: u64,
+    pub revoked: bool,
+}
+
+impl ApiKeyInfo {
+    pub fn new(id: String, key: ApiKey) -> Self {
+        ApiKeyInfo {
+            id,
+            label: key.label,
+            scopes: key.scopes,
+            created: key.created,
+            revoked: key.revoked,
+        }
+    }
+}
+
+/// Parts of a request covered by its signature.
+pub struct SignedRequest<'a> {
+    pub timestamp: u64,
+    pub method: &'a str,
+    /// Path and query, as sent.
+    pub path: &'a str,
+    pub body: &'a [u8],
+}
+
+impl<'a> SignedRequest<'a> {
+    /// `timestamp`, method and path on their own lines, then the body.
+    pub fn message(&self) -> Vec<u8> {
+        let mut message =
+            format!("{}\n{}\n{}\n", self.timestamp, self.method, self.path).into_bytes();
+        message.extend_from_slice(self.body);
+        message
+    }
+}
+
+/// Who a request is from.
+#[derive(Debug)]
+pub enum Caller {
+    Session(Claims),
+    ApiKey { id: String, owner: String },
+}
+
+impl Caller {
+    pub fn user_id(&self) -> &str {
+        match self {
+            Caller::Session(claims) => &claims.user_id,
+            Caller::ApiKey { owner, .. } => owner,
+        }
+    }
+
+    /// Session whose orders are cancelled when it ends.
+    pub fn cancelling_session(&self) -> Option<&str> {
+        match self {
+            Caller::Session(claims) if claims.cancel_on_disconnect => Some(&claims.session),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum ApiKeyError {
+    UnknownKey,
+    NoScopes,
+    /// Keys allowed to withdraw are only created from sessions that passed
+    /// a second factor.
+    TwoFactorRequired,
+}
+
+/// Why the stored API keys can't be opened with the configured key.
+#[derive(Debug, PartialEq)]
+pub enum SealingKeyError {
+    /// There are keys but no key to open them.
+    Missing,
+    /// The keys were sealed with another one.
+    Mismatch,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum SignatureError {
+    /// Unknown or revoked.
+    InvalidKey,
+    /// Too far from the time of the server.
+    StaleTimestamp,
+    InvalidSignature,
+    /// A request already seen within the replay window.
+    Replayed,
+    MissingScope,
+}
+
+impl From<SignatureError> for AuthorizeError {
+    fn from(err: SignatureError) -> Self {
+        match err {
+            SignatureError::MissingScope => AuthorizeError::Forbidden,
+            _ => AuthorizeError::InvalidToken,
+        }
+    }
+}
+
+derive_key_of!(ApiKeyKey<'_>, ApiKey, "ApiKey", 24);
+derive_key_of!(UserApiKeyKey, UserApiKeyEntry, "UserApiKey", 25);
@@ -1,103 +1,397 @@
-use jsonwebtoken::{self as jwt, DecodingKey, EncodingKey};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use ring::{constant_time, digest};
 use serde::{Deserialize, Serialize};
+use sled::{abort, ConflictableTransactionResult, TransactionError, Transactional};
+use std::convert::TryFrom;
+use std::sync::Mutex;
 use std::time;
-use warp::{http::header, Reply};
+use warp::Reply;
 
 use crate::auth::{
-    AuthenticateError, AuthorizeError, SignUpError, DELETE_JWT_COOKIE, JWT_COOKIE_NAME,
+    keys::{KeyError, KeyRing},
+    password::{self, PasswordViolation},
+    totp, with_cookies, AuthenticateError, AuthorizeError, Credentials, PasswordChange,
+    PasswordError, PasswordResetBody, RefreshError, ResetToken, RoleError, SessionOptions,
+    SignUpError, TwoFactorChallenge, TwoFactorEnrollment, TwoFactorError, DELETE_JWT_COOKIE,
+    DELETE_REFRESH_COOKIE, JWT_COOKIE_NAME, REFRESH_COOKIE_NAME,
 };
+use crate::config::{Config, LoginThrottle, PasswordPolicy};
 use crate::prelude::*;
-use crate::typed_tree::{KeyOf, TypedTree};
-use crate::user::{BlackListed, BlackListedKey, User, UserKey};
+use crate::typed_tree::{valid_user_id, KeyOf, TypedTree};
+use crate::user::{
+    BlackListed, BlackListedKey, LoginAttempts, LoginAttemptsKey, LoginChallenge,
+    LoginChallengeKey, PasswordReset, PasswordResetKey, RefreshToken, RefreshTokenKey, Role,
+    TokenFamily, TokenFamilyKey, TokenGeneration, TokenGenerationKey, TwoFactor, TwoFactorKey,
+    User, UserKey,
+};
+use crate::utils::now_in_secs;
+
+const ACCESS_MAX_AGE_SECS: u64 = 15 * 60;
+const REFRESH_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+const RESET_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+const BCRYPT_COST: u32 = 8;
+const CHALLENGE_MAX_AGE_SECS: u64 = 5 * 60;
+/// Wrong codes after which a login must start over with the password.
+const MAX_CHALLENGE_FAILURES: u32 = 5;
+const RECOVERY_CODES: usize = 10;
 
-const SECRET: &'static str = "secret";
+/// `Set-Cookie` values of the tokens of a session.
+pub struct SessionCookies {
+    pub access: String,
+    pub refresh: String,
+}
+
+/// What a right password leads to.
+pub enum LoginStep {
+    Session(SessionCookies),
+    /// The user has a second factor, see [`AuthManager::verify_login`].
+    TwoFactor(TwoFactorChallenge),
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Claims {
-    exp: u64, // seconds
-    user_id: String,
-    ip: String,
+    pub exp: u64, // seconds
+    pub user_id: String,
+    pub ip: String,
+    /// Random id of the login that issued the token.
+    #[serde(default)]
+    pub session: String,
+    #[serde(default)]
+    pub cancel_on_disconnect: bool,
+    /// Random id of the token, blacklisted on logout.
+    pub jti: String,
+    /// Token generation of the user when the session started.
+    pub generation: u64,
+    pub role: Role,
+    /// Whether the login passed a second factor.
+    pub two_factor: bool,
+}
+
+/// Session whose tokens expired while it asked to cancel its orders on
+/// disconnect.
+pub struct ExpiredSession {
+    pub user_id: String,
+    pub session: String,
 }
 
 pub struct AuthManager {
     user_db: sled::Tree,
     blacklist_db: sled::Tree,
-    jwt_encoding_key: EncodingKey,
-    jwt_decoding_key: DecodingKey<'static>,
-    jwt_validation: jwt::Validation,
-    jwt_header: jwt::Header,
+    refresh_db: sled::Tree,
+    families_db: sled::Tree,
+    generations_db: sled::Tree,
+    attempts_db: sled::Tree,
+    resets_db: sled::Tree,
+    two_factor_db: sled::Tree,
+    challenges_db: sled::Tree,
+    throttle: Option<LoginThrottle>,
+    password_policy: PasswordPolicy,
+    keys: KeyRing,
+    admins: Vec<String>,
+    operators: Vec<String>,
+    expired_sessions: Mutex<Option<UnboundedReceiver<ExpiredSession>>>,
     _blacklist_interval_handle: tokio::task::JoinHandle<()>,
 }
 
 impl AuthManager {
-    pub fn new(db: sled::Db, jwt_validation: jwt::Validation) -> Self {
-        let algorithm = jwt_validation.algorithms[0].clone();
+    pub fn new(db: sled::Db, config: &Config) -> Result<Self, KeyError> {
+        let keys = KeyRing::load(&config.jwt_keys)?;
         let blacklist_db = db.open_tree(<BlackListedKey as KeyOf>::PREFIX).unwrap();
+        let refresh_db = db.open_tree(<RefreshTokenKey as KeyOf>::PREFIX).unwrap();
+        let families_db = db.open_tree(<TokenFamilyKey as KeyOf>::PREFIX).unwrap();
+        let attempts_db = db.open_tree(<LoginAttemptsKey as KeyOf>::PREFIX).unwrap();
+        let resets_db = db.open_tree(<PasswordResetKey as KeyOf>::PREFIX).unwrap();
+        let challenges_db = db.open_tree(<LoginChallengeKey as KeyOf>::PREFIX).unwrap();
 
+        let (expired_sender, expired_sessions) = unbounded();
         let mut interval = tokio::time::interval(time::Duration::new(60 * 5, 0)); // 5 min
-        let interval_db = blacklist_db.clone();
+        let interval_dbs = (
+            blacklist_db.clone(),
+            refresh_db.clone(),
+            families_db.clone(),
+            attempts_db.clone(),
+            resets_db.clone(),
+            challenges_db.clone(),
+        );
         let handle = tokio::spawn(async move {
             loop {
                 interval.tick().await;
-                clear_blacklist(&interval_db).await;
+                clear_blacklist(&interval_dbs.0).await;
+                clear_expired::<RefreshToken, _>(&interval_dbs.1, |t| t.exp);
+                clear_families(&interval_dbs.2, &expired_sender);
+                clear_expired::<LoginAttempts, _>(&interval_dbs.3, |a| a.exp);
+                clear_expired::<PasswordReset, _>(&interval_dbs.4, |r| r.exp);
+                clear_expired::<LoginChallenge, _>(&interval_dbs.5, |c| c.exp);
             }
         });
 
-        AuthManager {
+        Ok(AuthManager {
             user_db: db.open_tree(<UserKey as KeyOf>::PREFIX).unwrap(),
             blacklist_db: blacklist_db.clone(),
-            jwt_encoding_key: EncodingKey::from_secret(SECRET.as_ref()),
-            jwt_decoding_key: DecodingKey::from_secret(SECRET.as_ref()),
-            jwt_validation,
-            jwt_header: jwt::Header::new(algorithm),
+            refresh_db,
+            families_db,
+            generations_db: db.open_tree(<TokenGenerationKey as KeyOf>::PREFIX).unwrap(),
+            attempts_db,
+            resets_db,
+            two_factor_db: db.open_tree(<TwoFactorKey as KeyOf>::PREFIX).unwrap(),
+            challenges_db,
+            throttle: config.login_throttle.clone(),
+            password_policy: config.password_policy.clone(),
+            keys,
+            admins: config.admins.clone(),
+            operators: config.operators.clone(),
+            expired_sessions: Mutex::new(Some(expired_sessions)),
             _blacklist_interval_handle: handle,
-        }
+        })
     }
 
-    pub fn authenticate(&self, user: &User, ip: &str) -> Result<String, AuthenticateError> {
-        let pers_user = self.user_db.get_typed(&user.key()).unwrap();
-        if let Some(pers_user) = pers_user {
+    pub fn authenticate(
+        &self,
+        user: &Credentials,
+        ip: &str,
+        options: &SessionOptions,
+    ) -> Result<LoginStep, AuthenticateError> {
+        let user_key = LoginAttemptsKey::user(&user.id);
+        let ip_key = LoginAttemptsKey::ip(ip);
+        let now = now_in_secs();
+        // Before bcrypt, locked out attempts must not cost anything.
+        let previous = self
+            .reserve_attempt(&user_key, &ip_key, now)
+            .map_err(|retry_after| AuthenticateError::Locked { retry_after })?;
+
+        let pers_user = self.user_db.get_typed(&UserKey(&user.id)).unwrap();
+        let result = if let Some(pers_user) = pers_user {
             if bcrypt::verify(&user.password, &pers_user.password).unwrap() {
-                Ok(self.make_cookie(pers_user.id, ip.to_string()))
+                let role = pers_user.role.max(self.configured_role(&pers_user.id));
+                if self.has_two_factor(&pers_user.id) {
+                    // Failures are forgotten once the code is right too.
+                    self.release_attempt(&user_key, &ip_key, previous);
+                    let challenge = self.challenge(pers_user.id, role, ip, options);
+                    return Ok(LoginStep::TwoFactor(challenge));
+                }
+                Ok(LoginStep::Session(self.start_session(
+                    pers_user.id,
+                    role,
+                    ip.to_string(),
+                    options,
+                    false,
+                )))
             } else {
                 Err(AuthenticateError::IncorrectCombination)
             }
         } else {
             Err(AuthenticateError::UserDoesNotExist)
+        };
+        // Failures of the IP are kept, a login of its own must not let an
+        // attacker start over.
+        if result.is_ok() {
+            self.release_attempt(&user_key, &ip_key, None);
         }
+        result
     }
 
-    pub fn authorize(&self, ip: &str, cookie: &str) -> Result<(), AuthorizeError> {
-        let data = jwt::decode::<Claims>(cookie, &self.jwt_decoding_key, &self.jwt_validation);
-        if let Ok(data) = data {
-            if data.claims.ip == ip {
-                if self.is_in_blacklist(cookie) {
-                    Err(AuthorizeError::BlackListedToken)
-                } else {
-                    Ok(())
+    /// Second step of a login with a second factor, its session starts if
+    /// `code` is a current code or an unused recovery code of the user.
+    pub async fn verify_login(
+        &self,
+        challenge: &str,
+        code: &str,
+        ip: &str,
+    ) -> Result<SessionCookies, AuthenticateError> {
+        let key = LoginChallengeKey(challenge);
+        let now = now_in_secs();
+        let mut pending = match self.challenges_db.get_typed(&key).unwrap() {
+            Some(pending) if pending.exp > now && pending.ip == ip => pending,
+            _ => return Err(AuthenticateError::InvalidChallenge),
+        };
+        let user_key = LoginAttemptsKey::user(&pending.user_id);
+        let ip_key = LoginAttemptsKey::ip(ip);
+        self.reserve_attempt(&user_key, &ip_key, now)
+            .map_err(|retry_after| AuthenticateError::Locked { retry_after })?;
+        // Taken before the code is checked, so it's used only once.
+        if self.challenges_db.remove(&key).unwrap().is_none() {
+            return Err(AuthenticateError::InvalidChallenge);
+        }
+
+        if self
+            .use_second_factor(&pending.user_id, code, now)
+            .await
+            .is_err()
+        {
+            pending.failures += 1;
+            if pending.failures < MAX_CHALLENGE_FAILURES {
+                self.challenges_db.insert_typed(&key, pending).unwrap();
+            }
+            return Err(AuthenticateError::IncorrectCode);
+        }
+        self.release_attempt(&user_key, &ip_key, None);
+        let options = SessionOptions {
+            cancel_on_disconnect: pending.cancel_on_disconnect,
+        };
+        // The role may have changed while the code was asked for.
+        let role = self.role(&pending.user_id);
+        Ok(self.start_session(pending.user_id, role, ip.to_string(), &options, true))
+    }
+
+    /// Counts an attempt against the user and the IP it comes from as a
+    /// failure, unless either must wait, then the seconds to wait. Checked
+    /// and counted in one transaction, so concurrent attempts can't all get
+    /// through. The failures of the user it replaced are returned, for
+    /// [`release_attempt`](Self::release_attempt) once it succeeds.
+    fn reserve_attempt(
+        &self,
+        user_key: &LoginAttemptsKey,
+        ip_key: &LoginAttemptsKey,
+        now: u64,
+    ) -> Result<Option<LoginAttempts>, u64> {
+        let throttle = match &self.throttle {
+            Some(throttle) => throttle,
+            None => return Ok(None),
+        };
+        let keys = [
+            (user_key, throttle.max_failures, true),
+            (ip_key, throttle.max_ip_failures, false),
+        ];
+        let result = self.attempts_db.transaction(|attempts_db| {
+            let mut previous = Vec::with_capacity(keys.len());
+            for (key, _, _) in keys.iter() {
+                let attempts: Option<LoginAttempts> = match attempts_db.get_typed(*key)? {
+                    Some(attempts) if attempts.exp > now => Some(attempts),
+                    _ => None,
+                };
+                previous.push(attempts);
+            }
+            let retry_after = previous
+                .iter()
+                .flatten()
+                .filter_map(|attempts| attempts.retry_after(now))
+                .max();
+            if let Some(retry_after) = retry_after {
+                return abort(retry_after);
+            }
+            for ((key, max_failures, backoff), attempts) in keys.iter().zip(&previous) {
+                let mut attempts = attempts.clone().unwrap_or_default();
+                attempts.failures += 1;
+                if attempts.failures >= *max_failures {
+                    attempts.failures = 0;
+                    attempts.locked_until = now + throttle.lockout_secs;
+                } else if *backoff {
+                    let delay = throttle.backoff_secs << (attempts.failures - 1).min(16);
+                    attempts.next_attempt = now + delay.min(throttle.lockout_secs);
                 }
+                attempts.exp = attempts.locked_until.max(now + throttle.lockout_secs);
+                attempts_db.insert_typed(*key, attempts)?;
+            }
+            Ok(previous.swap_remove(0))
+        });
+        match result {
+            Ok(previous) => Ok(previous),
+            Err(TransactionError::Abort(retry_after)) => Err(retry_after),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Takes back an attempt reserved by
+    /// [`reserve_attempt`](Self::reserve_attempt) that succeeded. The user
+    /// gets back `user`, none forgets their failures and clears their
+    /// lockout. A lockout of the IP the attempt started stays.
+    fn release_attempt(
+        &self,
+        user_key: &LoginAttemptsKey,
+        ip_key: &LoginAttemptsKey,
+        user: Option<LoginAttempts>,
+    ) {
+        if self.throttle.is_none() {
+            return;
+        }
+        self.attempts_db
+            .transaction(|attempts_db| -> ConflictableTransactionResult<(), ()> {
+                match &user {
+                    Some(attempts) => {
+                        attempts_db.insert_typed(user_key, attempts.clone())?;
+                    }
+                    None => {
+                        attempts_db.remove(user_key.as_ref())?;
+                    }
+                }
+                let ip_attempts: Option<LoginAttempts> = attempts_db.get_typed(ip_key)?;
+                if let Some(mut attempts) = ip_attempts {
+                    if attempts.failures > 0 {
+                        attempts.failures -= 1;
+                        attempts_db.insert_typed(ip_key, attempts)?;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    pub fn authorize(&self, ip: &str, cookie: &str) -> Result<Claims, AuthorizeError> {
+        let claims = self.claims(cookie)?;
+        if claims.ip == ip {
+            Ok(claims)
+        } else {
+            Err(AuthorizeError::DifferentIp)
+        }
+    }
+
+    /// Claims of a valid token, wherever it is used from.
+    pub fn claims(&self, cookie: &str) -> Result<Claims, AuthorizeError> {
+        if let Some(claims) = self.keys.decode::<Claims>(cookie) {
+            if self.is_in_blacklist(&claims.jti)
+                || self.is_revoked(&claims.session)
+                || claims.generation < self.generation(&claims.user_id)
+            {
+                Err(AuthorizeError::BlackListedToken)
             } else {
-                Err(AuthorizeError::DifferentIp)
+                Ok(claims)
             }
         } else {
             Err(AuthorizeError::InvalidToken)
         }
     }
 
+    pub fn authorize_admin(&self, ip: &str, cookie: &str) -> Result<Claims, AuthorizeError> {
+        self.authorize_role(ip, cookie, Role::Admin)
+    }
+
+    pub fn authorize_role(
+        &self,
+        ip: &str,
+        cookie: &str,
+        role: Role,
+    ) -> Result<Claims, AuthorizeError> {
+        let claims = self.authorize(ip, cookie)?;
+        if claims.role >= role {
+            Ok(claims)
+        } else {
+            Err(AuthorizeError::Forbidden)
+        }
+    }
+
     pub async fn signup<R>(
         &self,
         reply: R,
-        mut user: User,
+        credentials: Credentials,
         ip: &str,
     ) -> Result<impl warp::Reply, SignUpError>
     where
         R: warp::Reply,
     {
+        if !valid_user_id(&credentials.id) {
+            return Err(SignUpError::InvalidUserId);
+        }
+        self.check_password(&credentials.id, &credentials.password)
+            .map_err(SignUpError::WeakPassword)?;
         let mut start = time::Instant::now();
-        let hashed_pasword = bcrypt::hash(&user.password, 8).unwrap();
+        let hashed_pasword = bcrypt::hash(&credentials.password, BCRYPT_COST).unwrap();
         println!("bcrypt: {:?}", start.elapsed());
 
-        user.password = hashed_pasword;
+        let user = User {
+            id: credentials.id,
+            password: hashed_pasword,
+            role: Role::Trader,
+        };
         let user_id = user.id.clone();
 
         start = time::Instant::now();
@@ -116,69 +410,603 @@ impl AuthManager {
                 start = time::Instant::now();
                 self.user_db.flush_async().await.unwrap();
                 println!("flush: {:?}", start.elapsed());
-                Ok(self.set_cookie(reply, user_id, ip.to_string()))
+                let role = self.configured_role(&user_id);
+                let cookies = self.start_session(
+                    user_id,
+                    role,
+                    ip.to_string(),
+                    &SessionOptions::default(),
+                    false,
+                );
+                Ok(with_cookies(
+                    reply,
+                    &[cookies.access.as_str(), cookies.refresh.as_str()],
+                ))
             }
             Err(_) => Err(SignUpError::UserAlreadyCreated),
         }
     }
 
-    pub async fn logout<R>(&self, reply: R, cookie: &str) -> warp::reply::WithHeader<R>
+    pub async fn logout<R>(&self, reply: R, cookie: &str) -> warp::reply::Response
     where
         R: Reply,
     {
-        let data = jwt::decode::<Claims>(cookie, &self.jwt_decoding_key, &self.jwt_validation);
-        if let Ok(data) = data {
-            let key = BlackListedKey(cookie);
-            let value = BlackListed {
-                exp: data.claims.exp,
-            };
+        if let Some(claims) = self.keys.decode::<Claims>(cookie) {
+            let key = BlackListedKey(&claims.jti);
+            let value = BlackListed { exp: claims.exp };
             self.blacklist_db.insert_typed(&key, value).unwrap();
             self.blacklist_db.flush_async().await.unwrap();
+            self.revoke_family(&claims.session).await;
             self.remove_cookie(reply)
         } else {
             self.remove_cookie(reply)
         }
     }
 
+    /// Ends every session of a user, their tokens are refused from now on.
+    pub async fn logout_all(&self, user_id: &str) {
+        let key = TokenGenerationKey(user_id);
+        self.generations_db
+            .transaction(|generations| -> ConflictableTransactionResult<(), ()> {
+                let mut current: TokenGeneration = generations.get_typed(&key)?.unwrap_or_default();
+                current.generation += 1;
+                generations.insert_typed(&key, current)?;
+                Ok(())
+            })
+            .unwrap();
+        self.generations_db.flush_async().await.unwrap();
+    }
+
+    /// Exchanges a refresh token for new access and refresh tokens of the
+    /// same session. A token used twice was stolen from one of the parties,
+    /// so its whole family is revoked.
+    pub async fn refresh(&self, token: &str, ip: &str) -> Result<SessionCookies, RefreshError> {
+        let now = now_in_secs();
+        let next = random_token();
+        let trees = (&self.refresh_db, &self.families_db, &self.generations_db);
+        let token_key = RefreshTokenKey::new(token);
+        let next_key = RefreshTokenKey::new(&next);
+        let result = trees.transaction(|(tokens, families, generations)| {
+            let mut refresh: RefreshToken = match tokens.get_typed(&token_key)? {
+                Some(refresh) if refresh.exp > now => refresh,
+                _ => return abort(RefreshError::InvalidToken),
+            };
+            let family_key = TokenFamilyKey(&refresh.family);
+            let mut family: TokenFamily = match families.get_typed(&family_key)? {
+                Some(family) if !family.revoked => family,
+                _ => return abort(RefreshError::InvalidToken),
+            };
+            let current: TokenGeneration = generations
+                .get_typed(&TokenGenerationKey(&family.user_id))?
+                .unwrap_or_default();
+            if family.generation < current.generation {
+                return abort(RefreshError::InvalidToken);
+            }
+            if refresh.used {
+                family.revoked = true;
+                families.insert_typed(&family_key, family)?;
+                // Committed, the revocation must stick.
+                return Ok(Err(RefreshError::TokenReused));
+            }
+            if family.ip != ip {
+                return abort(RefreshError::DifferentIp);
+            }
+
+            refresh.used = true;
+            tokens.insert_typed(&token_key, refresh.clone())?;
+            let rotated = RefreshToken {
+                family: refresh.family.clone(),
+                exp: now + REFRESH_MAX_AGE_SECS,
+                used: false,
+            };
+            tokens.insert_typed(&next_key, rotated)?;
+            family.exp = now + REFRESH_MAX_AGE_SECS;
+            families.insert_typed(&family_key, family.clone())?;
+            Ok(Ok((refresh.family, family)))
+        });
+        let result = match result {
+            Ok(result) => result,
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        };
+        // Either the rotation or the revocation of a reused token.
+        self.refresh_db.flush_async().await.unwrap();
+        self.families_db.flush_async().await.unwrap();
+        let (session, family) = result?;
+
+        Ok(SessionCookies {
+            access: self.access_cookie(&session, &family),
+            refresh: refresh_cookie(&next),
+        })
+    }
+
+    /// User `user_id` if `password` is theirs. Wrong passwords count like
+    /// failed logins from `ip`, so a stolen session must not make guessing
+    /// it any cheaper.
+    fn check_current_password(
+        &self,
+        user_id: &str,
+        ip: &str,
+        password: &str,
+    ) -> Result<User, PasswordError> {
+        let user_key = LoginAttemptsKey::user(user_id);
+        let ip_key = LoginAttemptsKey::ip(ip);
+        let previous = self
+            .reserve_attempt(&user_key, &ip_key, now_in_secs())
+            .map_err(|retry_after| PasswordError::Locked { retry_after })?;
+        let user = self
+            .user_db
+            .get_typed(&UserKey(user_id))
+            .unwrap()
+            .ok_or(PasswordError::UnknownUser)?;
+        if !bcrypt::verify(password, &user.password).unwrap() {
+            return Err(PasswordError::IncorrectPassword);
+        }
+        self.release_attempt(&user_key, &ip_key, previous);
+        Ok(user)
+    }
+
+    /// Sets a new password of the caller, who must know the current one.
+    /// Every session of the user ends, the caller gets a new one.
+    pub async fn change_password(
+        &self,
+        claims: &Claims,
+        ip: &str,
+        change: &PasswordChange,
+    ) -> Result<SessionCookies, PasswordError> {
+        let mut user = self.check_current_password(&claims.user_id, ip, &change.current)?;
+        self.check_password(&claims.user_id, &change.new)
+            .map_err(|violations| PasswordError::WeakPassword { violations })?;
+
+        user.password = bcrypt::hash(&change.new, BCRYPT_COST).unwrap();
+        self.user_db
+            .insert_typed(&UserKey(&claims.user_id), user)
+            .unwrap();
+        self.user_db.flush_async().await.unwrap();
+        self.resets_db
+            .remove(&PasswordResetKey(&claims.user_id))
+            .unwrap();
+        self.logout_all(&claims.user_id).await;
+        let options = SessionOptions {
+            cancel_on_disconnect: claims.cancel_on_disconnect,
+        };
+        Ok(self.start_session(
+            claims.user_id.clone(),
+            self.role(&claims.user_id),
+            ip.to_string(),
+            &options,
+            claims.two_factor,
+        ))
+    }
+
+    /// Issues a token to set the password of a user without the current
+    /// one, replacing the pending one.
+    pub async fn issue_reset(&self, user_id: &str) -> Result<ResetToken, PasswordError> {
+        if !self.user_db.contains_key(&UserKey(user_id)).unwrap() {
+            return Err(PasswordError::UnknownUser);
+        }
+        let token = random_token();
+        let exp = now_in_secs() + RESET_MAX_AGE_SECS;
+        let reset = PasswordReset {
+            token_hash: digest::digest(&digest::SHA256, token.as_bytes())
+                .as_ref()
+                .to_vec(),
+            exp,
+        };
+        self.resets_db
+            .insert_typed(&PasswordResetKey(user_id), reset)
+            .unwrap();
+        self.resets_db.flush_async().await.unwrap();
+        Ok(ResetToken { token, exp })
+    }
+
+    /// Sets a new password with a token of [`issue_reset`](Self::issue_reset),
+    /// which can't be used again. Every session of the user ends and their
+    /// failed logins are forgotten.
+    pub async fn reset_password(&self, reset: &PasswordResetBody) -> Result<(), PasswordError> {
+        self.check_password(&reset.user_id, &reset.new)
+            .map_err(|violations| PasswordError::WeakPassword { violations })?;
+        let hashed_password = bcrypt::hash(&reset.new, BCRYPT_COST).unwrap();
+        let token_hash = digest::digest(&digest::SHA256, reset.token.as_bytes());
+        let now = now_in_secs();
+        let reset_key = PasswordResetKey(&reset.user_id);
+        let user_key = UserKey(&reset.user_id);
+
+        let result = (&self.resets_db, &self.user_db).transaction(|(resets, users)| {
+            let pending: Option<PasswordReset> = resets.get_typed(&reset_key)?;
+            let valid = pending.map_or(false, |pending| {
+                pending.exp > now
+                    && constant_time::verify_slices_are_equal(
+                        &pending.token_hash,
+                        token_hash.as_ref(),
+                    )
+                    .is_ok()
+            });
+            if !valid {
+                return abort(PasswordError::InvalidResetToken);
+            }
+            let mut user: User = match users.get_typed(&user_key)? {
+                Some(user) => user,
+                None => return abort(PasswordError::InvalidResetToken),
+            };
+            user.password = hashed_password.clone();
+            users.insert_typed(&user_key, user)?;
+            resets.remove(reset_key.as_ref())?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.user_db.flush_async().await.unwrap();
+                self.attempts_db
+                    .remove(&LoginAttemptsKey::user(&reset.user_id))
+                    .unwrap();
+                self.logout_all(&reset.user_id).await;
+                Ok(())
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    fn check_password(&self, user_id: &str, password: &str) -> Result<(), Vec<PasswordViolation>> {
+        let violations = password::check(&self.password_policy, user_id, password);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Starts the enrollment of a second factor, replacing an unconfirmed
+    /// one. Logins ask for it once [`confirm_two_factor`](Self::confirm_two_factor)
+    /// is called. Both take the current password, a stolen session must not
+    /// let its thief lock the user out.
+    pub async fn enroll_two_factor(
+        &self,
+        user_id: &str,
+        ip: &str,
+        password: &str,
+    ) -> Result<TwoFactorEnrollment, TwoFactorError> {
+        self.check_two_factor_password(user_id, ip, password)?;
+        let key = TwoFactorKey(user_id);
+        let secret = totp::generate_secret();
+        let result = self.two_factor_db.transaction(|tree| {
+            let current: Option<TwoFactor> = tree.get_typed(&key)?;
+            if current.map_or(false, |current| current.enabled) {
+                return abort(TwoFactorError::AlreadyEnabled);
+            }
+            let pending = TwoFactor {
+                secret: secret.clone(),
+                enabled: false,
+                recovery_codes: Vec::new(),
+                last_step: 0,
+            };
+            tree.insert_typed(&key, pending)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.two_factor_db.flush_async().await.unwrap();
+                Ok(TwoFactorEnrollment {
+                    secret: totp::base32(&secret),
+                    uri: totp::uri(user_id, &secret),
+                })
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Enables the enrolled second factor once `code` shows the user set it
+    /// up. Returns recovery codes, each usable once in place of a code, they
+    /// are never shown again.
+    pub async fn confirm_two_factor(
+        &self,
+        user_id: &str,
+        ip: &str,
+        password: &str,
+        code: &str,
+    ) -> Result<Vec<String>, TwoFactorError> {
+        self.check_two_factor_password(user_id, ip, password)?;
+        let key = TwoFactorKey(user_id);
+        let now = now_in_secs();
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| format!("{:032x}", rand::random::<u128>()))
+            .collect();
+        let result = self.two_factor_db.transaction(|tree| {
+            let mut two_factor: TwoFactor = match tree.get_typed(&key)? {
+                Some(two_factor) if two_factor.enabled => {
+                    return abort(TwoFactorError::AlreadyEnabled)
+                }
+                Some(two_factor) => two_factor,
+                None => return abort(TwoFactorError::NotEnrolled),
+            };
+            match totp::verify(&two_factor.secret, code, now, two_factor.last_step) {
+                Some(step) => two_factor.last_step = step,
+                None => return abort(TwoFactorError::InvalidCode),
+            }
+            two_factor.enabled = true;
+            two_factor.recovery_codes = codes.iter().map(|code| recovery_hash(code)).collect();
+            tree.insert_typed(&key, two_factor)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.two_factor_db.flush_async().await.unwrap();
+                Ok(codes)
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Turns the second factor of the caller off, given their password and
+    /// one of its codes. Wrong codes count like failed logins from `ip`.
+    /// Every session of the user ends, as they passed a factor that is gone,
+    /// the caller gets a new one.
+    pub async fn disable_two_factor(
+        &self,
+        claims: &Claims,
+        ip: &str,
+        password: &str,
+        code: &str,
+    ) -> Result<SessionCookies, TwoFactorError> {
+        let user_id = &claims.user_id;
+        self.check_two_factor_password(user_id, ip, password)?;
+        let user_key = LoginAttemptsKey::user(user_id);
+        let ip_key = LoginAttemptsKey::ip(ip);
+        let now = now_in_secs();
+        let previous = self
+            .reserve_attempt(&user_key, &ip_key, now)
+            .map_err(|retry_after| TwoFactorError::Locked { retry_after })?;
+        match self.use_second_factor(user_id, code, now).await {
+            Err(TwoFactorError::InvalidCode) => return Err(TwoFactorError::InvalidCode),
+            result => {
+                self.release_attempt(&user_key, &ip_key, previous);
+                result?;
+            }
+        }
+        self.two_factor_db.remove(&TwoFactorKey(user_id)).unwrap();
+        self.two_factor_db.flush_async().await.unwrap();
+        self.logout_all(user_id).await;
+        let options = SessionOptions {
+            cancel_on_disconnect: claims.cancel_on_disconnect,
+        };
+        Ok(self.start_session(
+            user_id.clone(),
+            self.role(user_id),
+            ip.to_string(),
+            &options,
+            false,
+        ))
+    }
+
+    /// [`check_current_password`](Self::check_current_password) with the
+    /// errors of the second factor.
+    fn check_two_factor_password(
+        &self,
+        user_id: &str,
+        ip: &str,
+        password: &str,
+    ) -> Result<(), TwoFactorError> {
+        match self.check_current_password(user_id, ip, password) {
+            Ok(_) => Ok(()),
+            Err(PasswordError::Locked { retry_after }) => {
+                Err(TwoFactorError::Locked { retry_after })
+            }
+            Err(_) => Err(TwoFactorError::IncorrectPassword),
+        }
+    }
+
+    pub fn has_two_factor(&self, user_id: &str) -> bool {
+        let two_factor = self
+            .two_factor_db
+            .get_typed(&TwoFactorKey(user_id))
+            .unwrap();
+        two_factor.map_or(false, |two_factor| two_factor.enabled)
+    }
+
+    /// Role of a user at their next login.
+    pub fn role(&self, user_id: &str) -> Role {
+        let user = self.user_db.get_typed(&UserKey(user_id)).unwrap();
+        let stored = user.map_or(Role::default(), |user| user.role);
+        stored.max(self.configured_role(user_id))
+    }
+
+    /// Changes the role of a user, ending their sessions so none keeps the
+    /// old one.
+    pub async fn set_role(&self, user_id: &str, role: Role) -> Result<(), RoleError> {
+        let key = UserKey(user_id);
+        let mut user = self
+            .user_db
+            .get_typed(&key)
+            .unwrap()
+            .ok_or(RoleError::UnknownUser)?;
+        user.role = role;
+        self.user_db.insert_typed(&key, user).unwrap();
+        self.user_db.flush_async().await.unwrap();
+        self.logout_all(user_id).await;
+        Ok(())
+    }
+
+    /// Role given by the configuration, which stored roles can only raise.
+    fn configured_role(&self, user_id: &str) -> Role {
+        if self.admins.iter().any(|admin| admin == user_id) {
+            Role::Admin
+        } else if self.operators.iter().any(|operator| operator == user_id) {
+            Role::Operator
+        } else {
+            Role::Trader
+        }
+    }
+
+    /// Sessions found expired from now on, whose orders are left to the
+    /// caller. Only the first call gets them.
+    pub fn take_expired_sessions(&self) -> Option<UnboundedReceiver<ExpiredSession>> {
+        self.expired_sessions.lock().unwrap().take()
+    }
+
     pub fn get_num_users(&self) -> usize {
         self.user_db.len()
     }
 
-    pub fn remove_cookie<R>(&self, reply: R) -> warp::reply::WithHeader<R>
+    pub fn remove_cookie<R>(&self, reply: R) -> warp::reply::Response
     where
         R: warp::Reply,
     {
-        warp::reply::with_header(reply, header::SET_COOKIE, DELETE_JWT_COOKIE)
+        with_cookies(reply, &[DELETE_JWT_COOKIE, DELETE_REFRESH_COOKIE])
     }
 
-    fn make_cookie(&self, user_id: String, ip: String) -> String {
-        let max_age_secs = 12 * 60 * 60; // 12 hours
-        let claims = Claims {
-            exp: now_plus_duration(time::Duration::from_secs(max_age_secs)),
+    /// Pending login of a user with a second factor.
+    fn challenge(
+        &self,
+        user_id: String,
+        role: Role,
+        ip: &str,
+        options: &SessionOptions,
+    ) -> TwoFactorChallenge {
+        let challenge = random_token();
+        let exp = now_in_secs() + CHALLENGE_MAX_AGE_SECS;
+        let pending = LoginChallenge {
+            user_id,
+            ip: ip.to_string(),
+            cancel_on_disconnect: options.cancel_on_disconnect,
+            role,
+            failures: 0,
+            exp,
+        };
+        self.challenges_db
+            .insert_typed(&LoginChallengeKey(&challenge), pending)
+            .unwrap();
+        TwoFactorChallenge { challenge, exp }
+    }
+
+    /// Accepts a code of the second factor of a user, or one of their
+    /// recovery codes which is then used up.
+    async fn use_second_factor(
+        &self,
+        user_id: &str,
+        code: &str,
+        now: u64,
+    ) -> Result<(), TwoFactorError> {
+        let key = TwoFactorKey(user_id);
+        let result = self.two_factor_db.transaction(|tree| {
+            let mut two_factor: TwoFactor = match tree.get_typed(&key)? {
+                Some(two_factor) if two_factor.enabled => two_factor,
+                _ => return abort(TwoFactorError::NotEnabled),
+            };
+            if let Some(step) = totp::verify(&two_factor.secret, code, now, two_factor.last_step) {
+                two_factor.last_step = step;
+            } else {
+                let hash = recovery_hash(code);
+                match two_factor.recovery_codes.iter().position(|h| *h == hash) {
+                    Some(i) => {
+                        two_factor.recovery_codes.remove(i);
+                    }
+                    None => return abort(TwoFactorError::InvalidCode),
+                }
+            }
+            tree.insert_typed(&key, two_factor)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.two_factor_db.flush_async().await.unwrap();
+                Ok(())
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Starts a token family for a login, with its first refresh token.
+    fn start_session(
+        &self,
+        user_id: String,
+        role: Role,
+        ip: String,
+        options: &SessionOptions,
+        two_factor: bool,
+    ) -> SessionCookies {
+        let session = format!("{:016x}", rand::random::<u64>());
+        let exp = now_in_secs() + REFRESH_MAX_AGE_SECS;
+        let generation = self.generation(&user_id);
+        let family = TokenFamily {
             user_id,
             ip,
+            cancel_on_disconnect: options.cancel_on_disconnect,
+            revoked: false,
+            exp,
+            generation,
+            role,
+            two_factor,
+        };
+        let token = random_token();
+        let refresh = RefreshToken {
+            family: session.clone(),
+            exp,
+            used: false,
         };
-        let cookie = jwt::encode(&self.jwt_header, &claims, &self.jwt_encoding_key).unwrap();
+        self.families_db
+            .insert_typed(&TokenFamilyKey(&session), family.clone())
+            .unwrap();
+        self.refresh_db
+            .insert_typed(&RefreshTokenKey::new(&token), refresh)
+            .unwrap();
+
+        SessionCookies {
+            access: self.access_cookie(&session, &family),
+            refresh: refresh_cookie(&token),
+        }
+    }
+
+    fn is_revoked(&self, session: &str) -> bool {
+        let family = self
+            .families_db
+            .get_typed(&TokenFamilyKey(session))
+            .unwrap();
+        family.map_or(false, |family| family.revoked)
+    }
+
+    async fn revoke_family(&self, session: &str) {
+        let key = TokenFamilyKey(session);
+        if let Some(mut family) = self.families_db.get_typed(&key).unwrap() {
+            family.revoked = true;
+            self.families_db.insert_typed(&key, family).unwrap();
+            self.families_db.flush_async().await.unwrap();
+        }
+    }
+
+    fn access_cookie(&self, session: &str, family: &TokenFamily) -> String {
+        let claims = Claims {
+            exp: now_plus_duration(time::Duration::from_secs(ACCESS_MAX_AGE_SECS)),
+            user_id: family.user_id.clone(),
+            ip: family.ip.clone(),
+            session: session.to_string(),
+            cancel_on_disconnect: family.cancel_on_disconnect,
+            jti: random_token(),
+            generation: family.generation,
+            role: family.role,
+            two_factor: family.two_factor,
+        };
+        let cookie = self.keys.encode(&claims);
 
         format!(
             "{}={}; Max-Age={}; SameSite=Lax; HttpOnly",
-            JWT_COOKIE_NAME,
-            cookie,
-            max_age_secs * 1000
+            JWT_COOKIE_NAME, cookie, ACCESS_MAX_AGE_SECS
         )
     }
 
-    fn set_cookie<R>(&self, reply: R, user_id: String, ip: String) -> warp::reply::WithHeader<R>
-    where
-        R: warp::Reply,
-    {
-        let cookie = self.make_cookie(user_id, ip);
-
-        warp::reply::with_header(reply, header::SET_COOKIE, cookie)
+    fn generation(&self, user_id: &str) -> u64 {
+        let generation = self
+            .generations_db
+            .get_typed(&TokenGenerationKey(user_id))
+            .unwrap();
+        generation.map_or(0, |g| g.generation)
     }
 
-    fn is_in_blacklist(&self, cookie: &str) -> bool {
-        let key = BlackListedKey(cookie);
+    fn is_in_blacklist(&self, jti: &str) -> bool {
+        let key = BlackListedKey(jti);
         let black = self.blacklist_db.get_typed(&key).unwrap();
         if let Some(black) = black {
             let not_expired = now_in_secs() < black.exp;
@@ -189,12 +1017,24 @@ impl AuthManager {
     }
 }
 
-fn now_in_secs() -> u64 {
-    let start = time::SystemTime::now();
-    start
-        .duration_since(time::UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs()
+/// Only sent to the refresh endpoint.
+fn refresh_cookie(token: &str) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/refresh; SameSite=Strict; HttpOnly",
+        REFRESH_COOKIE_NAME, token, REFRESH_MAX_AGE_SECS
+    )
+}
+
+/// Recovery codes are random enough for a digest without salt.
+fn recovery_hash(code: &str) -> Vec<u8> {
+    let code = code.trim().to_lowercase();
+    digest::digest(&digest::SHA256, code.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+fn random_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
 }
 
 fn now_plus_duration(duration: time::Duration) -> u64 {
@@ -220,3 +1060,495 @@ async fn clear_blacklist(blacklist_db: &sled::Tree) {
     println!("blacklist: {} deleted", count);
     blacklist_db.apply_batch(batch).unwrap()
 }
+
+/// Removes the expired token families, sending the sessions that cancel
+/// their orders on disconnect to `expired`.
+fn clear_families(families_db: &sled::Tree, expired: &UnboundedSender<ExpiredSession>) {
+    let now = now_in_secs();
+    let mut batch = sled::Batch::default();
+    for entry in families_db.iter() {
+        let (key, value) = entry.unwrap();
+        let family = TokenFamily::try_from(value).unwrap();
+        if now > family.exp {
+            if family.cancel_on_disconnect {
+                let session = ExpiredSession {
+                    user_id: family.user_id,
+                    session: String::from_utf8_lossy(&key).into_owned(),
+                };
+                // Nobody may be ending them.
+                let _ = expired.unbounded_send(session);
+            }
+            batch.remove(key);
+        }
+    }
+    families_db.apply_batch(batch).unwrap()
+}
+
+/// Removes the entries of `tree` whose expiry, as given by `exp`, passed.
+fn clear_expired<V, F>(tree: &sled::Tree, exp: F)
+where
+    V: std::convert::TryFrom<sled::IVec, Error = sled::Error>,
+    F: Fn(&V) -> u64,
+{
+    let now = now_in_secs();
+    let mut batch = sled::Batch::default();
+    for entry in tree.iter() {
+        let (key, value) = entry.unwrap();
+        if now > exp(&V::try_from(value).unwrap()) {
+            batch.remove(key);
+        }
+    }
+    tree.apply_batch(batch).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: &str = "127.0.0.1";
+
+    fn manager() -> AuthManager {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        AuthManager::new(db, &Config::default()).unwrap()
+    }
+
+    fn session(auth: &AuthManager, user_id: &str) -> SessionCookies {
+        let options = SessionOptions::default();
+        auth.start_session(
+            user_id.to_string(),
+            Role::Trader,
+            IP.to_string(),
+            &options,
+            false,
+        )
+    }
+
+    /// Value of a `Set-Cookie` header.
+    fn cookie_value(cookie: &str) -> &str {
+        let pair = cookie.split(';').next().unwrap();
+        &pair[pair.find('=').unwrap() + 1..]
+    }
+
+    fn family(user_id: &str, cancel_on_disconnect: bool, exp: u64) -> TokenFamily {
+        TokenFamily {
+            user_id: user_id.to_string(),
+            ip: "127.0.0.1".to_string(),
+            cancel_on_disconnect,
+            revoked: false,
+            exp,
+            generation: 0,
+            role: Role::Trader,
+            two_factor: false,
+        }
+    }
+
+    #[test]
+    fn expired_families_end_sessions() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let families_db = db.open_tree(<TokenFamilyKey as KeyOf>::PREFIX).unwrap();
+        let now = now_in_secs();
+        let families = [
+            ("a", family("alice", true, now - 1)),
+            ("b", family("alice", false, now - 1)),
+            ("c", family("bob", true, now + 60)),
+        ];
+        for (session, family) in families.iter() {
+            families_db
+                .insert_typed(&TokenFamilyKey(session), family.clone())
+                .unwrap();
+        }
+
+        let (sender, mut receiver) = unbounded();
+        clear_families(&families_db, &sender);
+        let expired = receiver.try_next().unwrap().unwrap();
+        assert_eq!(expired.user_id, "alice");
+        assert_eq!(expired.session, "a");
+        assert!(receiver.try_next().is_err());
+        assert_eq!(families_db.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refresh_reuse_revokes_family() {
+        let auth = manager();
+        let first = session(&auth, "alice");
+        let other = session(&auth, "alice");
+        let token = cookie_value(&first.refresh);
+        assert!(!auth.refresh_db.contains_key(token).unwrap());
+
+        let second = auth.refresh(token, IP).await.unwrap();
+        let access = cookie_value(&second.access);
+        assert!(auth.claims(access).is_ok());
+
+        let reused = auth.refresh(token, IP).await;
+        assert!(matches!(reused, Err(RefreshError::TokenReused)));
+        let rotated = auth.refresh(cookie_value(&second.refresh), IP).await;
+        assert!(matches!(rotated, Err(RefreshError::InvalidToken)));
+        for access in [&first.access, &second.access].iter() {
+            let claims = auth.claims(cookie_value(access));
+            assert!(matches!(claims, Err(AuthorizeError::BlackListedToken)));
+        }
+
+        // Other sessions of the user go on.
+        assert!(auth.claims(cookie_value(&other.access)).is_ok());
+        assert!(auth.refresh(cookie_value(&other.refresh), IP).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_token() {
+        let auth = manager();
+        let first = session(&auth, "alice");
+        let other = session(&auth, "alice");
+        let access = cookie_value(&first.access);
+        let jti = auth.claims(access).unwrap().jti;
+
+        auth.logout(warp::reply(), access).await;
+        assert!(auth.is_in_blacklist(&jti));
+        let claims = auth.claims(access);
+        assert!(matches!(claims, Err(AuthorizeError::BlackListedToken)));
+        let refreshed = auth.refresh(cookie_value(&first.refresh), IP).await;
+        assert!(matches!(refreshed, Err(RefreshError::InvalidToken)));
+        assert!(auth.claims(cookie_value(&other.access)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn logout_all_ends_every_session() {
+        let auth = manager();
+        let first = session(&auth, "alice");
+        let second = session(&auth, "alice");
+        let bob = session(&auth, "bob");
+
+        auth.logout_all("alice").await;
+        for cookies in [&first, &second].iter() {
+            let claims = auth.claims(cookie_value(&cookies.access));
+            assert!(matches!(claims, Err(AuthorizeError::BlackListedToken)));
+            let refreshed = auth.refresh(cookie_value(&cookies.refresh), IP).await;
+            assert!(matches!(refreshed, Err(RefreshError::InvalidToken)));
+        }
+        assert!(auth.claims(cookie_value(&bob.access)).is_ok());
+
+        // Sessions started afterwards carry the new generation.
+        let next = session(&auth, "alice");
+        assert!(auth.claims(cookie_value(&next.access)).is_ok());
+        assert!(auth.refresh(cookie_value(&next.refresh), IP).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn demoted_user_loses_role() {
+        let auth = manager();
+        let user = User {
+            id: "alice".to_string(),
+            password: bcrypt::hash("correct horse 42", BCRYPT_COST).unwrap(),
+            role: Role::Admin,
+        };
+        auth.user_db
+            .insert_typed(&user.key(), user.clone())
+            .unwrap();
+        let options = SessionOptions::default();
+        let admin = auth.start_session(user.id, Role::Admin, IP.to_string(), &options, false);
+        let access = cookie_value(&admin.access);
+        let claims = auth.authorize_role(IP, access, Role::Admin).unwrap();
+
+        auth.set_role("alice", Role::Trader).await.unwrap();
+        let demoted = auth.authorize_role(IP, access, Role::Admin);
+        assert!(matches!(demoted, Err(AuthorizeError::BlackListedToken)));
+        let refreshed = auth.refresh(cookie_value(&admin.refresh), IP).await;
+        assert!(matches!(refreshed, Err(RefreshError::InvalidToken)));
+        assert_eq!(auth.role("alice"), Role::Trader);
+
+        // A session restarted from claims issued before keeps no old role.
+        let change = PasswordChange {
+            current: "correct horse 42".to_string(),
+            new: "battery staple 7".to_string(),
+        };
+        let cookies = auth.change_password(&claims, IP, &change).await.unwrap();
+        let access = cookie_value(&cookies.access);
+        assert_eq!(auth.authorize(IP, access).unwrap().role, Role::Trader);
+        let forbidden = auth.authorize_role(IP, access, Role::Admin);
+        assert!(matches!(forbidden, Err(AuthorizeError::Forbidden)));
+
+        let unknown = auth.set_role("bob", Role::Admin).await;
+        assert_eq!(unknown, Err(RoleError::UnknownUser));
+    }
+
+    #[test]
+    fn retry_after() {
+        let attempts = LoginAttempts {
+            failures: 1,
+            next_attempt: 10,
+            locked_until: 0,
+            exp: 100,
+        };
+        assert_eq!(attempts.retry_after(4), Some(6));
+        assert_eq!(attempts.retry_after(10), None);
+        let locked = LoginAttempts {
+            locked_until: 20,
+            ..attempts
+        };
+        assert_eq!(locked.retry_after(4), Some(16));
+        assert_eq!(locked.retry_after(20), None);
+    }
+
+    #[tokio::test]
+    async fn login_backoff_and_lockout() {
+        let auth = manager();
+        let user = LoginAttemptsKey::user("alice");
+        let ip = LoginAttemptsKey::ip(IP);
+        let mut now = 1_000;
+        for delay in [1, 2, 4, 8].iter() {
+            assert!(auth.reserve_attempt(&user, &ip, now).is_ok());
+            assert_eq!(auth.reserve_attempt(&user, &ip, now), Err(*delay));
+            now += delay;
+        }
+        // The fifth failure in a row locks the user out.
+        assert!(auth.reserve_attempt(&user, &ip, now).is_ok());
+        assert_eq!(auth.reserve_attempt(&user, &ip, now), Err(15 * 60));
+        assert_eq!(auth.reserve_attempt(&user, &ip, now + 15 * 60 - 1), Err(1));
+        let previous = auth.reserve_attempt(&user, &ip, now + 15 * 60);
+        assert_eq!(previous, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn ip_lockout() {
+        let auth = manager();
+        let ip = LoginAttemptsKey::ip(IP);
+        let max_failures = LoginThrottle::default().max_ip_failures;
+        for i in 2..max_failures {
+            let user = LoginAttemptsKey::user(&i.to_string());
+            assert!(auth.reserve_attempt(&user, &ip, 1_000).is_ok());
+        }
+        // A success takes its attempt back, the other failures of the IP
+        // are kept.
+        let user = LoginAttemptsKey::user("alice");
+        let previous = auth.reserve_attempt(&user, &ip, 1_000).unwrap();
+        auth.release_attempt(&user, &ip, previous);
+        assert_eq!(auth.attempts_db.get_typed(&user).unwrap(), None);
+
+        for other in ["bob", "carol"].iter() {
+            let other = LoginAttemptsKey::user(other);
+            assert!(auth.reserve_attempt(&other, &ip, 1_000).is_ok());
+        }
+        assert_eq!(auth.reserve_attempt(&user, &ip, 1_000), Err(15 * 60));
+    }
+
+    #[tokio::test]
+    async fn concurrent_attempts_are_reserved_once() {
+        let auth = std::sync::Arc::new(manager());
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let auth = auth.clone();
+                std::thread::spawn(move || {
+                    let user = LoginAttemptsKey::user("alice");
+                    let ip = LoginAttemptsKey::ip(&format!("10.0.0.{}", i));
+                    auth.reserve_attempt(&user, &ip, 1_000).is_ok()
+                })
+            })
+            .collect();
+        let reserved = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|reserved| *reserved)
+            .count();
+        assert_eq!(reserved, 1);
+    }
+
+    const PASSWORD: &str = "correct horse 42";
+
+    fn unthrottled_manager() -> AuthManager {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let config = Config {
+            login_throttle: None,
+            ..Config::default()
+        };
+        AuthManager::new(db, &config).unwrap()
+    }
+
+    fn add_user(auth: &AuthManager, id: &str) {
+        let user = User {
+            id: id.to_string(),
+            password: bcrypt::hash(PASSWORD, BCRYPT_COST).unwrap(),
+            role: Role::Trader,
+        };
+        auth.user_db.insert_typed(&user.key(), user).unwrap();
+    }
+
+    /// Enables a second factor of alice, returning its secret and recovery
+    /// codes.
+    async fn enable_two_factor(auth: &AuthManager) -> (Vec<u8>, Vec<String>) {
+        add_user(auth, "alice");
+        auth.enroll_two_factor("alice", IP, PASSWORD).await.unwrap();
+        let key = TwoFactorKey("alice");
+        let secret = auth.two_factor_db.get_typed(&key).unwrap().unwrap().secret;
+        let code = totp::code(&secret, now_in_secs());
+        let recovery_codes = auth
+            .confirm_two_factor("alice", IP, PASSWORD, &code)
+            .await
+            .unwrap();
+        (secret, recovery_codes)
+    }
+
+    /// Challenge of a login of alice from `IP`.
+    fn login(auth: &AuthManager) -> String {
+        let credentials = Credentials {
+            id: "alice".to_string(),
+            password: PASSWORD.to_string(),
+        };
+        match auth.authenticate(&credentials, IP, &SessionOptions::default()) {
+            Ok(LoginStep::TwoFactor(challenge)) => challenge.challenge,
+            _ => panic!("no challenge"),
+        }
+    }
+
+    #[tokio::test]
+    async fn two_factor_changes_need_password() {
+        let auth = unthrottled_manager();
+        add_user(&auth, "alice");
+        let enrolled = auth.enroll_two_factor("alice", IP, "wrong").await;
+        assert_eq!(enrolled.err(), Some(TwoFactorError::IncorrectPassword));
+        let enrollment = auth.enroll_two_factor("alice", IP, PASSWORD).await;
+        assert!(enrollment.is_ok());
+
+        let secret = auth
+            .two_factor_db
+            .get_typed(&TwoFactorKey("alice"))
+            .unwrap()
+            .unwrap()
+            .secret;
+        let code = totp::code(&secret, now_in_secs());
+        let confirmed = auth.confirm_two_factor("alice", IP, "wrong", &code).await;
+        assert_eq!(confirmed, Err(TwoFactorError::IncorrectPassword));
+        assert!(!auth.has_two_factor("alice"));
+        let recovery_codes = auth
+            .confirm_two_factor("alice", IP, PASSWORD, &code)
+            .await
+            .unwrap();
+        assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+        assert!(recovery_codes.iter().all(|code| code.len() == 32));
+
+        // Wrong passwords count as failed logins.
+        let auth = manager();
+        add_user(&auth, "bob");
+        let enrolled = auth.enroll_two_factor("bob", IP, "wrong").await;
+        assert_eq!(enrolled.err(), Some(TwoFactorError::IncorrectPassword));
+        let attempts = auth.attempts_db.get_typed(&LoginAttemptsKey::user("bob"));
+        assert_eq!(attempts.unwrap().unwrap().failures, 1);
+    }
+
+    #[tokio::test]
+    async fn challenges() {
+        let auth = unthrottled_manager();
+        let (secret, _) = enable_two_factor(&auth).await;
+        let code = totp::code(&secret, now_in_secs() + totp::STEP_SECS);
+
+        let challenge = login(&auth);
+        let elsewhere = auth.verify_login(&challenge, &code, "10.0.0.2").await;
+        assert!(matches!(
+            elsewhere,
+            Err(AuthenticateError::InvalidChallenge)
+        ));
+        for _ in 0..MAX_CHALLENGE_FAILURES {
+            let wrong = auth.verify_login(&challenge, "000000x", IP).await;
+            assert!(matches!(wrong, Err(AuthenticateError::IncorrectCode)));
+        }
+        // The login must start over with the password.
+        let exhausted = auth.verify_login(&challenge, &code, IP).await;
+        assert!(matches!(
+            exhausted,
+            Err(AuthenticateError::InvalidChallenge)
+        ));
+
+        let challenge = login(&auth);
+        let cookies = auth.verify_login(&challenge, &code, IP).await.unwrap();
+        let claims = auth.authorize(IP, cookie_value(&cookies.access)).unwrap();
+        assert!(claims.two_factor);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_used_once() {
+        let auth = unthrottled_manager();
+        let (_, recovery_codes) = enable_two_factor(&auth).await;
+        let recovery_code = &recovery_codes[0];
+
+        let challenge = login(&auth);
+        assert!(auth
+            .verify_login(&challenge, recovery_code, IP)
+            .await
+            .is_ok());
+        let challenge = login(&auth);
+        let reused = auth.verify_login(&challenge, recovery_code, IP).await;
+        assert!(matches!(reused, Err(AuthenticateError::IncorrectCode)));
+        let other = auth.verify_login(&challenge, &recovery_codes[1], IP).await;
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn disabling_two_factor_ends_sessions() {
+        let auth = unthrottled_manager();
+        let (_, recovery_codes) = enable_two_factor(&auth).await;
+        let challenge = login(&auth);
+        let cookies = auth
+            .verify_login(&challenge, &recovery_codes[0], IP)
+            .await
+            .unwrap();
+        let claims = auth.authorize(IP, cookie_value(&cookies.access)).unwrap();
+
+        let code = &recovery_codes[1];
+        let disabled = auth.disable_two_factor(&claims, IP, "wrong", code).await;
+        assert_eq!(disabled.err(), Some(TwoFactorError::IncorrectPassword));
+        let disabled = auth
+            .disable_two_factor(&claims, IP, PASSWORD, "00000x")
+            .await;
+        assert_eq!(disabled.err(), Some(TwoFactorError::InvalidCode));
+        let restarted = auth
+            .disable_two_factor(&claims, IP, PASSWORD, code)
+            .await
+            .unwrap();
+        assert!(!auth.has_two_factor("alice"));
+        let ended = auth.authorize(IP, cookie_value(&cookies.access));
+        assert!(matches!(ended, Err(AuthorizeError::BlackListedToken)));
+        let claims = auth.authorize(IP, cookie_value(&restarted.access)).unwrap();
+        assert!(!claims.two_factor);
+    }
+
+    #[tokio::test]
+    async fn wrong_disabling_codes_count_as_failures() {
+        let auth = manager();
+        let (_, recovery_codes) = enable_two_factor(&auth).await;
+        let challenge = login(&auth);
+        let cookies = auth
+            .verify_login(&challenge, &recovery_codes[0], IP)
+            .await
+            .unwrap();
+        let claims = auth.authorize(IP, cookie_value(&cookies.access)).unwrap();
+
+        let disabled = auth
+            .disable_two_factor(&claims, IP, PASSWORD, "00000x")
+            .await;
+        assert_eq!(disabled.err(), Some(TwoFactorError::InvalidCode));
+        assert!(auth.has_two_factor("alice"));
+        let attempts = auth.attempts_db.get_typed(&LoginAttemptsKey::user("alice"));
+        assert_eq!(attempts.unwrap().unwrap().failures, 1);
+        let attempts = auth.attempts_db.get_typed(&LoginAttemptsKey::ip(IP));
+        assert_eq!(attempts.unwrap().unwrap().failures, 1);
+    }
+
+    #[tokio::test]
+    async fn signup_rejects_invalid_ids() {
+        let auth = manager();
+        for id in ["", "alice\0x"].iter() {
+            let credentials = Credentials {
+                id: id.to_string(),
+                password: PASSWORD.to_string(),
+            };
+            let result = auth.signup(warp::reply(), credentials, IP).await;
+            assert!(matches!(result, Err(SignUpError::InvalidUserId)));
+        }
+        assert_eq!(auth.get_num_users(), 0);
+
+        let credentials = Credentials {
+            id: "alice".to_string(),
+            password: PASSWORD.to_string(),
+        };
+        let result = auth.signup(warp::reply(), credentials, IP).await;
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::config::{JwtKey, KeyMaterial};
+use jsonwebtoken::{self as jwt, Algorithm, DecodingKey, EncodingKey};
+use std::collections::HashMap;
+
+/// Shortest HMAC secret accepted, the length of the SHA-256 output.
+pub const MIN_SECRET_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum KeyError {
+    NoKeys,
+    /// Shorter than [`MIN_SECRET_LEN`].
+    WeakSecret(String),
+    /// The first key must be able to sign.
+    MissingPrivateKey(String),
+    UnsupportedAlgorithm(String),
+    Io(String, std::io::Error),
+    Invalid(String, jwt::errors::Error),
+}
+
+struct VerifyingKey {
+    key: DecodingKey<'static>,
+    validation: jwt::Validation,
+}
+
+/// Keys loaded from the configuration. Tokens without a key id are checked
+/// against the signing key.
+pub struct KeyRing {
+    signing_id: String,
+    encoding_key: EncodingKey,
+    header: jwt::Header,
+    verifying: HashMap<String, VerifyingKey>,
+}
+
+impl KeyRing {
+    pub fn load(keys: &[JwtKey]) -> Result<Self, KeyError> {
+        let signing = keys.first().ok_or(KeyError::NoKeys)?;
+        let encoding_key = encoding_key(signing)?;
+        let mut header = jwt::Header::new(signing.algorithm);
+        header.kid = Some(signing.id.clone());
+
+        let mut verifying = HashMap::new();
+        for key in keys {
+            let validation = jwt::Validation {
+                algorithms: vec![key.algorithm],
+                ..jwt::Validation::default()
+            };
+            let key_id = key.id.clone();
+            verifying.insert(
+                key_id,
+                VerifyingKey {
+                    key: decoding_key(key)?,
+                    validation,
+                },
+            );
+        }
+
+        Ok(KeyRing {
+            signing_id: signing.id.clone(),
+            encoding_key,
+            header,
+            verifying,
+        })
+    }
+
+    pub fn encode<T: serde::Serialize>(&self, claims: &T) -> String {
+        jwt::encode(&self.header, claims, &self.encoding_key).unwrap()
+    }
+
+    /// Claims of a token signed by any of the keys and not expired.
+    pub fn decode<T: serde::de::DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let header = jwt::decode_header(token).ok()?;
+        let id = header.kid.as_ref().unwrap_or(&self.signing_id);
+        let key = self.verifying.get(id)?;
+        jwt::decode::<T>(token, &key.key, &key.validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+fn is_hmac(algorithm: Algorithm) -> bool {
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => true,
+        _ => false,
+    }
+}
+
+fn is_rsa(algorithm: Algorithm) -> bool {
+    match algorithm {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => true,
+        _ => false,
+    }
+}
+
+fn read(id: &str, path: &str) -> Result<Vec<u8>, KeyError> {
+    std::fs::read(path).map_err(|e| KeyError::Io(id.to_string(), e))
+}
+
+/// Secret of an HMAC key.
+fn secret(key: &JwtKey) -> Result<Option<Vec<u8>>, KeyError> {
+    let secret = match &key.material {
+        KeyMaterial::Secret { secret } if is_hmac(key.algorithm) => secret.as_bytes().to_vec(),
+        KeyMaterial::SecretFile { path } if is_hmac(key.algorithm) => read(&key.id, path)?,
+        _ => return Ok(None),
+    };
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(KeyError::WeakSecret(key.id.clone()));
+    }
+    Ok(Some(secret))
+}
+
+fn encoding_key(key: &JwtKey) -> Result<EncodingKey, KeyError> {
+    let invalid = |e| KeyError::Invalid(key.id.clone(), e);
+    if let Some(secret) = secret(key)? {
+        return Ok(EncodingKey::from_secret(&secret));
+    }
+    match &key.material {
+        KeyMaterial::Pem {
+            private_key: Some(path),
+            ..
+        } => {
+            let pem = read(&key.id, path)?;
+            if is_rsa(key.algorithm) {
+                EncodingKey::from_rsa_pem(&pem).map_err(invalid)
+            } else {
+                EncodingKey::from_ec_pem(&pem).map_err(invalid)
+            }
+        }
+        KeyMaterial::Pem { .. } => Err(KeyError::MissingPrivateKey(key.id.clone())),
+        _ => Err(KeyError::UnsupportedAlgorithm(key.id.clone())),
+    }
+}
+
+fn decoding_key(key: &JwtKey) -> Result<DecodingKey<'static>, KeyError> {
+    let invalid = |e| KeyError::Invalid(key.id.clone(), e);
+    if let Some(secret) = secret(key)? {
+        return Ok(DecodingKey::from_secret(&secret).into_static());
+    }
+    match &key.material {
+        KeyMaterial::Pem { public_key, .. } if !is_hmac(key.algorithm) => {
+            let pem = read(&key.id, public_key)?;
+            let key = if is_rsa(key.algorithm) {
+                DecodingKey::from_rsa_pem(&pem)
+            } else {
+                DecodingKey::from_ec_pem(&pem)
+            };
+            key.map(DecodingKey::into_static).map_err(invalid)
+        }
+        _ => Err(KeyError::UnsupportedAlgorithm(key.id.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct TestClaims {
+        exp: u64,
+        user_id: String,
+    }
+
+    fn hmac_key(id: &str, secret: &str) -> JwtKey {
+        JwtKey {
+            id: id.to_string(),
+            algorithm: Algorithm::HS256,
+            material: KeyMaterial::Secret {
+                secret: secret.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn rotation() {
+        let claims = TestClaims {
+            exp: crate::utils::now_in_secs() + 60,
+            user_id: "user".to_string(),
+        };
+        let (a, b, c) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        let old = KeyRing::load(&[hmac_key("old", &a)]).unwrap();
+        let token = old.encode(&claims);
+
+        let rotated = KeyRing::load(&[hmac_key("new", &b), hmac_key("old", &a)]).unwrap();
+        assert_eq!(rotated.decode::<TestClaims>(&token), Some(claims));
+        let retired = KeyRing::load(&[hmac_key("new", &b)]).unwrap();
+        assert_eq!(retired.decode::<TestClaims>(&token), None);
+
+        // Another key under the same id doesn't verify
+        let forged = KeyRing::load(&[hmac_key("old", &c)]).unwrap();
+        assert_eq!(forged.decode::<TestClaims>(&token), None);
+    }
+
+    #[test]
+    fn weak_secret() {
+        match KeyRing::load(&[hmac_key("default", "secret")]) {
+            Err(KeyError::WeakSecret(id)) => assert_eq!(id, "default"),
+            _ => panic!("short secret accepted"),
+        }
+    }
+}
@@ -1,46 +1,121 @@
 mod handler;
+mod keys;
+mod password;
+mod totp;
 
 use crate::{
-    user::User,
+    api_keys::Scope,
+    offers,
+    user::Role,
     utils::{bytes_body, json_body},
-    with_ctx, Ctx, IpQueryParam,
+    with_client_ip, with_ctx, ClientIp, Ctx,
 };
-pub use handler::AuthManager;
+pub use handler::{AuthManager, Claims, ExpiredSession, LoginStep, SessionCookies};
+pub use keys::{KeyError, MIN_SECRET_LEN};
+pub use password::PasswordViolation;
+use futures::StreamExt;
 use serde::{Serialize, Deserialize};
 use std::convert::Infallible;
 use std::time::Instant;
 use warp::{
-    http::{header, Response, StatusCode},
+    http::{
+        header::{self, HeaderValue},
+        Response, StatusCode,
+    },
     reply, Filter, Rejection, Reply,
 };
 
 pub const JWT_COOKIE_NAME: &'static str = "JWT";
 pub const DELETE_JWT_COOKIE: &'static str = "JWT=; Max-Age=0;";
+pub const REFRESH_COOKIE_NAME: &str = "REFRESH";
+pub const DELETE_REFRESH_COOKIE: &str = "REFRESH=; Max-Age=0; Path=/refresh";
 
 pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     login(ctx.clone())
         .or(signup(ctx.clone()))
         .or(logout(ctx.clone()))
+        .or(logout_all(ctx.clone()))
+        .or(refresh(ctx.clone()))
+        .or(set_role(ctx.clone()))
+        .or(change_password(ctx.clone()))
+        .or(issue_reset(ctx.clone()))
+        .or(reset_password(ctx.clone()))
+        .or(login_two_factor(ctx.clone()))
+        .or(enroll_two_factor(ctx.clone()))
+        .or(confirm_two_factor(ctx.clone()))
+        .or(disable_two_factor(ctx.clone()))
         .or(num_users(ctx.clone())).or(config_bytes(ctx.clone())).or(config_path(ctx))
 }
 
+/// Cancels the orders of the sessions whose tokens expire, as the
+/// [`AuthManager`] clears them. Runs until the manager is dropped.
+pub async fn end_expired_sessions(ctx: Ctx) {
+    let mut expired = match ctx.auth_manager.take_expired_sessions() {
+        Some(expired) => expired,
+        None => return,
+    };
+    while let Some(session) = expired.next().await {
+        offers::cancel_session(&ctx, &session.user_id, &session.session).await;
+    }
+}
+
+/// Claims of the caller, the request is rejected unless their role is at
+/// least `role`. Routes using it need [`recover_unauthorized`].
+pub fn with_role(
+    ctx: Ctx,
+    role: Role,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    warp::cookie(JWT_COOKIE_NAME)
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String, ip: ClientIp, ctx: Ctx| -> Result<Claims, Rejection> {
+                ctx.auth_manager
+                    .authorize_role(&ip.ip, &cookie, role)
+                    .map_err(|err| warp::reject::custom(Unauthorized(err)))
+            },
+        )
+}
+
+#[derive(Debug)]
+struct Unauthorized(AuthorizeError);
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Replies to the requests rejected by [`with_role`].
+pub async fn recover_unauthorized(
+    err: Rejection,
+) -> Result<reply::WithStatus<reply::Json>, Rejection> {
+    match err.find::<Unauthorized>() {
+        Some(Unauthorized(e)) => Ok(reply_authorize_error(e.clone())),
+        None => Err(err),
+    }
+}
+
 fn num_users(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("num_users")
         .and(warp::get())
+        .and(with_role(ctx.clone(), Role::Operator))
         .and(with_ctx(ctx))
-        .and_then(async move |ctx: Ctx| -> Result<Response<_>, Infallible> {
-            let num_users = ctx.auth_manager.get_num_users();
-            Ok(Response::builder().body(format!("{}", num_users)).unwrap())
-        })
+        .and_then(
+            async move |_: Claims, ctx: Ctx| -> Result<Response<_>, Infallible> {
+                let num_users = ctx.auth_manager.get_num_users();
+                Ok(Response::builder().body(format!("{}", num_users)).unwrap())
+            },
+        )
 }
 
 fn config_bytes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("config")
         .and(warp::post())
+        .and(with_role(ctx.clone(), Role::Operator))
         .and(warp::body::bytes())
         .and(with_ctx(ctx))
         .and_then(
-            async move |body: bytes::Bytes, ctx: Ctx| -> Result<Response<_>, Infallible> {
+            async move |_: Claims,
+                        body: bytes::Bytes,
+                        ctx: Ctx|
+                        -> Result<Response<_>, Infallible> {
                 let instant = Instant::now();
                 let num_users = ctx.deserializer.replace(&body.to_vec());
                 let duration = instant.elapsed();
@@ -60,11 +135,21 @@ pub struct PathBody {
 fn config_path(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("config_path")
         .and(warp::post())
+        .and(with_role(ctx.clone(), Role::Operator))
         .and(json_body::<PathBody>(4))
         .and(with_ctx(ctx))
         .and_then(
-            async move |body: PathBody, ctx: Ctx| -> Result<Response<_>, Infallible> {
-                let module_bytes = std::fs::read(&body.path).unwrap();
+            async move |_: Claims,
+                        body: PathBody,
+                        ctx: Ctx|
+                        -> Result<reply::Response, Infallible> {
+                let module_bytes = match std::fs::read(&body.path) {
+                    Ok(bytes) => bytes,
+                    Err(_) => {
+                        let reply = reply_error(StatusCode::BAD_REQUEST, "Unreadable module");
+                        return Ok(reply.into_response());
+                    }
+                };
                 let instant = Instant::now();
                 ctx.deserializer.replace(&module_bytes);
                 let duration = instant.elapsed();
@@ -72,79 +157,403 @@ fn config_path(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection>
 
                 Ok(Response::builder()
                     .body(format!("{}", duration.as_millis()))
-                    .unwrap())
+                    .unwrap()
+                    .into_response())
             },
         )
 }
 
+/// Body of a signup or a login. Users sign up as traders.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Credentials {
+    pub id: String,
+    pub password: String,
+}
+
+/// Options of a login, given as query parameters.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct SessionOptions {
+    /// Cancel the orders placed in the session when it ends.
+    #[serde(default)]
+    pub cancel_on_disconnect: bool,
+}
+
 fn login(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("login")
         .and(warp::post())
-        .and(json_body::<User>(4))
-        .and(warp::query::<IpQueryParam>())
-        .and(with_ctx(ctx))
-        .and_then(
-            async move |user: User, ip: IpQueryParam, ctx: Ctx| -> Result<_, Infallible> {
-                let json: reply::Json;
-                let code;
-                let cookie: Option<String>;
-                match ctx.auth_manager.authenticate(&user, ip.ip.as_str()) {
-                    Ok(_cookie) => {
-                        // warp::http::Response::builder()
-                        //     .header(header::SET_COOKIE, cookie)
-                        //     .status(StatusCode::OK)
-                        //     .body(reply)
-                        //     .unwrap()
-                        code = StatusCode::OK;
-                        json = warp::reply::json(&"Logged in");
-                        cookie = Some(_cookie);
+        .and(json_body::<Credentials>(4))
+        .and(with_client_ip(ctx.clone()))
+        .and(warp::query::<SessionOptions>())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: Credentials,
+                        ip: ClientIp,
+                        options: SessionOptions,
+                        ctx: Ctx|
+                        -> Result<_, Infallible> {
+                match ctx
+                    .auth_manager
+                    .authenticate(&user, ip.ip.as_str(), &options)
+                {
+                    Ok(LoginStep::Session(cookies)) => {
+                        let json = warp::reply::json(&"Logged in");
+                        Ok(with_cookies(
+                            json,
+                            &[cookies.access.as_str(), cookies.refresh.as_str()],
+                        ))
+                    }
+                    Ok(LoginStep::TwoFactor(challenge)) => {
+                        let json = warp::reply::json(&challenge);
+                        Ok(reply::with_status(json, StatusCode::ACCEPTED).into_response())
                     }
-                    Err(auth_err) => {
-                        code = StatusCode::UNAUTHORIZED;
-                        let message = match auth_err {
-                            AuthenticateError::UserDoesNotExist => "User id doesn't exist",
-                            AuthenticateError::IncorrectCombination => "Incorrect combination",
-                        };
-                        let err = ErrorMessage {
-                            code: code.as_u16(),
-                            message,
-                        };
-                        json = warp::reply::json(&err);
-                        cookie = None;
+                    Err(auth_err) => Ok(reply_authenticate_error(auth_err)),
+                }
+            },
+        )
+}
+
+/// Login waiting for a code of the second factor of its user, finished
+/// with `POST /login/two_factor`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwoFactorChallenge {
+    pub challenge: String,
+    /// Seconds since the epoch.
+    pub exp: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TwoFactorLogin {
+    pub challenge: String,
+    /// Current code or a recovery code.
+    pub code: String,
+}
+
+fn login_two_factor(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("login" / "two_factor")
+        .and(warp::post())
+        .and(json_body::<TwoFactorLogin>(4))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |login: TwoFactorLogin, ip: ClientIp, ctx: Ctx| -> Result<_, Infallible> {
+                match ctx
+                    .auth_manager
+                    .verify_login(&login.challenge, &login.code, &ip.ip)
+                    .await
+                {
+                    Ok(cookies) => {
+                        let json = warp::reply::json(&"Logged in");
+                        Ok(with_cookies(
+                            json,
+                            &[cookies.access.as_str(), cookies.refresh.as_str()],
+                        ))
                     }
+                    Err(auth_err) => Ok(reply_authenticate_error(auth_err)),
+                }
+            },
+        )
+}
+
+fn reply_authenticate_error(err: AuthenticateError) -> warp::reply::Response {
+    let message = match err {
+        AuthenticateError::Locked { retry_after } => {
+            let reply = reply_error(StatusCode::TOO_MANY_REQUESTS, "Too many attempts");
+            let reply = reply::with_header(reply, header::RETRY_AFTER, retry_after.to_string());
+            return with_cookies(reply, &[DELETE_JWT_COOKIE, DELETE_REFRESH_COOKIE]);
+        }
+        AuthenticateError::UserDoesNotExist => "User id doesn't exist",
+        AuthenticateError::IncorrectCombination => "Incorrect combination",
+        AuthenticateError::InvalidChallenge => "Invalid challenge",
+        AuthenticateError::IncorrectCode => "Incorrect code",
+    };
+    let reply = reply_error(StatusCode::UNAUTHORIZED, message);
+    with_cookies(reply, &[DELETE_JWT_COOKIE, DELETE_REFRESH_COOKIE])
+}
+
+/// Secret of a second factor being enrolled, to type in or scan from
+/// `uri` into an authenticator app.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwoFactorEnrollment {
+    /// Base32.
+    pub secret: String,
+    /// `otpauth://` key URI.
+    pub uri: String,
+}
+
+/// Current password of the caller, asked for to change their second factor.
+#[derive(Deserialize, Serialize)]
+pub struct CurrentPassword {
+    pub password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TwoFactorCode {
+    pub password: String,
+    /// Current code or a recovery code.
+    pub code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TwoFactorConfirmation {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+fn enroll_two_factor(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("two_factor")
+        .and(warp::post())
+        .and(warp::cookie(JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<CurrentPassword>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        body: CurrentPassword,
+                        ctx: Ctx|
+                        -> Result<_, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(err) => return Ok(reply_authorize_error(err).into_response()),
+                };
+                Ok(
+                    match ctx
+                        .auth_manager
+                        .enroll_two_factor(&claims.user_id, &ip.ip, &body.password)
+                        .await
+                    {
+                        Ok(enrollment) => {
+                            reply::with_status(reply::json(&enrollment), StatusCode::CREATED)
+                                .into_response()
+                        }
+                        Err(err) => reply_two_factor_error(err),
+                    },
+                )
+            },
+        )
+}
+
+fn confirm_two_factor(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("two_factor" / "confirm")
+        .and(warp::post())
+        .and(warp::cookie(JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<TwoFactorConfirmation>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        body: TwoFactorConfirmation,
+                        ctx: Ctx|
+                        -> Result<_, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(err) => return Ok(reply_authorize_error(err).into_response()),
+                };
+                Ok(
+                    match ctx
+                        .auth_manager
+                        .confirm_two_factor(&claims.user_id, &ip.ip, &body.password, &body.code)
+                        .await
+                    {
+                        Ok(recovery_codes) => {
+                            reply::json(&RecoveryCodes { recovery_codes }).into_response()
+                        }
+                        Err(err) => reply_two_factor_error(err),
+                    },
+                )
+            },
+        )
+}
+
+fn disable_two_factor(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("two_factor")
+        .and(warp::delete())
+        .and(warp::cookie(JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<TwoFactorCode>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        body: TwoFactorCode,
+                        ctx: Ctx|
+                        -> Result<_, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(err) => return Ok(reply_authorize_error(err).into_response()),
                 };
-                let cookie_ref = cookie.as_ref().map_or(DELETE_JWT_COOKIE, |s| s.as_str());
+                if can_withdraw(&ctx, &claims.user_id) {
+                    return Ok(reply_two_factor_error(TwoFactorError::Required));
+                }
+                Ok(
+                    match ctx
+                        .auth_manager
+                        .disable_two_factor(&claims, &ip.ip, &body.password, &body.code)
+                        .await
+                    {
+                        Ok(cookies) => {
+                            // Orders of the ended sessions must not outlive them.
+                            offers::cancel_sessions(&ctx, &claims.user_id).await;
+                            with_cookies(
+                                StatusCode::NO_CONTENT,
+                                &[cookies.access.as_str(), cookies.refresh.as_str()],
+                            )
+                        }
+                        Err(err) => reply_two_factor_error(err),
+                    },
+                )
+            },
+        )
+}
+
+/// Withdrawals take a second factor: admins record them and API keys may
+/// be allowed to.
+fn can_withdraw(ctx: &Ctx, user_id: &str) -> bool {
+    ctx.auth_manager.role(user_id) >= Role::Admin
+        || ctx
+            .api_key_handler
+            .has_scope(user_id, Scope::Withdraw)
+            .unwrap()
+}
+
+pub(crate) fn reply_two_factor_error(err: TwoFactorError) -> warp::reply::Response {
+    let code = match &err {
+        TwoFactorError::AlreadyEnabled => StatusCode::CONFLICT,
+        TwoFactorError::NotEnrolled | TwoFactorError::NotEnabled => StatusCode::NOT_FOUND,
+        TwoFactorError::InvalidCode
+        | TwoFactorError::IncorrectPassword
+        | TwoFactorError::Required => StatusCode::FORBIDDEN,
+        TwoFactorError::Locked { retry_after } => {
+            let reply = reply_error(StatusCode::TOO_MANY_REQUESTS, "Too many attempts");
+            return reply::with_header(reply, header::RETRY_AFTER, retry_after.to_string())
+                .into_response();
+        }
+    };
+    reply::with_status(reply::json(&err), code).into_response()
+}
 
-                let r = reply::with_status(json, code);
-                Ok(reply::with_header(r, header::SET_COOKIE, cookie_ref))
+fn refresh(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("refresh")
+        .and(warp::post())
+        .and(warp::cookie(REFRESH_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |token: String, ip: ClientIp, ctx: Ctx| -> Result<_, Infallible> {
+                match ctx.auth_manager.refresh(&token, &ip.ip).await {
+                    Ok(cookies) => {
+                        let json = warp::reply::json(&"Refreshed");
+                        Ok(with_cookies(
+                            json,
+                            &[cookies.access.as_str(), cookies.refresh.as_str()],
+                        ))
+                    }
+                    Err(RefreshError::DifferentIp) => {
+                        Ok(reply_error(StatusCode::UNAUTHORIZED, "Unauthorized").into_response())
+                    }
+                    Err(_) => {
+                        let reply = reply_error(StatusCode::UNAUTHORIZED, "Unauthorized");
+                        Ok(with_cookies(
+                            reply,
+                            &[DELETE_JWT_COOKIE, DELETE_REFRESH_COOKIE],
+                        ))
+                    }
+                }
             },
         )
 }
 
+/// Adds a `Set-Cookie` header per cookie, `with_header` would keep the last.
+pub(crate) fn with_cookies<R: Reply>(reply: R, cookies: &[&str]) -> reply::Response {
+    let mut response = reply.into_response();
+    for cookie in cookies {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+    }
+    response
+}
+
 fn logout(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("logout")
         .and(warp::post())
         .and(warp::cookie(JWT_COOKIE_NAME))
         .and(with_ctx(ctx))
         .and_then(
-            async move |cookie: String,
-                        ctx: Ctx|
-                        -> Result<warp::reply::WithHeader<_>, Infallible> {
+            async move |cookie: String, ctx: Ctx| -> Result<reply::Response, Infallible> {
+                if let Ok(claims) = ctx.auth_manager.claims(&cookie) {
+                    offers::end_session(&ctx, &claims).await;
+                }
                 let reply = warp::reply::json(&"Logged out");
                 Ok(ctx.auth_manager.logout(reply, &cookie).await)
             },
         )
 }
 
+/// Logs the caller out of every session they started.
+fn logout_all(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("logout" / "all")
+        .and(warp::post())
+        .and(warp::cookie(JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String, ip: ClientIp, ctx: Ctx| -> Result<_, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(err) => return Ok(reply_authorize_error(err).into_response()),
+                };
+                ctx.auth_manager.logout_all(&claims.user_id).await;
+                offers::cancel_sessions(&ctx, &claims.user_id).await;
+                let reply = warp::reply::json(&"Logged out of every session");
+                Ok(ctx.auth_manager.remove_cookie(reply))
+            },
+        )
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RoleBody {
+    pub role: Role,
+}
+
+fn set_role(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("users" / String / "role")
+        .and(warp::put())
+        .and(with_role(ctx.clone(), Role::Admin))
+        .and(json_body::<RoleBody>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String,
+                        _: Claims,
+                        body: RoleBody,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                Ok(match ctx.auth_manager.set_role(&user, body.role).await {
+                    Ok(()) => {
+                        offers::cancel_sessions(&ctx, &user).await;
+                        Box::new(reply::json(&body))
+                    }
+                    Err(e) => Box::new(reply::with_status(reply::json(&e), StatusCode::NOT_FOUND)),
+                })
+            },
+        )
+}
+
 fn signup(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("signup")
         .and(warp::post())
-        .and(json_body::<User>(4))
-        .and(warp::query::<IpQueryParam>())
+        .and(json_body::<Credentials>(4))
+        .and(with_client_ip(ctx.clone()))
         .and(with_ctx(ctx))
         .and_then(
-            async move |user: User,
-                        ip: IpQueryParam,
+            async move |user: Credentials,
+                        ip: ClientIp,
                         ctx: Ctx|
                         -> Result<Box<dyn Reply>, Infallible> {
                 let reply = warp::reply::json(&"Signed up");
@@ -157,6 +566,17 @@ fn signup(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Cl
                             let code = StatusCode::EXPECTATION_FAILED;
                             let message = match err {
                                 SignUpError::UserAlreadyCreated => "User taken",
+                                SignUpError::InvalidUserId => {
+                                    return Ok(Box::new(reply_error(
+                                        StatusCode::BAD_REQUEST,
+                                        "Invalid user id",
+                                    )))
+                                }
+                                SignUpError::WeakPassword(violations) => {
+                                    return Ok(Box::new(reply_password_error(
+                                        PasswordError::WeakPassword { violations },
+                                    )))
+                                }
                             };
                             Box::new(reply_error(code, message))
                         }
@@ -166,7 +586,126 @@ fn signup(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Cl
         )
 }
 
-fn reply_error(code: StatusCode, message: &'static str) -> reply::WithStatus<reply::Json> {
+#[derive(Deserialize, Serialize)]
+pub struct PasswordChange {
+    pub current: String,
+    pub new: String,
+}
+
+fn change_password(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("password")
+        .and(warp::post())
+        .and(warp::cookie(JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<PasswordChange>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        change: PasswordChange,
+                        ctx: Ctx|
+                        -> Result<_, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(err) => return Ok(reply_authorize_error(err).into_response()),
+                };
+                match ctx
+                    .auth_manager
+                    .change_password(&claims, &ip.ip, &change)
+                    .await
+                {
+                    Ok(cookies) => {
+                        // Orders of the ended sessions must not outlive them.
+                        offers::cancel_sessions(&ctx, &claims.user_id).await;
+                        let json = warp::reply::json(&"Password changed");
+                        Ok(with_cookies(
+                            json,
+                            &[cookies.access.as_str(), cookies.refresh.as_str()],
+                        ))
+                    }
+                    Err(err) => Ok(reply_password_error(err)),
+                }
+            },
+        )
+}
+
+/// Token of a password reset, for an admin to hand to its user.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ResetToken {
+    pub token: String,
+    /// Seconds since the epoch.
+    pub exp: u64,
+}
+
+fn issue_reset(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("users" / String / "password_reset")
+        .and(warp::post())
+        .and(with_role(ctx.clone(), Role::Admin))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String, _: Claims, ctx: Ctx| -> Result<_, Infallible> {
+                Ok(match ctx.auth_manager.issue_reset(&user).await {
+                    Ok(token) => {
+                        reply::with_status(reply::json(&token), StatusCode::CREATED).into_response()
+                    }
+                    Err(err) => reply_password_error(err),
+                })
+            },
+        )
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PasswordResetBody {
+    pub user_id: String,
+    pub token: String,
+    pub new: String,
+}
+
+fn reset_password(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("password" / "reset")
+        .and(warp::post())
+        .and(json_body::<PasswordResetBody>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |reset: PasswordResetBody, ctx: Ctx| -> Result<_, Infallible> {
+                Ok(match ctx.auth_manager.reset_password(&reset).await {
+                    Ok(()) => {
+                        offers::cancel_sessions(&ctx, &reset.user_id).await;
+                        reply::json(&"Password reset").into_response()
+                    }
+                    Err(err) => reply_password_error(err),
+                })
+            },
+        )
+}
+
+fn reply_password_error(err: PasswordError) -> warp::reply::Response {
+    let code = match &err {
+        PasswordError::WeakPassword { .. } | PasswordError::InvalidResetToken => {
+            StatusCode::BAD_REQUEST
+        }
+        PasswordError::IncorrectPassword => StatusCode::FORBIDDEN,
+        PasswordError::UnknownUser => StatusCode::NOT_FOUND,
+        PasswordError::Locked { retry_after } => {
+            let reply = reply_error(StatusCode::TOO_MANY_REQUESTS, "Too many attempts");
+            return reply::with_header(reply, header::RETRY_AFTER, retry_after.to_string())
+                .into_response();
+        }
+    };
+    reply::with_status(reply::json(&err), code).into_response()
+}
+
+pub(crate) fn reply_authorize_error(err: AuthorizeError) -> reply::WithStatus<reply::Json> {
+    match err {
+        AuthorizeError::Forbidden => reply_error(StatusCode::FORBIDDEN, "Forbidden"),
+        _ => reply_error(StatusCode::UNAUTHORIZED, "Unauthorized"),
+    }
+}
+
+pub(crate) fn reply_error(
+    code: StatusCode,
+    message: &'static str,
+) -> reply::WithStatus<reply::Json> {
     let err = ErrorMessage {
         code: code.as_u16(),
         message,
@@ -178,19 +717,79 @@ fn reply_error(code: StatusCode, message: &'static str) -> reply::WithStatus<rep
 #[derive(Debug)]
 pub enum SignUpError {
     UserAlreadyCreated,
+    /// Empty or with a zero byte.
+    InvalidUserId,
+    WeakPassword(Vec<PasswordViolation>),
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum PasswordError {
+    /// The new password breaks the rules of the policy.
+    WeakPassword {
+        violations: Vec<PasswordViolation>,
+    },
+    IncorrectPassword,
+    /// Unknown, used or expired.
+    InvalidResetToken,
+    UnknownUser,
+    /// Too many failed attempts of the user or from the IP.
+    Locked {
+        retry_after: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub enum AuthorizeError {
     DifferentIp,
     InvalidToken,
     BlackListedToken,
+    Forbidden,
+}
+
+#[derive(Debug, Clone)]
+pub enum RefreshError {
+    InvalidToken,
+    DifferentIp,
+    /// An already exchanged token was presented, its family is revoked.
+    TokenReused,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum RoleError {
+    UnknownUser,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum TwoFactorError {
+    AlreadyEnabled,
+    /// No enrollment to confirm.
+    NotEnrolled,
+    NotEnabled,
+    InvalidCode,
+    IncorrectPassword,
+    /// Too many wrong passwords of the user or from the IP.
+    Locked {
+        retry_after: u64,
+    },
+    /// Users who can withdraw must have a second factor, and withdraw from
+    /// sessions that passed it.
+    Required,
 }
 
 #[derive(Debug)]
 pub enum AuthenticateError {
     UserDoesNotExist,
     IncorrectCombination,
+    /// Unknown, expired, from another IP or given too many wrong codes.
+    InvalidChallenge,
+    IncorrectCode,
+    /// Too many failed logins of the user or from the IP.
+    Locked {
+        retry_after: u64,
+    },
 }
 
 #[derive(Serialize)]
@@ -0,0 +1,73 @@
+use crate::config::PasswordPolicy;
+use serde::{Deserialize, Serialize};
+
+/// A rule of the [`PasswordPolicy`] a password breaks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "rule")]
+pub enum PasswordViolation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    MissingLetter,
+    MissingDigit,
+    MissingSymbol,
+    ContainsUserId,
+}
+
+/// Every rule of `policy` that `password` of `user_id` breaks, none when
+/// it can be used.
+pub fn check(policy: &PasswordPolicy, user_id: &str, password: &str) -> Vec<PasswordViolation> {
+    let mut violations = Vec::new();
+    if password.len() < policy.min_length {
+        violations.push(PasswordViolation::TooShort {
+            min_length: policy.min_length,
+        });
+    }
+    if password.len() > policy.max_length {
+        violations.push(PasswordViolation::TooLong {
+            max_length: policy.max_length,
+        });
+    }
+    if policy.require_letter && !password.chars().any(char::is_alphabetic) {
+        violations.push(PasswordViolation::MissingLetter);
+    }
+    if policy.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+        violations.push(PasswordViolation::MissingDigit);
+    }
+    if policy.require_symbol && password.chars().all(char::is_alphanumeric) {
+        violations.push(PasswordViolation::MissingSymbol);
+    }
+    if policy.forbid_user_id
+        && !user_id.is_empty()
+        && password.to_lowercase().contains(&user_id.to_lowercase())
+    {
+        violations.push(PasswordViolation::ContainsUserId);
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(check(&policy, "alice", "correct horse 42"), vec![]);
+        assert_eq!(
+            check(&policy, "alice", ""),
+            vec![
+                PasswordViolation::TooShort { min_length: 10 },
+                PasswordViolation::MissingLetter,
+                PasswordViolation::MissingDigit,
+            ]
+        );
+        assert_eq!(
+            check(&policy, "alice", "ALICE-1234567"),
+            vec![PasswordViolation::ContainsUserId]
+        );
+        assert_eq!(
+            check(&policy, "bob", &"a1".repeat(40)),
+            vec![PasswordViolation::TooLong { max_length: 72 }]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use ring::hmac;
+
+/// Shown by authenticator apps next to the account.
+pub const ISSUER: &str = "reto2";
+pub const STEP_SECS: u64 = 30;
+pub const DIGITS: u32 = 6;
+/// Steps a code is still accepted before or after the current one, for
+/// clocks that drift.
+const SKEW_STEPS: u64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Random secret of the length of an HMAC-SHA1 key, as RFC 4226 advises.
+pub fn generate_secret() -> Vec<u8> {
+    rand::random::<[u8; 20]>().to_vec()
+}
+
+/// Step of the codes at `now`, seconds since the epoch.
+pub fn step(now: u64) -> u64 {
+    now / STEP_SECS
+}
+
+/// Step of the first code that matches `code`, only steps after
+/// `last_step` count so a code can't be used twice.
+pub fn verify(secret: &[u8], code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = step(now);
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+        .filter(|step| *step > last_step)
+        .find(|step| hotp(secret, *step, DIGITS) == code)
+}
+
+/// Code an authenticator app shows at `now`.
+#[cfg(test)]
+pub fn code(secret: &[u8], now: u64) -> String {
+    format!("{:06}", hotp(secret, step(now), DIGITS))
+}
+
+/// Key URI understood by authenticator apps, usually shown as a QR code.
+pub fn uri(account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(ISSUER),
+        account = percent_encode(account),
+        secret = base32(secret),
+        digits = DIGITS,
+        period = STEP_SECS,
+    )
+}
+
+/// RFC 4648 base32 without padding, how secrets are typed in.
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[usize::from((buffer >> bits) & 31)] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 31)] as char);
+    }
+    encoded
+}
+
+/// RFC 4226 code of `counter`.
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = usize::from(hash[hash.len() - 1] & 0xf);
+    let binary = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    binary % 10u32.pow(digits)
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_vectors() {
+        assert_eq!(hotp(SECRET, 0, 6), 755224);
+        assert_eq!(hotp(SECRET, 1, 6), 287082);
+        // RFC 6238, SHA-1.
+        assert_eq!(hotp(SECRET, step(59), 8), 94287082);
+        assert_eq!(hotp(SECRET, step(1111111109), 8), 7081804);
+        assert_eq!(hotp(SECRET, step(2000000000), 8), 69279037);
+
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn verify_once() {
+        let now = 1111111109;
+        assert_eq!(verify(SECRET, "081804", now, 0), Some(step(now)));
+        assert_eq!(
+            verify(SECRET, "081804", now + STEP_SECS, 0),
+            Some(step(now))
+        );
+        assert_eq!(verify(SECRET, "081804", now, step(now)), None);
+        assert_eq!(verify(SECRET, "81804", now, 0), None);
+    }
+}
@@ -0,0 +1,461 @@
+use crate::balances::{
+    Balance, BalanceKey, EntryKind, FundsError, IdempotencyKey, IdempotencyRecord, JournalEntry,
+    JournalKey, JournalRecord, LedgerError, MovementRequest, UserJournalKey,
+};
+use crate::engine::Fill;
+use crate::instruments::Instrument;
+use crate::offers::{OfferEventKey, Order, Side};
+use crate::prelude::*;
+use crate::utils::now_in_secs;
+use sled::{
+    abort, ConflictableTransactionResult, TransactionError, Transactional, TransactionalTree,
+};
+use std::convert::TryFrom;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+#[derive(Clone)]
+pub struct BalanceHandler {
+    pub(crate) balances_db: sled::Tree,
+    pub(crate) journal_db: sled::Tree,
+    pub(crate) journal_index_db: sled::Tree,
+    idempotency_db: sled::Tree,
+    journal_counter: Arc<AtomicU64>,
+}
+
+/// Trees of the ledger as seen from inside a transaction.
+pub struct LedgerTx<'a> {
+    pub balances: &'a TransactionalTree,
+    pub journal: &'a TransactionalTree,
+    pub journal_index: &'a TransactionalTree,
+}
+
+impl BalanceHandler {
+    pub fn new(db: sled::Db) -> Self {
+        let mut journal_db = db.open_tree(<JournalKey as KeyOf>::PREFIX).unwrap();
+        let journal_counter = Arc::new(AtomicU64::from(
+            <sled::Tree as MonotonicTypedTree<JournalKey>>::get_max_key(&mut journal_db).unwrap(),
+        ));
+
+        Self {
+            balances_db: db.open_tree(<BalanceKey as KeyOf>::PREFIX).unwrap(),
+            journal_db,
+            journal_index_db: db.open_tree(<UserJournalKey as KeyOf>::PREFIX).unwrap(),
+            idempotency_db: db.open_tree(<IdempotencyKey as KeyOf>::PREFIX).unwrap(),
+            journal_counter,
+        }
+    }
+
+    pub fn get(&self, user: &str, asset: &str) -> sled::Result<Balance> {
+        self.balances_db
+            .get_typed(&BalanceKey::new(user, asset))
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Moves the funds an order needs from available to held, failing if
+    /// its owner doesn't have them.
+    pub fn hold(&self, instrument: &Instrument, order: &Order) -> Result<(), FundsError> {
+        if order.value.side == Side::Buy && order.value.price.is_none() {
+            return Err(FundsError::PriceRequired);
+        }
+        let required = order.hold();
+        let asset = instrument.hold_asset(order.value.side);
+        let key = BalanceKey::new(&order.owner, asset);
+
+        let result = self.balances_db.transaction(|balances| {
+            let mut balance: Balance = balances.get_typed(&key)?.unwrap_or_default();
+            if balance.available < required {
+                return abort(FundsError::InsufficientFunds {
+                    asset: asset.to_string(),
+                    available: balance.available,
+                    required,
+                });
+            }
+            balance.available -= required;
+            balance.held += required;
+            balances.insert_typed(&key, balance)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    pub fn deposit(&self, request: &MovementRequest) -> Result<JournalRecord, LedgerError> {
+        let kind = EntryKind::Deposit {
+            idempotency_key: request.idempotency_key.clone(),
+        };
+        self.apply_movement(request, kind, request.amount, 0)
+    }
+
+    pub fn withdraw(&self, request: &MovementRequest) -> Result<JournalRecord, LedgerError> {
+        let kind = EntryKind::Withdrawal {
+            idempotency_key: request.idempotency_key.clone(),
+        };
+        self.apply_movement(request, kind, 0, request.amount)
+    }
+
+    fn apply_movement(
+        &self,
+        request: &MovementRequest,
+        kind: EntryKind,
+        credit: u64,
+        debit: u64,
+    ) -> Result<JournalRecord, LedgerError> {
+        if request.amount == 0 || request.idempotency_key.is_empty() {
+            return Err(LedgerError::InvalidMovement);
+        }
+        let entry = JournalEntry {
+            user: request.user.clone(),
+            asset: request.asset.clone(),
+            kind,
+            credit,
+            debit,
+            time: now_in_secs(),
+        };
+        let balance_key = BalanceKey::new(&entry.user, &entry.asset);
+        let idempotency_key = IdempotencyKey(&request.idempotency_key);
+
+        let result = (
+            &self.balances_db,
+            &self.journal_db,
+            &self.journal_index_db,
+            &self.idempotency_db,
+        )
+            .transaction(|(balances, journal, journal_index, idempotency)| {
+                if let Some(record) = idempotency.get_typed(&idempotency_key)? {
+                    let first = journal
+                        .get_typed(&JournalKey::from(record.journal_id))?
+                        .expect("Idempotency record without journal entry");
+                    return Ok((record.journal_id, first));
+                }
+
+                let mut balance: Balance = balances.get_typed(&balance_key)?.unwrap_or_default();
+                if balance.available < debit {
+                    return abort(LedgerError::InsufficientFunds {
+                        asset: entry.asset.clone(),
+                        available: balance.available,
+                        required: debit,
+                    });
+                }
+                balance.available = match (balance.available - debit).checked_add(credit) {
+                    Some(available) => available,
+                    None => return abort(LedgerError::BalanceOverflow),
+                };
+                balances.insert_typed(&balance_key, balance)?;
+
+                let tx = LedgerTx {
+                    balances,
+                    journal,
+                    journal_index,
+                };
+                let journal_id = u64::from(self.record(&tx, entry.clone())?);
+                idempotency.insert_typed(&idempotency_key, IdempotencyRecord { journal_id })?;
+                Ok((journal_id, entry.clone()))
+            });
+
+        match result {
+            Ok((id, first)) => {
+                let same_movement = first.user == entry.user
+                    && first.asset == entry.asset
+                    && first.kind == entry.kind
+                    && first.credit == entry.credit
+                    && first.debit == entry.debit;
+                if same_movement {
+                    Ok(JournalRecord { id, entry: first })
+                } else {
+                    Err(LedgerError::IdempotencyKeyReused)
+                }
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Journal entries of a user, oldest first.
+    pub fn history(&self, user: &str) -> sled::Result<Vec<JournalRecord>> {
+        self.journal_index_db
+            .scan_prefix(user_prefix(user))
+            .values()
+            .map(|key| {
+                let key = JournalKey::try_from(key?)?;
+                let entry = self
+                    .journal_db
+                    .get_typed(&key)?
+                    .expect("Journal index without entry");
+                Ok(JournalRecord {
+                    id: key.into(),
+                    entry,
+                })
+            })
+            .collect()
+    }
+
+    fn record<E>(&self, tx: &LedgerTx, entry: JournalEntry) -> ConflictableTransactionResult<JournalKey, E> {
+        let key = JournalKey::from(self.journal_counter.fetch_add(1, Ordering::SeqCst));
+        tx.journal_index
+            .insert_typed(&UserJournalKey::new(&entry.user, &key), key.clone())?;
+        tx.journal.insert_typed(&key, entry)?;
+        Ok(key)
+    }
+
+    /// Applies one side of a fill to the balances of a funded order: frees the
+    /// hold of the executed amount, pays the counterpart, returns the
+    /// difference between the limit and the execution price to the buyer,
+    /// charges the fee in the quote asset and journals every change.
+    #[allow(clippy::too_many_arguments)]
+    pub fn settle<E>(
+        &self,
+        tx: &LedgerTx,
+        key: &OfferEventKey,
+        order: &Order,
+        instrument: &Instrument,
+        fill: &Fill,
+        released: u64,
+        fee: u64,
+    ) -> ConflictableTransactionResult<(), E> {
+        let amount = fill.maker.value.amount;
+        let notional = fill.notional().unwrap_or(0);
+        let (base_credit, base_debit, quote_credit, quote_debit) = match order.value.side {
+            Side::Buy => {
+                update_balance(tx.balances, &order.owner, &instrument.quote, |b| {
+                    b.held -= released;
+                    b.available += released - notional - fee;
+                })?;
+                update_balance(tx.balances, &order.owner, &instrument.base, |b| {
+                    b.available += amount;
+                })?;
+                (amount, 0, 0, notional)
+            }
+            Side::Sell => {
+                update_balance(tx.balances, &order.owner, &instrument.base, |b| {
+                    b.held -= released;
+                })?;
+                update_balance(tx.balances, &order.owner, &instrument.quote, |b| {
+                    b.available += notional - fee;
+                })?;
+                (0, amount, notional, 0)
+            }
+        };
+
+        let time = now_in_secs();
+        let order_id = u64::from(key.clone());
+        let entry = |kind, asset: &str, credit, debit| JournalEntry {
+            user: order.owner.clone(),
+            asset: asset.to_string(),
+            kind,
+            credit,
+            debit,
+            time,
+        };
+        let trade = || EntryKind::Trade { order: order_id };
+        self.record(tx, entry(trade(), &instrument.base, base_credit, base_debit))?;
+        self.record(tx, entry(trade(), &instrument.quote, quote_credit, quote_debit))?;
+        if fee > 0 {
+            let kind = EntryKind::Fee { order: order_id };
+            self.record(tx, entry(kind, &instrument.quote, 0, fee))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the hold freed by a cancelled order to the available funds.
+    pub fn release<E>(
+        &self,
+        tx: &LedgerTx,
+        order: &Order,
+        instrument: &Instrument,
+        released: u64,
+    ) -> ConflictableTransactionResult<(), E> {
+        let asset = instrument.hold_asset(order.value.side);
+        update_balance(tx.balances, &order.owner, asset, |b| {
+            b.held -= released;
+            b.available += released;
+        })
+    }
+}
+
+fn update_balance<E, F>(
+    balances: &TransactionalTree,
+    user: &str,
+    asset: &str,
+    f: F,
+) -> ConflictableTransactionResult<(), E>
+where
+    F: FnOnce(&mut Balance),
+{
+    let key = BalanceKey::new(user, asset);
+    let mut balance: Balance = balances.get_typed(&key)?.unwrap_or_default();
+    f(&mut balance);
+    balances.insert_typed(&key, balance)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruments::InstrumentRequest;
+    use crate::offers::{Offer, OfferValue, Security};
+
+    #[test]
+    fn hold_and_settle() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = BalanceHandler::new(db);
+        let instrument = InstrumentRequest::with_defaults("BTC", "USD")
+            .into_instrument(Security(1))
+            .unwrap();
+        let funds = Balance {
+            available: 100,
+            held: 0,
+        };
+        handler
+            .balances_db
+            .insert_typed(&BalanceKey::new("buyer", "USD"), funds)
+            .unwrap();
+
+        let value = OfferValue {
+            security: Security(1),
+            side: Side::Buy,
+            amount: 10,
+            price: Some(12),
+        };
+        // Reserving a 10% fee on top of the notional
+        let order = Order::new("buyer", value.clone(), true, 1_000);
+        assert_eq!(
+            handler.hold(&instrument, &order),
+            Err(FundsError::InsufficientFunds {
+                asset: "USD".to_string(),
+                available: 100,
+                required: 132,
+            })
+        );
+        let value = OfferValue {
+            price: Some(8),
+            ..value
+        };
+        let mut order = Order::new("buyer", value.clone(), true, 1_000);
+        assert_eq!(handler.hold(&instrument, &order), Ok(()));
+        assert_eq!(
+            handler.get("buyer", "USD").unwrap(),
+            Balance {
+                available: 12,
+                held: 88
+            }
+        );
+
+        // Buy 4 at the price of a resting sell of 5
+        let fill = Fill {
+            maker: Offer {
+                key: OfferEventKey::from(1),
+                value: OfferValue {
+                    side: Side::Sell,
+                    amount: 4,
+                    price: Some(5),
+                    ..value.clone()
+                },
+            },
+            taker: Offer {
+                key: OfferEventKey::from(2),
+                value: OfferValue {
+                    amount: 4,
+                    ..value.clone()
+                },
+            },
+        };
+        let released = order.fill(4);
+        assert_eq!(released, 36);
+        let trees = (
+            &handler.balances_db,
+            &handler.journal_db,
+            &handler.journal_index_db,
+        );
+        trees
+            .transaction(|(balances, journal, journal_index)| {
+                let tx = LedgerTx {
+                    balances,
+                    journal,
+                    journal_index,
+                };
+                handler.settle(&tx, &fill.taker.key, &order, &instrument, &fill, released, 2)
+            })
+            .unwrap();
+
+        assert_eq!(
+            handler.get("buyer", "USD").unwrap(),
+            Balance {
+                available: 26,
+                held: 52
+            }
+        );
+        assert_eq!(handler.get("buyer", "BTC").unwrap().available, 4);
+
+        let history = handler.history("buyer").unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].entry.credit, 4);
+        assert_eq!(history[1].entry.debit, 20);
+        assert_eq!(history[2].entry.kind, EntryKind::Fee { order: 2 });
+        assert_eq!(history[2].entry.debit, 2);
+    }
+
+    #[test]
+    fn idempotent_movements() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = BalanceHandler::new(db);
+        let mut request = MovementRequest {
+            user: "user".to_string(),
+            asset: "USD".to_string(),
+            amount: 50,
+            idempotency_key: "deposit-1".to_string(),
+        };
+
+        let first = handler.deposit(&request).unwrap();
+        let retry = handler.deposit(&request).unwrap();
+        assert_eq!(first.id, retry.id);
+        assert_eq!(handler.get("user", "USD").unwrap().available, 50);
+
+        request.amount = 60;
+        assert_eq!(
+            handler.deposit(&request),
+            Err(LedgerError::IdempotencyKeyReused)
+        );
+
+        request.idempotency_key = "withdrawal-1".to_string();
+        assert_eq!(
+            handler.withdraw(&request).map(|r| r.id),
+            Err(LedgerError::InsufficientFunds {
+                asset: "USD".to_string(),
+                available: 50,
+                required: 60,
+            })
+        );
+        request.amount = 20;
+        handler.withdraw(&request).unwrap();
+        assert_eq!(handler.get("user", "USD").unwrap().available, 30);
+        assert_eq!(handler.history("user").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn overflowing_deposit() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = BalanceHandler::new(db);
+        let mut request = MovementRequest {
+            user: "user".to_string(),
+            asset: "USD".to_string(),
+            amount: u64::MAX,
+            idempotency_key: "deposit-1".to_string(),
+        };
+        handler.deposit(&request).unwrap();
+
+        request.amount = 1;
+        request.idempotency_key = "deposit-2".to_string();
+        assert_eq!(
+            handler.deposit(&request).map(|r| r.id),
+            Err(LedgerError::BalanceOverflow)
+        );
+        assert_eq!(handler.get("user", "USD").unwrap().available, u64::MAX);
+        assert_eq!(handler.history("user").unwrap().len(), 1);
+    }
+}
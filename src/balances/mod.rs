@@ -0,0 +1,109 @@
+mod handler;
+mod model;
+
+use crate::{
+    api_keys::{self, Caller, Scope},
+    auth::{self, AuthorizeError},
+    user::Role,
+    with_client_ip, with_ctx, ClientIp, Ctx,
+};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub use handler::{BalanceHandler, LedgerTx};
+pub use model::{
+    Balance, BalanceKey, EntryKind, FundsError, IdempotencyKey, IdempotencyRecord, JournalEntry,
+    JournalKey, JournalRecord, LedgerError, MovementDenied, MovementRequest, UserJournalKey,
+};
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    movement(ctx.clone(), "deposits", BalanceHandler::deposit, false)
+        .or(movement(ctx.clone(), "withdrawals", BalanceHandler::withdraw, true))
+        .or(ledger(ctx))
+}
+
+/// Movements are recorded by admins, withdrawals also by API keys allowing
+/// them, see [`MovementRequest::check_caller`].
+fn movement(
+    ctx: Ctx,
+    path: &'static str,
+    apply: fn(&BalanceHandler, &MovementRequest) -> Result<JournalRecord, LedgerError>,
+    withdrawal: bool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path(path)
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(api_keys::with_caller(ctx.clone(), Scope::Withdraw, 4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |caller: Result<Caller, AuthorizeError>,
+                        body: bytes::Bytes,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let caller = match caller {
+                    Ok(caller) => caller,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                let request: MovementRequest = match serde_json::from_slice(&body) {
+                    Ok(request) => request,
+                    Err(_) => return Ok(Box::new(StatusCode::BAD_REQUEST)),
+                };
+                let role = |owner: &str| ctx.auth_manager.role(owner);
+                match request.check_caller(&caller, withdrawal, role) {
+                    Ok(()) => {}
+                    Err(MovementDenied::Forbidden) => {
+                        return Ok(Box::new(auth::reply_authorize_error(
+                            AuthorizeError::Forbidden,
+                        )))
+                    }
+                    Err(MovementDenied::TwoFactorRequired) => {
+                        return Ok(Box::new(auth::reply_two_factor_error(
+                            auth::TwoFactorError::Required,
+                        )))
+                    }
+                }
+                if !ctx.instrument_handler.has_asset(&request.asset).unwrap() {
+                    return Ok(Box::new(reply::with_status(
+                        reply::json(&LedgerError::UnknownAsset),
+                        StatusCode::BAD_REQUEST,
+                    )));
+                }
+                Ok(match apply(&ctx.balance_handler, &request) {
+                    Ok(record) => Box::new(reply::json(&record)),
+                    Err(e @ LedgerError::IdempotencyKeyReused) => {
+                        Box::new(reply::with_status(reply::json(&e), StatusCode::CONFLICT))
+                    }
+                    Err(e) => {
+                        Box::new(reply::with_status(reply::json(&e), StatusCode::BAD_REQUEST))
+                    }
+                })
+            },
+        )
+}
+
+fn ledger(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("ledger" / String)
+        .and(warp::get())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                if claims.user_id != user && claims.role < Role::Admin {
+                    return Ok(Box::new(auth::reply_authorize_error(
+                        AuthorizeError::Forbidden,
+                    )));
+                }
+                let history = ctx.balance_handler.history(&user).unwrap();
+                Ok(Box::new(reply::json(&history)))
+            },
+        )
+}
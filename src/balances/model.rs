@@ -0,0 +1,232 @@
+use crate::{
+    api_keys::Caller,
+    bincode_des, bincode_ser, derive_key_of, derive_monotonic_key, derive_simple_struct,
+    typed_tree::{user_key, KeyOf},
+    user::Role,
+};
+use serde::{Deserialize, Serialize};
+
+/// Key of the balance of `asset` owned by `user`, the user id comes first so
+/// all the balances of a user share a prefix.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct BalanceKey(Vec<u8>);
+
+impl BalanceKey {
+    pub fn new(user: &str, asset: &str) -> Self {
+        BalanceKey(user_key(user, asset.as_bytes()))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for BalanceKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Funds of a user in one asset. `held` is reserved by open orders and
+/// can't be used until they are filled or cancelled.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct Balance {
+    pub available: u64,
+    pub held: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum FundsError {
+    InsufficientFunds {
+        asset: String,
+        available: u64,
+        required: u64,
+    },
+    /// Buy orders need a limit price to know how much to hold.
+    PriceRequired,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct JournalKey(pub [u8; 8]);
+derive_monotonic_key!(JournalKey);
+
+/// Index of the journal entries of a user: user id, a zero byte and the
+/// journal key, so a prefix scan returns them in order.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserJournalKey(Vec<u8>);
+
+impl UserJournalKey {
+    pub fn new(user: &str, key: &JournalKey) -> Self {
+        UserJournalKey(user_key(user, &key.0))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for UserJournalKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct IdempotencyKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for IdempotencyKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Immutable record of a change in the total funds of a user.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct JournalEntry {
+    pub user: String,
+    pub asset: String,
+    pub kind: EntryKind,
+    pub credit: u64,
+    pub debit: u64,
+    pub time: u64,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub enum EntryKind {
+    Deposit { idempotency_key: String },
+    Withdrawal { idempotency_key: String },
+    /// Settlement of a fill of the order with the given key.
+    Trade { order: u64 },
+    /// Fee charged on a fill of the order with the given key.
+    Fee { order: u64 },
+}
+
+/// Journal entry created by the first request with an idempotency key.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct IdempotencyRecord {
+    pub journal_id: u64,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct JournalRecord {
+    pub id: u64,
+    #[serde(flatten)]
+    pub entry: JournalEntry,
+}
+
+/// Deposit or withdrawal requested by an operator. Retrying with the same
+/// `idempotency_key` returns the entry of the first request.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MovementRequest {
+    pub user: String,
+    pub asset: String,
+    pub amount: u64,
+    pub idempotency_key: String,
+}
+
+impl MovementRequest {
+    /// Whether `caller` may record the movement, a withdrawal if
+    /// `withdrawal`. Sessions of admins record any, withdrawals once they
+    /// passed a second factor. API keys, already checked for the Withdraw
+    /// scope, only record withdrawals of their owner unless `role` of the
+    /// owner is admin.
+    pub fn check_caller(
+        &self,
+        caller: &Caller,
+        withdrawal: bool,
+        role: impl FnOnce(&str) -> Role,
+    ) -> Result<(), MovementDenied> {
+        let allowed = match caller {
+            Caller::Session(claims) => {
+                if withdrawal && !claims.two_factor {
+                    return Err(MovementDenied::TwoFactorRequired);
+                }
+                claims.role >= Role::Admin
+            }
+            Caller::ApiKey { owner, .. } => {
+                withdrawal && (*owner == self.user || role(owner) >= Role::Admin)
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(MovementDenied::Forbidden)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MovementDenied {
+    Forbidden,
+    TwoFactorRequired,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum LedgerError {
+    InvalidMovement,
+    UnknownAsset,
+    InsufficientFunds {
+        asset: String,
+        available: u64,
+        required: u64,
+    },
+    IdempotencyKeyReused,
+    /// The movement would take a balance past the largest amount.
+    BalanceOverflow,
+}
+
+derive_key_of!(BalanceKey, Balance, "Balance", 6);
+derive_key_of!(JournalKey, JournalEntry, "Journal", 8);
+derive_key_of!(UserJournalKey, JournalKey, "UserJournal", 9);
+derive_key_of!(IdempotencyKey<'_>, IdempotencyRecord, "Idempotency", 10);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Claims;
+
+    fn session(role: Role, two_factor: bool) -> Caller {
+        Caller::Session(Claims {
+            exp: 0,
+            user_id: "admin".to_string(),
+            ip: "127.0.0.1".to_string(),
+            session: String::new(),
+            cancel_on_disconnect: false,
+            jti: String::new(),
+            generation: 0,
+            role,
+            two_factor,
+        })
+    }
+
+    fn key(owner: &str) -> Caller {
+        Caller::ApiKey {
+            id: "key".to_string(),
+            owner: owner.to_string(),
+        }
+    }
+
+    #[test]
+    fn movement_callers() {
+        let request = MovementRequest {
+            user: "alice".to_string(),
+            asset: "BTC".to_string(),
+            amount: 1,
+            idempotency_key: "a".to_string(),
+        };
+        let trader = |_: &str| Role::Trader;
+        let check = |caller: Caller, withdrawal| request.check_caller(&caller, withdrawal, trader);
+
+        assert_eq!(check(session(Role::Admin, false), false), Ok(()));
+        assert_eq!(
+            check(session(Role::Admin, false), true),
+            Err(MovementDenied::TwoFactorRequired)
+        );
+        assert_eq!(check(session(Role::Admin, true), true), Ok(()));
+        assert_eq!(
+            check(session(Role::Trader, true), true),
+            Err(MovementDenied::Forbidden)
+        );
+
+        // Keys withdraw for their owner only, and never deposit.
+        assert_eq!(check(key("alice"), true), Ok(()));
+        assert_eq!(check(key("alice"), false), Err(MovementDenied::Forbidden));
+        assert_eq!(check(key("bob"), true), Err(MovementDenied::Forbidden));
+        let admin = request.check_caller(&key("bob"), true, |_| Role::Admin);
+        assert_eq!(admin, Ok(()));
+    }
+}
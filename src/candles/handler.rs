@@ -0,0 +1,121 @@
+use crate::candles::{Candle, CandleKey, CandleRecord, Interval};
+use crate::offers::Security;
+use crate::prelude::*;
+use sled::{ConflictableTransactionResult, TransactionError};
+use std::convert::TryFrom;
+
+/// Most candles returned by a query, from the oldest.
+pub const MAX_CANDLES: usize = 1000;
+
+#[derive(Clone)]
+pub struct CandleHandler {
+    candles_db: sled::Tree,
+}
+
+impl CandleHandler {
+    pub fn new(db: sled::Db) -> Self {
+        Self {
+            candles_db: db.open_tree(<CandleKey as KeyOf>::PREFIX).unwrap(),
+        }
+    }
+
+    /// Adds a trade to the candles of every interval it falls in. Trades
+    /// must come in time order.
+    pub fn record(
+        &self,
+        security: Security,
+        price: u64,
+        amount: u64,
+        time: u64,
+    ) -> sled::Result<()> {
+        self.candles_db
+            .transaction(|candles| -> ConflictableTransactionResult<(), ()> {
+                for interval in Interval::ALL.iter() {
+                    let key = CandleKey::new(security, *interval, interval.start(time));
+                    let candle = match candles.get_typed(&key)? {
+                        Some(mut candle) => {
+                            candle.add(price, amount);
+                            candle
+                        }
+                        None => Candle::new(price, amount),
+                    };
+                    candles.insert_typed(&key, candle)?;
+                }
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => e,
+                TransactionError::Abort(()) => unreachable!(),
+            })
+    }
+
+    /// Candles of `security` starting between `from` and `to`.
+    pub fn get(
+        &self,
+        security: Security,
+        interval: Interval,
+        from: u64,
+        to: u64,
+    ) -> sled::Result<Vec<CandleRecord>> {
+        let start = CandleKey::new(security, interval, interval.start(from));
+        let end = CandleKey::new(security, interval, to);
+        self.candles_db
+            .range(start.as_ref()..=end.as_ref())
+            .take(MAX_CANDLES)
+            .map(|item| {
+                let (key, value) = item?;
+                let mut start = [0; 8];
+                start.copy_from_slice(&key[9..]);
+                Ok(CandleRecord {
+                    time: u64::from_be_bytes(start),
+                    candle: Candle::try_from(value)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candles() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = CandleHandler::new(db);
+        let (security, hour) = (Security(1), 1_600_002_000);
+        handler.record(security, 10, 2, hour + 5).unwrap();
+        handler.record(security, 12, 1, hour + 30).unwrap();
+        handler.record(security, 9, 4, hour + 61).unwrap();
+        handler.record(Security(2), 50, 1, hour + 62).unwrap();
+
+        let minutes = handler
+            .get(security, Interval::OneMinute, hour, hour + 120)
+            .unwrap();
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(minutes[0].time, hour);
+        assert_eq!(
+            minutes[0].candle,
+            Candle {
+                open: 10,
+                high: 12,
+                low: 10,
+                close: 12,
+                volume: 3,
+                trades: 2
+            }
+        );
+        assert_eq!(minutes[1].time, hour + 60);
+
+        let hours = handler
+            .get(security, Interval::OneHour, hour + 30, hour + 30)
+            .unwrap();
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours[0].candle.low, 9);
+        assert_eq!(hours[0].candle.volume, 7);
+        assert!(handler
+            .get(security, Interval::OneMinute, hour + 120, hour + 600)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+mod handler;
+mod model;
+
+use crate::{utils::now_in_secs, with_ctx, Ctx};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub use handler::{CandleHandler, MAX_CANDLES};
+pub use model::{Candle, CandleError, CandleKey, CandleQuery, CandleRecord, Interval};
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    get_candles(ctx)
+}
+
+fn get_candles(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("candles" / String)
+        .and(warp::get())
+        .and(warp::query::<CandleQuery>())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |symbol: String,
+                        query: CandleQuery,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let instrument = match ctx.instrument_handler.get_by_symbol(&symbol).unwrap() {
+                    Some(instrument) => instrument,
+                    None => {
+                        return Ok(Box::new(reply::with_status(
+                            reply::json(&CandleError::UnknownSecurity),
+                            StatusCode::NOT_FOUND,
+                        )))
+                    }
+                };
+                let to = query.to.unwrap_or_else(now_in_secs);
+                let from = query.from.unwrap_or(0);
+                let candles = ctx
+                    .candle_handler
+                    .get(instrument.security, query.interval, from, to)
+                    .unwrap();
+                Ok(Box::new(reply::json(&candles)))
+            },
+        )
+}
@@ -0,0 +1,123 @@
+use crate::{bincode_des, bincode_ser, derive_key_of, offers::Security, typed_tree::KeyOf};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 4] = [
+        Interval::OneMinute,
+        Interval::FiveMinutes,
+        Interval::OneHour,
+        Interval::OneDay,
+    ];
+
+    pub fn secs(self) -> u64 {
+        match self {
+            Interval::OneMinute => 60,
+            Interval::FiveMinutes => 5 * 60,
+            Interval::OneHour => 60 * 60,
+            Interval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the candle `time` falls in.
+    pub fn start(self, time: u64) -> u64 {
+        time - time % self.secs()
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Interval::OneMinute => 0,
+            Interval::FiveMinutes => 1,
+            Interval::OneHour => 2,
+            Interval::OneDay => 3,
+        }
+    }
+}
+
+/// Key of a candle: security, interval and start time, so the candles of a
+/// security and interval are sorted by time.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct CandleKey([u8; 17]);
+
+impl CandleKey {
+    pub fn new(security: Security, interval: Interval, start: u64) -> Self {
+        let mut key = [0; 17];
+        key[..8].copy_from_slice(&security.0.to_be_bytes());
+        key[8] = interval.code();
+        key[9..].copy_from_slice(&start.to_be_bytes());
+        CandleKey(key)
+    }
+}
+
+impl std::convert::AsRef<[u8]> for CandleKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Prices of the trades of an interval, `volume` is in base units.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct Candle {
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    pub volume: u64,
+    pub trades: u64,
+}
+
+impl Candle {
+    pub fn new(price: u64, amount: u64) -> Self {
+        Candle {
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: amount,
+            trades: 1,
+        }
+    }
+
+    pub fn add(&mut self, price: u64, amount: u64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += amount;
+        self.trades += 1;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CandleRecord {
+    /// Start of the interval, in seconds since the epoch.
+    pub time: u64,
+    #[serde(flatten)]
+    pub candle: Candle,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CandleQuery {
+    pub interval: Interval,
+    /// Seconds since the epoch, both inclusive.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum CandleError {
+    UnknownSecurity,
+}
+
+derive_key_of!(CandleKey, Candle, "Candle", 20);
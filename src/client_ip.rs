@@ -0,0 +1,173 @@
+use crate::{auth, config::ClientIpSource, with_ctx, Ctx};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use warp::{
+    http::{HeaderMap, StatusCode},
+    reject::Reject,
+    reply, Filter, Rejection,
+};
+
+/// IP of the caller, the one session tokens are bound to.
+#[derive(Debug, Clone)]
+pub struct ClientIp {
+    pub ip: String,
+}
+
+#[derive(Deserialize, Default)]
+struct IpQuery {
+    ip: Option<String>,
+}
+
+#[derive(Debug)]
+struct UnknownClientIp;
+
+impl Reject for UnknownClientIp {}
+
+/// IP of the caller, from where `Config::client_ip` says.
+pub fn with_client_ip(ctx: Ctx) -> impl Filter<Extract = (ClientIp,), Error = Rejection> + Clone {
+    let query = warp::query::<IpQuery>()
+        .or(warp::any().map(IpQuery::default))
+        .unify();
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .and(query)
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |remote: Option<SocketAddr>,
+                        headers: HeaderMap,
+                        query: IpQuery,
+                        ctx: Ctx|
+                        -> Result<ClientIp, Rejection> {
+                let ip = match &ctx.client_ip {
+                    ClientIpSource::Query => query.ip,
+                    ClientIpSource::Connection { trusted_proxies } => remote
+                        .map(|remote| forwarded_client(remote.ip(), &headers, trusted_proxies))
+                        .map(|ip| ip.to_string()),
+                };
+                ip.map(|ip| ClientIp { ip })
+                    .ok_or_else(|| warp::reject::custom(UnknownClientIp))
+            },
+        )
+}
+
+/// Replies to the requests rejected by [`with_client_ip`].
+pub async fn recover_unknown_ip(
+    err: Rejection,
+) -> Result<reply::WithStatus<reply::Json>, Rejection> {
+    if err.find::<UnknownClientIp>().is_some() {
+        Ok(auth::reply_error(
+            StatusCode::BAD_REQUEST,
+            "Unknown client IP",
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+/// Each proxy appends the address it received the request from, so the
+/// client is the last hop not added by a trusted proxy. `Forwarded` wins
+/// over `X-Forwarded-For` when both are sent.
+fn forwarded_client(remote: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let hops = if headers.contains_key("forwarded") {
+        forwarded_hops(headers)
+    } else {
+        x_forwarded_for_hops(headers)
+    };
+    let mut client = remote;
+    for hop in hops.into_iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match hop {
+            Some(hop) => client = hop,
+            // Obfuscated or unknown, nothing before it can be told apart.
+            None => break,
+        }
+    }
+    client
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_values(headers, "x-forwarded-for")
+        .map(|hop| hop.parse().ok())
+        .collect()
+}
+
+/// `for` parameters of `Forwarded` (RFC 7239), as in
+/// `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_values(headers, "forwarded")
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| {
+                    let mut pair = pair.splitn(2, '=');
+                    match (pair.next(), pair.next()) {
+                        (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("for") => {
+                            Some(value.trim().trim_matches('"'))
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+                .and_then(parse_node)
+        })
+        .collect()
+}
+
+/// Address of a `Forwarded` node, without its port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if node.starts_with('[') {
+        node[1..].split(']').next()?.parse().ok()
+    } else {
+        node.split(':').next()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn forwarded() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let trusted = [proxy, "10.0.0.2".parse().unwrap()];
+
+        let xff = headers("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.2");
+        assert_eq!(
+            forwarded_client(proxy, &xff, &trusted),
+            "2.2.2.2".parse::<IpAddr>().unwrap()
+        );
+        // Only trusted peers can claim to forward.
+        let stranger: IpAddr = "3.3.3.3".parse().unwrap();
+        assert_eq!(forwarded_client(stranger, &xff, &trusted), stranger);
+
+        let rfc = headers(
+            "forwarded",
+            "for=1.1.1.1, for=\"[2001:db8::1]:4711\";proto=https",
+        );
+        assert_eq!(
+            forwarded_client(proxy, &rfc, &trusted),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        let unknown = headers("forwarded", "for=1.1.1.1, for=unknown");
+        assert_eq!(forwarded_client(proxy, &unknown, &trusted), proxy);
+    }
+}
@@ -0,0 +1,189 @@
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// JSON file the server reads its [`Config`] from, the defaults are used
+/// when unset.
+pub const CONFIG_ENV: &str = "RETO2_CONFIG";
+
+/// Server settings that are not part of the persisted state. Fields left out
+/// of the file take their default.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Ids of the users given the admin role when they log in.
+    pub admins: Vec<String>,
+    /// Ids of the users given the operator role when they log in.
+    pub operators: Vec<String>,
+    /// Whether offers must hold their funds in the balances ledger. When
+    /// disabled orders are accepted without funds and never settled.
+    pub funds_check: bool,
+    /// Keys of the session tokens. The first one signs new tokens, the rest
+    /// only verify, so a rotated key is kept until its tokens expire. By
+    /// default a random secret, so sessions don't survive a restart.
+    pub jwt_keys: Vec<JwtKey>,
+    /// Key the secrets of the API keys are encrypted with in the database.
+    /// They are not hashed like passwords, as checking the HMAC signature of
+    /// a request takes the secret itself. Without one a random key is drawn,
+    /// and the server refuses to start over keys it can no longer open.
+    pub api_key_encryption_key: Option<String>,
+    /// Where the IP that session tokens are bound to comes from.
+    pub client_ip: ClientIpSource,
+    /// Limits on failed logins, none when disabled.
+    pub login_throttle: Option<LoginThrottle>,
+    /// Rules new passwords must follow.
+    pub password_policy: PasswordPolicy,
+    /// Symbol of the instrument each currency of the modules built while
+    /// securities were a single currency trades on. Every symbol must be
+    /// registered, orders on other currencies are refused.
+    pub legacy_symbols: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Invalid(String, serde_json::Error),
+}
+
+impl Config {
+    /// Reads the file named by [`CONFIG_ENV`].
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = match std::env::var(CONFIG_ENV) {
+            Ok(path) => path,
+            Err(_) => return Ok(Config::default()),
+        };
+        let file = std::fs::read(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        serde_json::from_slice(&file).map_err(|e| ConfigError::Invalid(path, e))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            admins: Vec::new(),
+            operators: Vec::new(),
+            funds_check: true,
+            jwt_keys: vec![JwtKey::ephemeral()],
+            api_key_encryption_key: None,
+            client_ip: ClientIpSource::Connection {
+                trusted_proxies: Vec::new(),
+            },
+            login_throttle: Some(LoginThrottle::default()),
+            password_policy: PasswordPolicy::default(),
+            legacy_symbols: vec![("BTC".to_string(), "BTC/USD".to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// Key of the session tokens, `id` goes in the `kid` header of the tokens
+/// it signs.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JwtKey {
+    pub id: String,
+    pub algorithm: Algorithm,
+    pub material: KeyMaterial,
+}
+
+impl JwtKey {
+    /// HS256 key with a secret drawn at startup.
+    pub fn ephemeral() -> Self {
+        JwtKey {
+            id: "ephemeral".to_string(),
+            algorithm: Algorithm::HS256,
+            material: KeyMaterial::Secret {
+                secret: random_secret(),
+            },
+        }
+    }
+}
+
+/// 256 random bits, hex encoded.
+fn random_secret() -> String {
+    format!(
+        "{:032x}{:032x}",
+        rand::random::<u128>(),
+        rand::random::<u128>()
+    )
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum KeyMaterial {
+    /// Shared secret of the HMAC algorithms, at least
+    /// [`MIN_SECRET_LEN`](crate::auth::MIN_SECRET_LEN) bytes.
+    Secret { secret: String },
+    /// File holding the shared secret.
+    SecretFile { path: String },
+    /// PEM files of an RSA or EC key pair. Keys that only verify don't need
+    /// the private key.
+    Pem {
+        private_key: Option<String>,
+        public_key: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "source")]
+pub enum ClientIpSource {
+    /// The `ip` query parameter, set by the client itself. Only meant for
+    /// the test sequences, which pose as several clients.
+    Query,
+    /// The peer address of the connection. Requests from the trusted
+    /// proxies are attributed to the address they forward for, as given by
+    /// `Forwarded` or `X-Forwarded-For`.
+    Connection { trusted_proxies: Vec<IpAddr> },
+}
+
+/// Failed logins of a user make the next one wait `backoff_secs`, doubled
+/// on each failure, until `max_failures` lock the user out for
+/// `lockout_secs`. Failures from an IP, whichever the user, lock it out
+/// after `max_ip_failures`. Failures are forgotten after `lockout_secs`
+/// without any.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LoginThrottle {
+    pub max_failures: u32,
+    pub max_ip_failures: u32,
+    pub backoff_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        LoginThrottle {
+            max_failures: 5,
+            max_ip_failures: 50,
+            backoff_secs: 1,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+/// Rules of the passwords set on signup, change or reset. Lengths are in
+/// bytes, bcrypt ignores whatever follows the first 72.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_letter: bool,
+    pub require_digit: bool,
+    /// Anything but a letter or a digit.
+    pub require_symbol: bool,
+    /// Refuse passwords containing the user id, whatever the case.
+    pub forbid_user_id: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 10,
+            max_length: 72,
+            require_letter: true,
+            require_digit: true,
+            require_symbol: false,
+            forbid_user_id: true,
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use crate::offers::OfferEventRequest;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
 use wapc::WapcHost;
@@ -7,13 +9,17 @@ type SerResult = Result<Vec<u8>, Box<dyn Error>>;
 
 pub struct FormatDeserializer {
     host: Arc<Mutex<WapcHost>>,
+    legacy_symbols: HashMap<String, String>,
 }
 
 unsafe impl Send for FormatDeserializer {}
 unsafe impl Sync for FormatDeserializer {}
 
 impl FormatDeserializer {
-    pub fn new(wasm_path: &str) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(
+        wasm_path: &str,
+        legacy_symbols: HashMap<String, String>,
+    ) -> std::result::Result<Self, Box<dyn std::error::Error>> {
         let module_bytes = std::fs::read(wasm_path)?;
         println!("Started Load //////////////");
         let host = WapcHost::new(
@@ -35,6 +41,7 @@ impl FormatDeserializer {
 
         Ok(Self {
             host: Arc::new(Mutex::new(host)),
+            legacy_symbols,
         })
     }
 
@@ -44,7 +51,11 @@ impl FormatDeserializer {
         payload: &[u8],
     ) -> Result<OfferEventRequest, Box<dyn Error>> {
         let ans = self.host.clone().lock().unwrap().call(header, payload)?;
-        Ok(bincode::deserialize::<OfferEventRequest>(&ans)?)
+        match deserialize_exact::<OfferEventRequest>(&ans) {
+            Ok(event) => Ok(event),
+            Err(_) => deserialize_exact::<legacy::OfferEventRequest>(&ans)?
+                .into_request(&self.legacy_symbols),
+        }
     }
 
     pub fn replace(&self, module_bytes: &[u8]) {
@@ -67,6 +78,81 @@ impl FormatDeserializer {
     }
 }
 
+/// Deserializes a bincode value that must span the whole buffer, so that a
+/// payload of one layout is never half-read as another.
+fn deserialize_exact<T: DeserializeOwned>(mut bytes: &[u8]) -> bincode::Result<T> {
+    let value = bincode::deserialize_from(&mut bytes)?;
+    if bytes.is_empty() {
+        Ok(value)
+    } else {
+        Err(Box::new(bincode::ErrorKind::Custom("Trailing bytes".into())))
+    }
+}
+
+/// Layout returned by modules compiled while securities were a closed enum.
+mod legacy {
+    use crate::offers::{self, OfferValueRequest, Side};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    #[derive(Deserialize)]
+    pub enum OfferEventRequest {
+        Delete(u64),
+        Add(OfferValue),
+    }
+
+    #[derive(Deserialize)]
+    pub struct OfferValue {
+        security: Security,
+        side: Side,
+        amount: u64,
+        price: Option<u64>,
+    }
+
+    #[derive(Deserialize)]
+    enum Security {
+        BTC,
+        USD,
+        COP,
+    }
+
+    impl Security {
+        fn currency(&self) -> &'static str {
+            match self {
+                Security::BTC => "BTC",
+                Security::USD => "USD",
+                Security::COP => "COP",
+            }
+        }
+    }
+
+    impl OfferEventRequest {
+        /// Orders go to the instrument `legacy_symbols` gives their
+        /// currency, those on unmapped currencies are refused.
+        pub fn into_request(
+            self,
+            legacy_symbols: &HashMap<String, String>,
+        ) -> Result<offers::OfferEventRequest, Box<dyn Error>> {
+            match self {
+                OfferEventRequest::Delete(k) => Ok(offers::OfferEventRequest::Delete(k)),
+                OfferEventRequest::Add(v) => {
+                    let currency = v.security.currency();
+                    let symbol = legacy_symbols
+                        .get(currency)
+                        .ok_or_else(|| format!("Unmapped legacy security {}", currency))?;
+                    Ok(offers::OfferEventRequest::Add(OfferValueRequest {
+                        security: symbol.clone(),
+                        side: v.side,
+                        amount: v.amount,
+                        price: v.price,
+                    }))
+                }
+            }
+        }
+    }
+}
+
 // {
 //     "type": "record",
 //     "name": "TestData",
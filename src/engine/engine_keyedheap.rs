@@ -1,9 +1,10 @@
 use crate::{derive_offer_ord, engine::offer_ord::OfferOrdSigned};
 use crate::{
-    engine::{EngineDataStruct, MatchResult},
-    offers::{Offer, OfferEventKey, Side},
+    engine::{EngineDataStruct, MatchResult, PriceLevel},
+    offers::{Offer, OfferEventKey, Security, Side},
 };
 use keyed_priority_queue::KeyedPriorityQueue;
+use std::collections::BTreeMap;
 
 #[derive(Eq, PartialOrd, Clone, Debug)]
 pub struct EngineOfferKBH {
@@ -13,18 +14,89 @@ pub struct EngineOfferKBH {
 }
 derive_offer_ord!(OfferOrdSigned, EngineOfferKBH, cmp_max);
 
-pub type KeyedBinaryHeapEngine = KeyedPriorityQueue<OfferEventKey, EngineOfferKBH>;
+/// Offers of one side of a book, with the price levels they add up to kept
+/// alongside so reading the depth never walks the offers.
+pub struct KeyedBinaryHeapEngine {
+    offers: KeyedPriorityQueue<OfferEventKey, EngineOfferKBH>,
+    /// By signed price, so the best level comes first on both sides.
+    levels: BTreeMap<i64, PriceLevel>,
+}
+
+impl KeyedBinaryHeapEngine {
+    fn push(&mut self, key: OfferEventKey, offer: EngineOfferKBH) {
+        if let Some(price) = offer.price {
+            let level = self.levels.entry(price).or_insert(PriceLevel {
+                price: price.abs() as u64,
+                amount: 0,
+                orders: 0,
+            });
+            level.amount += offer.amount;
+            level.orders += 1;
+        }
+        self.offers.push(key, offer);
+    }
+
+    fn peek(&self) -> Option<(&OfferEventKey, &EngineOfferKBH)> {
+        self.offers.peek()
+    }
+
+    fn pop(&mut self) -> Option<(OfferEventKey, EngineOfferKBH)> {
+        let (key, offer) = self.offers.pop()?;
+        self.leave_level(&offer);
+        Some((key, offer))
+    }
+
+    fn remove(&mut self, key: &OfferEventKey) -> Option<EngineOfferKBH> {
+        let offer = self.offers.remove(key)?;
+        self.leave_level(&offer);
+        Some(offer)
+    }
+
+    fn leave_level(&mut self, offer: &EngineOfferKBH) {
+        if let Some(price) = offer.price {
+            let level = self.levels.get_mut(&price).expect("Offer without level");
+            level.amount -= offer.amount;
+            level.orders -= 1;
+            if level.orders == 0 {
+                self.levels.remove(&price);
+            }
+        }
+    }
+}
 
 impl EngineDataStruct for KeyedBinaryHeapEngine {
-    fn delete_key(&mut self, key: &OfferEventKey) -> bool {
-        match self.remove(key) {
-            Some(_) => true,
-            None => false,
+    fn delete_key(&mut self, key: &OfferEventKey, side: Side, security: Security) -> Option<Offer> {
+        self.remove(key).map(|o| o.into_offer(side, security))
+    }
+
+    fn delete_keys(
+        &mut self,
+        keys: &[OfferEventKey],
+        side: Side,
+        security: Security,
+    ) -> Vec<Offer> {
+        keys.iter()
+            .filter_map(|key| self.delete_key(key, side, security))
+            .collect()
+    }
+
+    fn drain(&mut self, side: Side, security: Security) -> Vec<Offer> {
+        let mut offers = Vec::new();
+        while let Some((_, o)) = self.pop() {
+            offers.push(o.into_offer(side, security));
         }
+        offers
+    }
+
+    fn depth(&self, depth: usize) -> Vec<PriceLevel> {
+        self.levels.values().take(depth).cloned().collect()
     }
 
     fn with_capacity(capacity: usize) -> Self {
-        Self::with_capacity(capacity)
+        KeyedBinaryHeapEngine {
+            offers: KeyedPriorityQueue::with_capacity(capacity),
+            levels: BTreeMap::new(),
+        }
     }
 
     fn match_offer(
@@ -112,10 +184,29 @@ impl EngineDataStruct for KeyedBinaryHeapEngine {
 mod tests {
     use super::*;
     use crate::{
-        engine::{Engine, Matches},
-        offers::{Offer, OfferEventKeyed, OfferValue, Security, Side},
+        engine::{BookDepth, Engine, Matches},
+        offers::{MassCancel, Offer, OfferEventKeyed, OfferValue, Security, Side},
     };
 
+    /// Engine that is never started, offers are fed to it directly.
+    fn engine() -> Engine<KeyedBinaryHeapEngine> {
+        let (_sender_offer, receiver_offer) = crossbeam_channel::unbounded::<OfferEventKeyed>();
+        let (sender_matches, _receiver_matches) = crossbeam_channel::unbounded::<Matches>();
+        Engine::new(receiver_offer, sender_matches)
+    }
+
+    fn offer(key: u64, security: u64, side: Side, amount: u64, price: Option<u64>) -> Offer {
+        Offer {
+            key: u64::to_be_bytes(key).into(),
+            value: OfferValue {
+                side,
+                security: Security(security),
+                amount,
+                price,
+            },
+        }
+    }
+
     #[test]
     fn new_keyed_priority_queue() {}
 
@@ -128,7 +219,7 @@ mod tests {
             key: u64::to_be_bytes(0).into(),
             value: OfferValue {
                 side: Side::Buy,
-                security: Security::BTC,
+                security: Security(1),
                 amount: 10,
                 price: None,
             },
@@ -138,7 +229,7 @@ mod tests {
             key: u64::to_be_bytes(1).into(),
             value: OfferValue {
                 side: Side::Buy,
-                security: Security::BTC,
+                security: Security(1),
                 amount: 5,
                 price: Some(32),
             },
@@ -150,7 +241,7 @@ mod tests {
             key: u64::to_be_bytes(2).into(),
             value: OfferValue {
                 side: Side::Sell,
-                security: Security::BTC,
+                security: Security(1),
                 amount: 8,
                 price: None,
             },
@@ -162,7 +253,7 @@ mod tests {
             key: u64::to_be_bytes(3).into(),
             value: OfferValue {
                 side: Side::Sell,
-                security: Security::BTC,
+                security: Security(1),
                 amount: 6,
                 price: Some(33),
             },
@@ -170,4 +261,108 @@ mod tests {
         let matches = engine.process_offer(offer);
         println!("{:?}", matches);
     }
+
+    #[test]
+    fn fills_test() {
+        let mut engine = engine();
+        engine.process_offer(offer(0, 1, Side::Sell, 4, Some(10)));
+        engine.process_offer(offer(1, 1, Side::Sell, 6, Some(12)));
+        // Other securities never match
+        engine.process_offer(offer(2, 2, Side::Sell, 6, Some(1)));
+
+        let matches = engine.process_offer(offer(3, 1, Side::Buy, 7, None));
+        let fills = matches.fills();
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].maker.key, OfferEventKey::from(0));
+        assert_eq!(fills[0].maker.value.amount, 4);
+        assert_eq!(fills[0].notional(), Some(40));
+        assert_eq!(fills[1].maker.key, OfferEventKey::from(1));
+        assert_eq!(fills[1].taker.value.amount, 3);
+        assert_eq!(fills[1].notional(), Some(36));
+
+        let matches = engine.process_offer(offer(4, 1, Side::Buy, 5, Some(12)));
+        let fills = matches.fills();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].taker.value.amount, 3);
+        assert_eq!(fills[0].price(), Some(12));
+    }
+
+    #[test]
+    fn mass_cancel_test() {
+        let mut engine = engine();
+        let offer = |key, security, side: Side| {
+            let price = if side == Side::Buy { 10 } else { 20 };
+            offer(key, security, side, 5, Some(price))
+        };
+        engine.process_offer(offer(0, 1, Side::Buy));
+        engine.process_offer(offer(1, 1, Side::Sell));
+        engine.process_offer(offer(2, 2, Side::Sell));
+        engine.process_offer(offer(3, 2, Side::Buy));
+
+        let keys = [2, 0, 9].iter().map(|k| OfferEventKey::from(*k)).collect();
+        let cancelled = engine.mass_cancel(&MassCancel::Offers(keys));
+        assert_eq!(
+            cancelled,
+            vec![offer(0, 1, Side::Buy), offer(2, 2, Side::Sell)]
+        );
+        assert_eq!(cancelled[1].value.price, Some(20));
+
+        let cancelled = engine.mass_cancel(&MassCancel::Security(Security(1)));
+        assert_eq!(cancelled, vec![offer(1, 1, Side::Sell)]);
+        let cancelled = engine.mass_cancel(&MassCancel::Security(Security(1)));
+        assert!(cancelled.is_empty());
+        assert!(engine.delete_offer(&OfferEventKey::from(3)).is_some());
+
+        engine.add_offer(offer(4, 1, Side::Buy), "alice".to_string());
+        engine.add_offer(offer(5, 2, Side::Sell), "bob".to_string());
+        engine.add_offer(offer(6, 2, Side::Sell), "alice".to_string());
+        let alice = MassCancel::User("alice".to_string());
+        assert_eq!(
+            engine.mass_cancel(&alice),
+            vec![offer(4, 1, Side::Buy), offer(6, 2, Side::Sell)]
+        );
+        assert!(engine.mass_cancel(&alice).is_empty());
+        assert!(engine.delete_offer(&OfferEventKey::from(5)).is_some());
+    }
+
+    #[test]
+    fn depth_test() {
+        let mut engine = engine();
+        let offer = |key, side, amount, price| offer(key, 1, side, amount, price);
+        engine.process_offer(offer(0, Side::Buy, 5, Some(10)));
+        engine.process_offer(offer(1, Side::Buy, 3, Some(11)));
+        engine.process_offer(offer(2, Side::Buy, 2, Some(10)));
+        engine.process_offer(offer(3, Side::Buy, 4, Some(9)));
+        engine.process_offer(offer(4, Side::Sell, 6, Some(12)));
+        engine.process_offer(offer(5, Side::Sell, 1, Some(14)));
+
+        let level = |price, amount, orders| PriceLevel {
+            price,
+            amount,
+            orders,
+        };
+        let depth = engine.depth(Security(1), 2);
+        assert_eq!(depth.bids, vec![level(11, 3, 1), level(10, 7, 2)]);
+        assert_eq!(depth.asks, vec![level(12, 6, 1), level(14, 1, 1)]);
+        assert!(engine.depth(Security(2), 2).bids.is_empty());
+
+        // Reading the depth keeps time priority
+        let matches = engine.process_offer(offer(6, Side::Sell, 8, Some(10)));
+        let fills = matches.fills();
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].maker.key, OfferEventKey::from(1));
+        assert_eq!(fills[1].maker.key, OfferEventKey::from(0));
+        assert_eq!(engine.depth(Security(1), 1).bids, vec![level(10, 2, 1)]);
+
+        // Levels follow partial fills, cancels and drains
+        engine.process_offer(offer(7, Side::Buy, 2, Some(12)));
+        engine.process_offer(offer(8, Side::Buy, 1, None));
+        let depth = engine.depth(Security(1), 5);
+        assert_eq!(depth.bids, vec![level(10, 2, 1), level(9, 4, 1)]);
+        assert_eq!(depth.asks, vec![level(12, 3, 1), level(14, 1, 1)]);
+        assert!(engine.delete_offer(&OfferEventKey::from(5)).is_some());
+        assert_eq!(engine.depth(Security(1), 5).asks, vec![level(12, 3, 1)]);
+        engine.mass_cancel(&MassCancel::Security(Security(1)));
+        assert_eq!(engine.depth(Security(1), 5), BookDepth::default());
+    }
 }
@@ -1,25 +1,115 @@
 mod engine_keyedheap;
 pub mod offer_ord;
 
-use crate::offers::{Offer, OfferEventKey, OfferEventKeyed, Side};
-use crossbeam_channel::{self, Receiver, Sender};
+use crate::offers::{MassCancel, Offer, OfferEventKey, OfferEventKeyed, Security, Side};
+use crossbeam_channel::{self, select, Receiver, Sender};
 pub use engine_keyedheap::KeyedBinaryHeapEngine;
+use futures::channel::oneshot;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum MatchResult {
     Complete,
     Partial { offer: Offer, to_substract: u64 },
+    /// The offer was deleted from its book with the amount it had left.
+    Cancelled(Offer),
+    /// Offers removed by a mass cancel, sorted by key.
+    MassCancelled(Vec<Offer>),
     None,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Matches {
     pub key: OfferEventKey,
     pub result: MatchResult,
     pub completed: Vec<Offer>,
 }
 
+/// Execution of the incoming offer against a resting one. Both offers carry
+/// the executed base amount.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub maker: Offer,
+    pub taker: Offer,
+}
+
+impl Fill {
+    /// Resting offers set the execution price, market offers only trade at
+    /// the price of their counterpart.
+    pub fn price(&self) -> Option<u64> {
+        self.maker.value.price.or(self.taker.value.price)
+    }
+
+    /// Executed amount in quote units.
+    pub fn notional(&self) -> Option<u64> {
+        self.price().map(|price| price * self.maker.value.amount)
+    }
+}
+
+impl Matches {
+    /// Executions between the offer with key `self.key` and the resting
+    /// offers it matched, in the order they happened.
+    pub fn fills(&self) -> Vec<Fill> {
+        let taker = match &self.result {
+            MatchResult::Partial { offer, .. } if offer.key == self.key => offer,
+            MatchResult::None => return Vec::new(),
+            _ => match self.completed.iter().find(|o| o.key == self.key) {
+                Some(taker) => taker,
+                None => return Vec::new(),
+            },
+        };
+        let fill = |maker: &Offer, amount: u64| {
+            let (mut maker, mut taker) = (maker.clone(), taker.clone());
+            maker.value.amount = amount;
+            taker.value.amount = amount;
+            Fill { maker, taker }
+        };
+
+        let mut fills: Vec<Fill> = self
+            .completed
+            .iter()
+            .filter(|o| o.key != self.key)
+            .map(|o| fill(o, o.value.amount))
+            .collect();
+        if let MatchResult::Partial {
+            offer,
+            to_substract,
+        } = &self.result
+        {
+            if offer.key != self.key {
+                fills.push(fill(offer, *to_substract));
+            }
+        }
+        fills
+    }
+}
+
+/// Resting amount and number of offers at a price.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PriceLevel {
+    pub price: u64,
+    pub amount: u64,
+    pub orders: u64,
+}
+
+/// Best price levels of a book, bids from the highest price and asks from
+/// the lowest.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct BookDepth {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    /// Key of the last event the engine processed.
+    pub event: Option<u64>,
+}
+
+/// Asks the engine for the depth of a book, answered between two events.
+pub struct DepthRequest {
+    pub security: Security,
+    pub depth: usize,
+    pub reply: oneshot::Sender<BookDepth>,
+}
+
 pub trait EngineDataStruct: Sized {
     fn match_offer(
         &mut self,
@@ -27,23 +117,53 @@ pub trait EngineDataStruct: Sized {
         offer: Offer,
         other: &mut Self,
     ) -> MatchResult;
-    fn delete_key(&mut self, key: &OfferEventKey) -> bool;
+    fn delete_key(&mut self, key: &OfferEventKey, side: Side, security: Security) -> Option<Offer>;
+    fn delete_keys(&mut self, keys: &[OfferEventKey], side: Side, security: Security)
+        -> Vec<Offer>;
+    /// Removes every offer.
+    fn drain(&mut self, side: Side, security: Security) -> Vec<Offer>;
+    /// At most `depth` price levels from the best one, market offers have
+    /// none.
+    fn depth(&self, depth: usize) -> Vec<PriceLevel>;
     fn with_capacity(capacity: usize) -> Self;
 }
 
-pub struct Engine<T>
+/// Buy and sell offers of a single security.
+pub struct Book<T>
 where
     T: EngineDataStruct,
 {
     sell_offers: T,
-    // market_sell_offers: Vec<MarketEngineOffer>,
     buy_offers: T,
+}
+
+impl<T> Book<T>
+where
+    T: EngineDataStruct,
+{
+    fn with_capacity(capacity: usize) -> Self {
+        Book {
+            sell_offers: T::with_capacity(capacity),
+            buy_offers: T::with_capacity(capacity),
+        }
+    }
+}
+
+pub struct Engine<T>
+where
+    T: EngineDataStruct,
+{
+    books: HashMap<Security, Book<T>>,
+    // market_sell_offers: Vec<MarketEngineOffer>,
     // market_buy_offers: Vec<MarketEngineOffer>,
     matches: Vec<Offer>,
     receiver: Receiver<OfferEventKeyed>,
+    depth_receiver: Receiver<DepthRequest>,
     not_processed: Vec<OfferEventKeyed>,
     last_processed: Option<u64>,
     sender: Sender<Matches>,
+    /// Owner of every resting offer, for mass cancels by user.
+    owners: HashMap<OfferEventKey, String>,
 }
 
 impl<T> Engine<T>
@@ -52,21 +172,45 @@ where
 {
     pub fn new(receiver: Receiver<OfferEventKeyed>, sender: Sender<Matches>) -> Self {
         Engine {
-            sell_offers: T::with_capacity(24),
+            books: HashMap::new(),
             not_processed: Vec::new(),
             last_processed: None,
             // market_sell_offers: Vec::with_capacity(24),
-            buy_offers: T::with_capacity(24),
             // market_buy_offers: Vec::with_capacity(24),
             matches: Vec::with_capacity(24),
             sender,
             receiver,
+            depth_receiver: crossbeam_channel::never(),
+            owners: HashMap::new(),
         }
     }
 
+    /// Serves the depth requests sent through `receiver`.
+    pub fn with_depth_requests(mut self, receiver: Receiver<DepthRequest>) -> Self {
+        self.depth_receiver = receiver;
+        self
+    }
+
     pub fn start(&mut self) {
         let mut counter = 0u32;
-        while let Ok(mut offer) = self.receiver.recv() {
+        // Owned so answering a request can borrow the engine.
+        let (receiver, depth_receiver) = (self.receiver.clone(), self.depth_receiver.clone());
+        loop {
+            let mut offer = select! {
+                recv(receiver) -> offer => match offer {
+                    Ok(offer) => offer,
+                    Err(_) => break,
+                },
+                recv(depth_receiver) -> request => match request {
+                    Ok(request) => {
+                        let depth = self.depth(request.security, request.depth);
+                        // The requester may have given up waiting.
+                        let _ = request.reply.send(depth);
+                        continue;
+                    }
+                    Err(_) => break,
+                },
+            };
             let seq = u64::from_be_bytes(offer.key().clone().into());
             if let Some(last_processed) = self.last_processed {
                 if seq != last_processed + 1 {
@@ -79,15 +223,39 @@ where
             loop {
                 counter += 1;
                 match offer {
-                    OfferEventKeyed::Add(offer) => {
-                        let matches = self.process_offer(offer);
+                    OfferEventKeyed::Add(offer, owner) => {
+                        let matches = self.add_offer(offer, owner);
                         println!("Engine {} - Match: {:?}", counter, matches);
 
                         self.sender.send(matches).unwrap();
                     }
-                    OfferEventKeyed::Delete(_, k) => {
+                    OfferEventKeyed::Delete(key, k) => {
                         let deleted = self.delete_offer(&k);
-                        println!("Engine {} - Deleted {}", counter, deleted);
+                        println!("Engine {} - Deleted {:?}", counter, deleted);
+
+                        let result = match deleted {
+                            Some(offer) => MatchResult::Cancelled(offer),
+                            None => MatchResult::None,
+                        };
+                        self.sender
+                            .send(Matches {
+                                key,
+                                result,
+                                completed: Vec::new(),
+                            })
+                            .unwrap();
+                    }
+                    OfferEventKeyed::MassCancel(key, scope) => {
+                        let cancelled = self.mass_cancel(&scope);
+                        println!("Engine {} - Cancelled {}", counter, cancelled.len());
+
+                        self.sender
+                            .send(Matches {
+                                key,
+                                result: MatchResult::MassCancelled(cancelled),
+                                completed: Vec::new(),
+                            })
+                            .unwrap();
                     }
                 }
                 if let Some(o) = self.get_next() {
@@ -107,10 +275,24 @@ where
             .remove_item(&OfferEventKeyed::Delete(key.clone(), key))
     }
 
+    /// Processes an offer of `owner`, who is remembered while it rests.
+    pub fn add_offer(&mut self, offer: Offer, owner: String) -> Matches {
+        self.owners.insert(offer.key.clone(), owner);
+        let matches = self.process_offer(offer);
+        for completed in matches.completed.iter() {
+            self.owners.remove(&completed.key);
+        }
+        matches
+    }
+
     pub fn process_offer(&mut self, offer: Offer) -> Matches {
+        let book = self
+            .books
+            .entry(offer.value.security)
+            .or_insert_with(|| Book::with_capacity(24));
         let (same_offers, opposite_offers) = match offer.value.side {
-            Side::Buy => (&mut self.buy_offers, &mut self.sell_offers),
-            Side::Sell => (&mut self.sell_offers, &mut self.buy_offers),
+            Side::Buy => (&mut book.buy_offers, &mut book.sell_offers),
+            Side::Sell => (&mut book.sell_offers, &mut book.buy_offers),
         };
 
         let result = opposite_offers.match_offer(&mut self.matches, offer.clone(), same_offers);
@@ -125,11 +307,65 @@ where
         Matches { completed, result , key}
     }
 
-    pub fn delete_offer(&mut self, key: &OfferEventKey) -> bool {
-        if self.buy_offers.delete_key(key) {
-            true
-        } else {
-            self.sell_offers.delete_key(key)
+    pub fn depth(&self, security: Security, depth: usize) -> BookDepth {
+        match self.books.get(&security) {
+            Some(book) => BookDepth {
+                bids: book.buy_offers.depth(depth),
+                asks: book.sell_offers.depth(depth),
+                event: self.last_processed,
+            },
+            None => BookDepth {
+                event: self.last_processed,
+                ..BookDepth::default()
+            },
+        }
+    }
+
+    pub fn delete_offer(&mut self, key: &OfferEventKey) -> Option<Offer> {
+        self.owners.remove(key);
+        self.books.iter_mut().find_map(|(security, book)| {
+            book.buy_offers
+                .delete_key(key, Side::Buy, *security)
+                .or_else(|| book.sell_offers.delete_key(key, Side::Sell, *security))
+        })
+    }
+
+    pub fn mass_cancel(&mut self, scope: &MassCancel) -> Vec<Offer> {
+        let mut cancelled = match scope {
+            MassCancel::Offers(keys) => self.delete_keys(keys),
+            MassCancel::User(user) => {
+                let keys: Vec<OfferEventKey> = self
+                    .owners
+                    .iter()
+                    .filter(|(_, owner)| *owner == user)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                self.delete_keys(&keys)
+            }
+            MassCancel::Security(security) => {
+                let mut cancelled = Vec::new();
+                if let Some(book) = self.books.get_mut(security) {
+                    cancelled.append(&mut book.buy_offers.drain(Side::Buy, *security));
+                    cancelled.append(&mut book.sell_offers.drain(Side::Sell, *security));
+                }
+                cancelled
+            }
+        };
+        for offer in cancelled.iter() {
+            self.owners.remove(&offer.key);
+        }
+        // Books are visited in no particular order, replicas must agree.
+        cancelled.sort_by(|a, b| a.key.0.cmp(&b.key.0));
+        cancelled
+    }
+
+    fn delete_keys(&mut self, keys: &[OfferEventKey]) -> Vec<Offer> {
+        let mut deleted = Vec::new();
+        for (security, book) in self.books.iter_mut() {
+            let security = *security;
+            deleted.append(&mut book.buy_offers.delete_keys(keys, Side::Buy, security));
+            deleted.append(&mut book.sell_offers.delete_keys(keys, Side::Sell, security));
         }
+        deleted
     }
 }
@@ -0,0 +1,123 @@
+use crate::executions::{ExecutionEvent, ExecutionReport};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Fans out the execution reports of every user to their open streams.
+#[derive(Clone, Default)]
+pub struct ExecutionHandler {
+    feeds: Arc<Mutex<HashMap<String, Feed>>>,
+    /// Streams open in each session of a user.
+    sessions: Arc<Mutex<HashMap<(String, String), usize>>>,
+}
+
+#[derive(Default)]
+struct Feed {
+    seq: u64,
+    subscribers: Vec<UnboundedSender<String>>,
+}
+
+impl ExecutionHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stream of the JSON reports of `user` from now on.
+    pub fn subscribe(&self, user: &str) -> UnboundedReceiver<String> {
+        let mut feeds = self.feeds.lock().unwrap();
+        let (sender, receiver) = unbounded();
+        feeds
+            .entry(user.to_string())
+            .or_default()
+            .subscribers
+            .push(sender);
+        receiver
+    }
+
+    /// Counts a stream opened in `session` of `user`.
+    pub fn open_session(&self, user: &str, session: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        *sessions
+            .entry((user.to_string(), session.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Counts a stream of `session` of `user` as closed, true when it was
+    /// the last one of the session.
+    pub fn close_session(&self, user: &str, session: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = (user.to_string(), session.to_string());
+        match sessions.get_mut(&key) {
+            Some(streams) if *streams > 1 => {
+                *streams -= 1;
+                false
+            }
+            _ => {
+                sessions.remove(&key);
+                true
+            }
+        }
+    }
+
+    pub fn publish(&self, user: &str, event: ExecutionEvent) {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds.entry(user.to_string()).or_default();
+        feed.seq += 1;
+        let report = ExecutionReport {
+            seq: feed.seq,
+            event,
+        };
+        let report = serde_json::to_string(&report).unwrap();
+        // Closed streams are dropped on the first report they miss.
+        feed.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(report.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let handler = ExecutionHandler::new();
+        let mut alice = handler.subscribe("alice");
+        let mut bob = handler.subscribe("bob");
+        let next = |stream: &mut UnboundedReceiver<String>| -> ExecutionReport {
+            serde_json::from_str(&stream.try_next().unwrap().unwrap()).unwrap()
+        };
+
+        let expired = ExecutionEvent::Expired {
+            order: 3,
+            remaining: 5,
+        };
+        handler.publish(
+            "alice",
+            ExecutionEvent::Cancelled {
+                order: 2,
+                remaining: 1,
+            },
+        );
+        handler.publish("alice", expired.clone());
+        assert_eq!(next(&mut alice).seq, 1);
+        assert_eq!(
+            next(&mut alice),
+            ExecutionReport {
+                seq: 2,
+                event: expired,
+            }
+        );
+        assert!(bob.try_next().is_err());
+    }
+
+    #[test]
+    fn session_streams() {
+        let handler = ExecutionHandler::new();
+        handler.open_session("alice", "s1");
+        handler.open_session("alice", "s1");
+        handler.open_session("alice", "s2");
+        assert!(!handler.close_session("alice", "s1"));
+        assert!(handler.close_session("alice", "s2"));
+        assert!(handler.close_session("alice", "s1"));
+    }
+}
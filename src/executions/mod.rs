@@ -0,0 +1,57 @@
+mod handler;
+mod model;
+
+use crate::{auth, offers, with_client_ip, with_ctx, ClientIp, Ctx};
+use futures::{future, StreamExt};
+use std::convert::Infallible;
+use warp::{
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+pub use handler::ExecutionHandler;
+pub use model::{ExecutionEvent, ExecutionReport};
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    execution_stream(ctx)
+}
+
+fn execution_stream(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("ws" / "executions")
+        .and(warp::ws())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |ws: Ws,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let claims = match ctx.auth_manager.authorize(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                Ok(Box::new(ws.on_upgrade(move |socket| {
+                    stream_executions(socket, ctx, claims)
+                })))
+            },
+        )
+}
+
+/// Forwards the reports of the user until the client goes away. The last
+/// stream of a session to go away ends it for cancel on disconnect.
+async fn stream_executions(socket: WebSocket, ctx: Ctx, claims: auth::Claims) {
+    let session = &claims.session;
+    ctx.executions.open_session(&claims.user_id, session);
+    let (sink, mut incoming) = socket.split();
+    let reports = ctx.executions.subscribe(&claims.user_id);
+    let send = reports.map(|text| Ok(Message::text(text))).forward(sink);
+    let receive = async move { while let Some(Ok(_)) = incoming.next().await {} };
+    futures::pin_mut!(send, receive);
+    future::select(send, receive).await;
+
+    if ctx.executions.close_session(&claims.user_id, session) {
+        offers::end_session(&ctx, &claims).await;
+    }
+}
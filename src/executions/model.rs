@@ -0,0 +1,58 @@
+use crate::offers::{OfferValueRequest, OrderFill};
+use serde::{Deserialize, Serialize};
+
+/// Message of the execution stream of a user. `seq` grows by one with every
+/// report of the user, a gap means reports were missed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExecutionReport {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: ExecutionEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ExecutionEvent {
+    Accepted {
+        order: u64,
+        request: OfferValueRequest,
+    },
+    PartiallyFilled {
+        order: u64,
+        fill: OrderFill,
+        remaining: u64,
+    },
+    Filled {
+        order: u64,
+        fill: OrderFill,
+    },
+    /// `remaining` is the amount that was left to execute.
+    Cancelled {
+        order: u64,
+        remaining: u64,
+    },
+    /// Cancelled as it reached its expire time.
+    Expired {
+        order: u64,
+        remaining: u64,
+    },
+    /// The offer never became an order, `reason` is the error it got.
+    Rejected {
+        request: OfferValueRequest,
+        reason: serde_json::Value,
+    },
+}
+
+impl ExecutionEvent {
+    pub fn fill(order: u64, fill: OrderFill, remaining: u64) -> Self {
+        if remaining == 0 {
+            ExecutionEvent::Filled { order, fill }
+        } else {
+            ExecutionEvent::PartiallyFilled {
+                order,
+                fill,
+                remaining,
+            }
+        }
+    }
+}
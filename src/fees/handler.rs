@@ -0,0 +1,104 @@
+use crate::fees::{fee, FeeError, FeeSchedule, FeeScheduleKey, Liquidity, Volume, VolumeKey};
+use crate::offers::Security;
+use crate::prelude::*;
+use sled::{ConflictableTransactionResult, TransactionalTree};
+
+#[derive(Clone)]
+pub struct FeeHandler {
+    schedules_db: sled::Tree,
+    pub(crate) volumes_db: sled::Tree,
+}
+
+impl FeeHandler {
+    pub fn new(db: sled::Db) -> Self {
+        Self {
+            schedules_db: db.open_tree(<FeeScheduleKey as KeyOf>::PREFIX).unwrap(),
+            volumes_db: db.open_tree(<VolumeKey as KeyOf>::PREFIX).unwrap(),
+        }
+    }
+
+    /// Schedule of an instrument, instruments without one trade for free.
+    pub fn get(&self, security: Security) -> sled::Result<FeeSchedule> {
+        self.schedules_db
+            .get_typed(&FeeScheduleKey::from(security))
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn set(&self, security: Security, schedule: FeeSchedule) -> Result<FeeSchedule, FeeError> {
+        schedule.validate()?;
+        self.schedules_db
+            .insert_typed(&FeeScheduleKey::from(security), schedule.clone())
+            .unwrap();
+        Ok(schedule)
+    }
+
+    pub fn volume(&self, user: &str, security: Security) -> sled::Result<u64> {
+        self.volumes_db
+            .get_typed(&VolumeKey::new(user, security))
+            .map(|v| v.unwrap_or_default().notional)
+    }
+}
+
+/// Returns the fee of one side of a fill at the rate of the user's tier and
+/// adds the notional to their traded volume. The rate is at most
+/// `max_bps`, what the order reserved when placed, so raising the fees
+/// never takes more than its hold.
+pub fn charge<E>(
+    volumes: &TransactionalTree,
+    schedule: &FeeSchedule,
+    user: &str,
+    security: Security,
+    liquidity: Liquidity,
+    notional: u64,
+    max_bps: u32,
+) -> ConflictableTransactionResult<u64, E> {
+    let key = VolumeKey::new(user, security);
+    let mut volume: Volume = volumes.get_typed(&key)?.unwrap_or_default();
+    let rate = schedule.rate(liquidity, volume.notional).min(max_bps);
+    let fee = fee(notional, rate);
+    volume.notional += notional;
+    volumes.insert_typed(&key, volume)?;
+    Ok(fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_raised_after_placement() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = FeeHandler::new(db);
+        let security = Security(1);
+        let low = FeeSchedule {
+            maker_bps: 10,
+            taker_bps: 20,
+            tiers: Vec::new(),
+        };
+        handler.set(security, low).unwrap();
+        let reserved = handler.get(security).unwrap().max_rate();
+
+        let high = FeeSchedule {
+            maker_bps: 50,
+            taker_bps: 100,
+            tiers: Vec::new(),
+        };
+        handler.set(security, high.clone()).unwrap();
+        let charged = handler
+            .volumes_db
+            .transaction(|volumes| {
+                charge::<()>(
+                    volumes,
+                    &high,
+                    "buyer",
+                    security,
+                    Liquidity::Taker,
+                    10_000,
+                    reserved,
+                )
+            })
+            .unwrap();
+        assert_eq!(charged, fee(10_000, 20));
+        assert_eq!(handler.volume("buyer", security).unwrap(), 10_000);
+    }
+}
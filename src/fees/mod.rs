@@ -0,0 +1,67 @@
+mod handler;
+mod model;
+
+use crate::{auth, offers::Security, utils::json_body, with_client_ip, with_ctx, ClientIp, Ctx};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub use handler::{charge, FeeHandler};
+pub use model::{
+    fee, FeeError, FeeSchedule, FeeScheduleKey, FeeTier, Liquidity, Volume, VolumeKey,
+};
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    get_schedule(ctx.clone()).or(set_schedule(ctx))
+}
+
+fn get_schedule(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("instruments" / u64 / "fees")
+        .and(warp::get())
+        .and(with_ctx(ctx))
+        .and_then(async move |id: u64, ctx: Ctx| -> Result<Box<dyn Reply>, Infallible> {
+            let security = Security(id);
+            if ctx.instrument_handler.get(security).unwrap().is_none() {
+                return Ok(Box::new(reply::with_status(
+                    reply::json(&FeeError::UnknownInstrument),
+                    StatusCode::NOT_FOUND,
+                )));
+            }
+            Ok(Box::new(reply::json(
+                &ctx.fee_handler.get(security).unwrap(),
+            )))
+        })
+}
+
+fn set_schedule(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("instruments" / u64 / "fees")
+        .and(warp::put())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<FeeSchedule>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |id: u64,
+                        cookie: String,
+                        ip: ClientIp,
+                        schedule: FeeSchedule,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                let security = Security(id);
+                if ctx.instrument_handler.get(security).unwrap().is_none() {
+                    return Ok(Box::new(reply::with_status(
+                        reply::json(&FeeError::UnknownInstrument),
+                        StatusCode::NOT_FOUND,
+                    )));
+                }
+                Ok(match ctx.fee_handler.set(security, schedule) {
+                    Ok(schedule) => Box::new(reply::json(&schedule)),
+                    Err(e) => {
+                        Box::new(reply::with_status(reply::json(&e), StatusCode::BAD_REQUEST))
+                    }
+                })
+            },
+        )
+}
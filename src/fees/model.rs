@@ -0,0 +1,167 @@
+use crate::{
+    bincode_des, bincode_ser, derive_key_of, derive_monotonic_key, derive_simple_struct,
+    offers::Security, typed_tree::KeyOf,
+};
+use serde::{Deserialize, Serialize};
+
+/// Rates are in basis points of the notional.
+const BPS: u128 = 10_000;
+
+/// Fee charged on `notional` at a rate of `bps`, rounded down.
+pub fn fee(notional: u64, bps: u32) -> u64 {
+    (notional as u128 * bps as u128 / BPS) as u64
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct FeeScheduleKey(pub [u8; 8]);
+derive_monotonic_key!(FeeScheduleKey);
+
+impl From<Security> for FeeScheduleKey {
+    fn from(security: Security) -> Self {
+        FeeScheduleKey::from(security.0)
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Liquidity {
+    /// The resting side of a fill.
+    Maker,
+    /// The incoming side of a fill.
+    Taker,
+}
+
+/// Fees of an instrument, charged in its quote asset. Users whose traded
+/// notional reached the `min_volume` of a tier pay the rates of the highest
+/// such tier instead of the base ones.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct FeeSchedule {
+    pub maker_bps: u32,
+    pub taker_bps: u32,
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct FeeTier {
+    pub min_volume: u64,
+    pub maker_bps: u32,
+    pub taker_bps: u32,
+}
+
+impl FeeSchedule {
+    /// Rates must not exceed 100% and tiers must be sorted by strictly
+    /// increasing volume.
+    pub fn validate(&self) -> Result<(), FeeError> {
+        let sorted = self
+            .tiers
+            .windows(2)
+            .all(|w| w[0].min_volume < w[1].min_volume);
+        if !sorted || self.rates().any(|bps| bps as u128 > BPS) {
+            return Err(FeeError::InvalidSchedule);
+        }
+        Ok(())
+    }
+
+    /// Rate for a user that has traded `volume` so far.
+    pub fn rate(&self, liquidity: Liquidity, volume: u64) -> u32 {
+        let (maker_bps, taker_bps) = self
+            .tiers
+            .iter()
+            .rev()
+            .find(|t| t.min_volume <= volume)
+            .map_or((self.maker_bps, self.taker_bps), |t| {
+                (t.maker_bps, t.taker_bps)
+            });
+        match liquidity {
+            Liquidity::Maker => maker_bps,
+            Liquidity::Taker => taker_bps,
+        }
+    }
+
+    /// Highest rate of the schedule, reserved by buy orders on top of their
+    /// notional so the fee can always be paid.
+    pub fn max_rate(&self) -> u32 {
+        self.rates().max().unwrap_or(0)
+    }
+
+    fn rates(&self) -> impl Iterator<Item = u32> + '_ {
+        let base = vec![self.maker_bps, self.taker_bps];
+        self.tiers
+            .iter()
+            .flat_map(|t| vec![t.maker_bps, t.taker_bps])
+            .chain(base)
+    }
+}
+
+/// Traded notional of a user in one instrument: user id, a zero byte and
+/// the security.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct VolumeKey(Vec<u8>);
+
+impl VolumeKey {
+    pub fn new(user: &str, security: Security) -> Self {
+        let mut key = Vec::with_capacity(user.len() + 9);
+        key.extend_from_slice(user.as_bytes());
+        key.push(0);
+        key.extend_from_slice(&security.0.to_be_bytes());
+        VolumeKey(key)
+    }
+}
+
+impl std::convert::AsRef<[u8]> for VolumeKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct Volume {
+    pub notional: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum FeeError {
+    InvalidSchedule,
+    UnknownInstrument,
+}
+
+derive_key_of!(FeeScheduleKey, FeeSchedule, "FeeSchedule", 11);
+derive_key_of!(VolumeKey, Volume, "Volume", 12);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiered_rates() {
+        let schedule = FeeSchedule {
+            maker_bps: 10,
+            taker_bps: 20,
+            tiers: vec![
+                FeeTier {
+                    min_volume: 1_000,
+                    maker_bps: 5,
+                    taker_bps: 15,
+                },
+                FeeTier {
+                    min_volume: 10_000,
+                    maker_bps: 0,
+                    taker_bps: 10,
+                },
+            ],
+        };
+        assert_eq!(schedule.validate(), Ok(()));
+        assert_eq!(schedule.rate(Liquidity::Taker, 999), 20);
+        assert_eq!(schedule.rate(Liquidity::Maker, 1_000), 5);
+        assert_eq!(schedule.rate(Liquidity::Maker, 50_000), 0);
+        assert_eq!(schedule.max_rate(), 20);
+        assert_eq!(fee(12_345, 20), 24);
+
+        let unsorted = FeeSchedule {
+            tiers: schedule.tiers.iter().rev().cloned().collect(),
+            ..schedule
+        };
+        assert_eq!(unsorted.validate(), Err(FeeError::InvalidSchedule));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::instruments::{
+    Instrument, InstrumentError, InstrumentKey, InstrumentRequest, InstrumentStatus,
+    InstrumentSymbolKey, OfferValidationError,
+};
+use crate::offers::{OfferEvent, OfferEventKey, OfferEventRequest, OfferValue, Security};
+use crate::prelude::*;
+use sled::{abort, TransactionError, Transactional};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+/// Pairs registered on first start.
+const DEFAULT_PAIRS: [(&str, &str); 2] = [("BTC", "USD"), ("USD", "COP")];
+
+pub struct InstrumentHandler {
+    instruments_db: sled::Tree,
+    symbols_db: sled::Tree,
+    /// Id of the next instrument, held while one is created so rejected
+    /// requests don't leave gaps.
+    next_id: Mutex<u64>,
+}
+
+impl InstrumentHandler {
+    pub fn new(db: sled::Db) -> Self {
+        let mut instruments_db = db.open_tree(<InstrumentKey as KeyOf>::PREFIX).unwrap();
+        let symbols_db = db
+            .open_tree(<InstrumentSymbolKey as KeyOf>::PREFIX)
+            .unwrap();
+        let next_id = Mutex::new(
+            <sled::Tree as MonotonicTypedTree<InstrumentKey>>::get_max_key(&mut instruments_db)
+                .unwrap(),
+        );
+
+        let handler = Self {
+            instruments_db,
+            symbols_db,
+            next_id,
+        };
+        if handler.instruments_db.is_empty() {
+            for (base, quote) in DEFAULT_PAIRS.iter() {
+                handler
+                    .create(InstrumentRequest::with_defaults(base, quote))
+                    .unwrap();
+            }
+        }
+        handler
+    }
+
+    pub fn create(&self, request: InstrumentRequest) -> Result<Instrument, InstrumentError> {
+        let mut next_id = self.next_id.lock().unwrap();
+        let instrument = request.into_instrument(Security(*next_id))?;
+
+        let result =
+            (&self.instruments_db, &self.symbols_db).transaction(|(instruments, symbols)| {
+                let symbol_key = instrument.symbol_key();
+                if symbols.get(symbol_key.as_ref())?.is_some() {
+                    return abort(());
+                }
+                symbols.insert(symbol_key.as_ref(), instrument.security)?;
+                instruments.insert(&instrument.key().0[..], instrument.clone())?;
+                Ok(())
+            });
+        match result {
+            Ok(()) => {
+                *next_id += 1;
+                Ok(instrument)
+            }
+            Err(TransactionError::Abort(())) => Err(InstrumentError::SymbolTaken),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    pub fn get(&self, security: Security) -> sled::Result<Option<Instrument>> {
+        self.instruments_db
+            .get_typed(&InstrumentKey::from(security.0))
+    }
+
+    pub fn get_by_symbol(&self, symbol: &str) -> sled::Result<Option<Instrument>> {
+        match self.symbols_db.get_typed(&InstrumentSymbolKey(symbol))? {
+            Some(security) => self.get(security),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list(&self) -> sled::Result<Vec<Instrument>> {
+        self.instruments_db
+            .iter()
+            .values()
+            .map(|v| v.and_then(Instrument::try_from))
+            .collect()
+    }
+
+    /// Whether `asset` is the base or the quote of any instrument.
+    pub fn has_asset(&self, asset: &str) -> sled::Result<bool> {
+        Ok(self
+            .list()?
+            .iter()
+            .any(|i| i.base == asset || i.quote == asset))
+    }
+
+    /// Suspends or resumes an instrument, halted ones are left to their
+    /// kill switch.
+    pub fn set_status(
+        &self,
+        security: Security,
+        status: InstrumentStatus,
+    ) -> Result<Instrument, InstrumentError> {
+        self.update_status(security, |current| match current {
+            InstrumentStatus::Halted => Err(InstrumentError::Halted),
+            _ => Ok(status),
+        })
+    }
+
+    pub fn halt(&self, security: Security) -> Result<Instrument, InstrumentError> {
+        self.update_status(security, |_| Ok(InstrumentStatus::Halted))
+    }
+
+    /// Resumes a halted instrument.
+    pub fn release_halt(&self, security: Security) -> Result<Instrument, InstrumentError> {
+        self.update_status(security, |current| match current {
+            InstrumentStatus::Halted => Ok(InstrumentStatus::Active),
+            _ => Err(InstrumentError::NotHalted),
+        })
+    }
+
+    /// Sets the status `f` gives for the current one, in a transaction so a
+    /// halt can't be undone by a change that read the status before it.
+    fn update_status<F>(&self, security: Security, f: F) -> Result<Instrument, InstrumentError>
+    where
+        F: Fn(InstrumentStatus) -> Result<InstrumentStatus, InstrumentError>,
+    {
+        let key = InstrumentKey::from(security.0);
+        let result = self.instruments_db.transaction(|instruments| {
+            let mut instrument: Instrument = match instruments.get_typed(&key)? {
+                Some(instrument) => instrument,
+                None => return abort(InstrumentError::NotFound),
+            };
+            instrument.status = match f(instrument.status) {
+                Ok(status) => status,
+                Err(e) => return abort(e),
+            };
+            instruments.insert_typed(&key, instrument.clone())?;
+            Ok(instrument)
+        });
+        match result {
+            Ok(instrument) => Ok(instrument),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => panic!("{:?}", e),
+        }
+    }
+
+    /// Resolves the symbol of a client request against the registry and
+    /// validates it with the instrument's reference data.
+    pub fn resolve(&self, event: OfferEventRequest) -> Result<OfferEvent, OfferValidationError> {
+        match event {
+            OfferEventRequest::Delete(k) => Ok(OfferEvent::Delete(OfferEventKey::from(k))),
+            OfferEventRequest::Add(request) => {
+                let instrument = self
+                    .get_by_symbol(&request.security)
+                    .unwrap()
+                    .ok_or(OfferValidationError::UnknownSecurity)?;
+                let value = OfferValue {
+                    security: instrument.security,
+                    side: request.side,
+                    amount: request.amount,
+                    price: request.price,
+                };
+                instrument.validate(&value)?;
+                Ok(OfferEvent::Add(value))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_creates_keep_ids() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = InstrumentHandler::new(db);
+        let last = handler.list().unwrap().len() as u64;
+
+        let mut invalid = InstrumentRequest::with_defaults("ETH", "USD");
+        invalid.tick_size = 0;
+        assert_eq!(
+            handler.create(invalid),
+            Err(InstrumentError::InvalidDefinition)
+        );
+        assert_eq!(
+            handler.create(InstrumentRequest::with_defaults("BTC", "USD")),
+            Err(InstrumentError::SymbolTaken)
+        );
+        let created = handler
+            .create(InstrumentRequest::with_defaults("ETH", "USD"))
+            .unwrap();
+        assert_eq!(created.security, Security(last + 1));
+    }
+
+    #[test]
+    fn halt_outlasts_resume() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = InstrumentHandler::new(db);
+        let security = handler.get_by_symbol("BTC/USD").unwrap().unwrap().security;
+
+        assert_eq!(
+            handler.release_halt(security),
+            Err(InstrumentError::NotHalted)
+        );
+        handler.halt(security).unwrap();
+        assert_eq!(
+            handler.set_status(security, InstrumentStatus::Active),
+            Err(InstrumentError::Halted)
+        );
+        let instrument = handler.release_halt(security).unwrap();
+        assert_eq!(instrument.status, InstrumentStatus::Active);
+        assert!(handler
+            .set_status(security, InstrumentStatus::Suspended)
+            .is_ok());
+    }
+
+    #[test]
+    fn halt_wins_concurrent_resume() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = std::sync::Arc::new(InstrumentHandler::new(db));
+        let security = handler.get_by_symbol("BTC/USD").unwrap().unwrap().security;
+
+        for _ in 0..20 {
+            handler.release_halt(security).ok();
+            let resumes: Vec<_> = (0..4)
+                .map(|_| {
+                    let handler = handler.clone();
+                    std::thread::spawn(move || {
+                        handler.set_status(security, InstrumentStatus::Active).ok();
+                    })
+                })
+                .collect();
+            handler.halt(security).unwrap();
+            for resume in resumes {
+                resume.join().unwrap();
+            }
+            let instrument = handler.get(security).unwrap().unwrap();
+            assert_eq!(instrument.status, InstrumentStatus::Halted);
+        }
+    }
+}
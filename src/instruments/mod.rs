@@ -0,0 +1,104 @@
+mod handler;
+mod model;
+
+use crate::{auth, offers::Security, utils::json_body, with_client_ip, with_ctx, ClientIp, Ctx};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub use handler::InstrumentHandler;
+pub use model::{
+    Instrument, InstrumentError, InstrumentKey, InstrumentRequest, InstrumentStatus,
+    InstrumentSymbolKey, OfferValidationError,
+};
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    list_instruments(ctx.clone())
+        .or(create_instrument(ctx.clone()))
+        .or(set_status(
+            ctx.clone(),
+            "suspend",
+            InstrumentStatus::Suspended,
+        ))
+        .or(set_status(ctx, "resume", InstrumentStatus::Active))
+}
+
+impl InstrumentError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            InstrumentError::NotFound => StatusCode::NOT_FOUND,
+            InstrumentError::Halted | InstrumentError::NotHalted => StatusCode::CONFLICT,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+fn list_instruments(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("instruments")
+        .and(warp::get())
+        .and(with_ctx(ctx))
+        .and_then(async move |ctx: Ctx| -> Result<_, Infallible> {
+            let instruments = ctx.instrument_handler.list().unwrap();
+            Ok(reply::json(&instruments))
+        })
+}
+
+fn create_instrument(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("instruments")
+        .and(warp::post())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<InstrumentRequest>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |cookie: String,
+                        ip: ClientIp,
+                        request: InstrumentRequest,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                Ok(match ctx.instrument_handler.create(request) {
+                    Ok(instrument) => Box::new(reply::with_status(
+                        reply::json(&instrument),
+                        StatusCode::CREATED,
+                    )),
+                    Err(e) => {
+                        Box::new(reply::with_status(reply::json(&e), StatusCode::BAD_REQUEST))
+                    }
+                })
+            },
+        )
+}
+
+fn set_status(
+    ctx: Ctx,
+    action: &'static str,
+    status: InstrumentStatus,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path("instruments")
+        .and(warp::path::param::<u64>())
+        .and(warp::path(action))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |id: u64,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                Ok(
+                    match ctx.instrument_handler.set_status(Security(id), status) {
+                        Ok(instrument) => Box::new(reply::json(&instrument)),
+                        Err(e) => Box::new(reply::with_status(reply::json(&e), e.status())),
+                    },
+                )
+            },
+        )
+}
@@ -0,0 +1,246 @@
+use crate::{
+    bincode_des, bincode_ser, derive_key_of, derive_monotonic_key, derive_simple_struct,
+    offers::{OfferValue, Security, Side},
+    typed_tree::KeyOf,
+    utils::{f64_to_u64, u64_to_f64},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct InstrumentKey(pub [u8; 8]);
+derive_monotonic_key!(InstrumentKey);
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct InstrumentSymbolKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for InstrumentSymbolKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Reference data of a tradable currency pair.
+///
+/// Prices are quoted in `quote` units per unit of `base`, as integers with
+/// `price_scale` decimal places, and must be a multiple of `tick_size`.
+/// Amounts are in `base` units, must be a multiple of `lot_size` and lie
+/// within `min_amount..=max_amount`.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct Instrument {
+    pub security: Security,
+    pub symbol: String,
+    pub base: String,
+    pub quote: String,
+    pub tick_size: u64,
+    pub lot_size: u64,
+    pub min_amount: u64,
+    pub max_amount: u64,
+    pub price_scale: u32,
+    pub status: InstrumentStatus,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstrumentStatus {
+    Active,
+    Suspended,
+    /// Stopped by a kill switch, only releasing it resumes trading.
+    Halted,
+}
+
+/// Definition of a new instrument, the registry assigns its id and
+/// names it `BASE/QUOTE`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstrumentRequest {
+    pub base: String,
+    pub quote: String,
+    pub tick_size: u64,
+    pub lot_size: u64,
+    pub min_amount: u64,
+    pub max_amount: u64,
+    pub price_scale: u32,
+}
+
+impl InstrumentRequest {
+    pub fn with_defaults(base: &str, quote: &str) -> Self {
+        InstrumentRequest {
+            base: base.to_string(),
+            quote: quote.to_string(),
+            tick_size: 1,
+            lot_size: 1,
+            min_amount: 1,
+            max_amount: u64::MAX,
+            price_scale: 4,
+        }
+    }
+
+    pub fn into_instrument(self, security: Security) -> Result<Instrument, InstrumentError> {
+        if self.base.is_empty()
+            || self.quote.is_empty()
+            || self.base == self.quote
+            || self.base.contains('/')
+            || self.quote.contains('/')
+            || self.tick_size == 0
+            || self.lot_size == 0
+            || self.min_amount > self.max_amount
+        {
+            return Err(InstrumentError::InvalidDefinition);
+        }
+        Ok(Instrument {
+            security,
+            symbol: format!("{}/{}", self.base, self.quote),
+            base: self.base,
+            quote: self.quote,
+            tick_size: self.tick_size,
+            lot_size: self.lot_size,
+            min_amount: self.min_amount,
+            max_amount: self.max_amount,
+            price_scale: self.price_scale,
+            status: InstrumentStatus::Active,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum InstrumentError {
+    InvalidDefinition,
+    SymbolTaken,
+    NotFound,
+    /// A kill switch holds the instrument.
+    Halted,
+    NotHalted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum OfferValidationError {
+    UnknownSecurity,
+    SecuritySuspended,
+    SecurityHalted,
+    InvalidPrice,
+    NotionalTooLarge,
+    PriceNotMultipleOfTick { tick_size: u64 },
+    AmountNotMultipleOfLot { lot_size: u64 },
+    AmountBelowMinimum { min_amount: u64 },
+    AmountAboveMaximum { max_amount: u64 },
+}
+
+impl Instrument {
+    pub fn key(&self) -> InstrumentKey {
+        InstrumentKey::from(self.security.0)
+    }
+
+    pub fn symbol_key(&self) -> InstrumentSymbolKey<'_> {
+        InstrumentSymbolKey(self.symbol.as_str())
+    }
+
+    pub fn validate(&self, value: &OfferValue) -> Result<(), OfferValidationError> {
+        match self.status {
+            InstrumentStatus::Active => {}
+            InstrumentStatus::Suspended => return Err(OfferValidationError::SecuritySuspended),
+            InstrumentStatus::Halted => return Err(OfferValidationError::SecurityHalted),
+        }
+        if let Some(price) = value.price {
+            if price == 0 {
+                return Err(OfferValidationError::InvalidPrice);
+            }
+            if price % self.tick_size != 0 {
+                return Err(OfferValidationError::PriceNotMultipleOfTick {
+                    tick_size: self.tick_size,
+                });
+            }
+            if price.checked_mul(value.amount).is_none() {
+                return Err(OfferValidationError::NotionalTooLarge);
+            }
+        }
+        if value.amount % self.lot_size != 0 {
+            return Err(OfferValidationError::AmountNotMultipleOfLot {
+                lot_size: self.lot_size,
+            });
+        }
+        if value.amount < self.min_amount {
+            return Err(OfferValidationError::AmountBelowMinimum {
+                min_amount: self.min_amount,
+            });
+        }
+        if value.amount > self.max_amount {
+            return Err(OfferValidationError::AmountAboveMaximum {
+                max_amount: self.max_amount,
+            });
+        }
+        Ok(())
+    }
+
+    /// Asset an offer on `side` reserves until it is filled.
+    pub fn hold_asset(&self, side: Side) -> &str {
+        match side {
+            Side::Sell => &self.base,
+            Side::Buy => &self.quote,
+        }
+    }
+
+    pub fn price_from_f64(&self, price: f64) -> u64 {
+        f64_to_u64(price, self.price_scale)
+    }
+
+    pub fn price_to_f64(&self, price: u64) -> f64 {
+        u64_to_f64(price, self.price_scale)
+    }
+}
+
+derive_key_of!(InstrumentKey, Instrument, "Instrument", 4);
+derive_key_of!(InstrumentSymbolKey<'_>, Security, "InstrumentSymbol", 5);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_offer() {
+        let mut instrument = InstrumentRequest {
+            tick_size: 5,
+            lot_size: 10,
+            min_amount: 10,
+            max_amount: 100,
+            ..InstrumentRequest::with_defaults("BTC", "USD")
+        }
+        .into_instrument(Security(1))
+        .unwrap();
+        assert_eq!(instrument.symbol, "BTC/USD");
+        let mut value = OfferValue {
+            security: Security(1),
+            side: Side::Buy,
+            amount: 20,
+            price: Some(15),
+        };
+        assert_eq!(instrument.validate(&value), Ok(()));
+
+        value.price = Some(12);
+        assert_eq!(
+            instrument.validate(&value),
+            Err(OfferValidationError::PriceNotMultipleOfTick { tick_size: 5 })
+        );
+
+        value.price = None;
+        value.amount = 25;
+        assert_eq!(
+            instrument.validate(&value),
+            Err(OfferValidationError::AmountNotMultipleOfLot { lot_size: 10 })
+        );
+
+        value.amount = 110;
+        assert_eq!(
+            instrument.validate(&value),
+            Err(OfferValidationError::AmountAboveMaximum { max_amount: 100 })
+        );
+
+        value.amount = 20;
+        instrument.status = InstrumentStatus::Suspended;
+        assert_eq!(
+            instrument.validate(&value),
+            Err(OfferValidationError::SecuritySuspended)
+        );
+
+        assert_eq!(instrument.price_from_f64(1.5), 15_000);
+    }
+}
@@ -1,29 +1,68 @@
 #![feature(async_closure)]
 #![feature(vec_remove_item)]
 
+pub mod api_keys;
 pub mod auth;
+pub mod balances;
+pub mod candles;
+pub mod client_ip;
+pub mod config;
 pub mod deserializer;
 mod engine;
+pub mod executions;
+pub mod fees;
+pub mod instruments;
+pub mod market_data;
 mod matches;
 pub mod offers;
+pub mod risk;
 pub mod test_utils;
+pub mod trades;
 mod typed_tree;
 pub mod user;
 mod utils;
 
+use api_keys::ApiKeyHandler;
 use auth::AuthManager;
+use balances::BalanceHandler;
+use candles::CandleHandler;
+pub use client_ip::{with_client_ip, ClientIp};
+use config::{ClientIpSource, Config};
+use executions::ExecutionHandler;
+use fees::FeeHandler;
+use instruments::InstrumentHandler;
+use market_data::MarketDataHandler;
 use offers::OfferHandler;
-use serde::Deserialize;
+use risk::RiskHandler;
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
+use trades::TradeHandler;
 use warp::{Filter, Rejection, Reply};
 
 pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    auth::routes(ctx.clone()).or(offers::routes(ctx))
+    auth::routes(ctx.clone())
+        .or(api_keys::routes(ctx.clone()))
+        .or(instruments::routes(ctx.clone()))
+        .or(balances::routes(ctx.clone()))
+        .or(fees::routes(ctx.clone()))
+        .or(risk::routes(ctx.clone()))
+        .or(trades::routes(ctx.clone()))
+        .or(candles::routes(ctx.clone()))
+        .or(market_data::routes(ctx.clone()))
+        .or(executions::routes(ctx.clone()))
+        .or(offers::routes(ctx))
+        .recover(auth::recover_unauthorized)
+        .recover(client_ip::recover_unknown_ip)
 }
 
 pub type Ctx = Arc<CtxData>;
 
+/// Starts the background work of a server that needs its context.
+pub fn spawn_tasks(ctx: Ctx) {
+    tokio::spawn(auth::end_expired_sessions(ctx.clone()));
+    tokio::spawn(offers::expire_orders(ctx));
+}
+
 pub fn with_ctx(
     ctx: Ctx,
 ) -> impl Filter<Extract = (Ctx,), Error = std::convert::Infallible> + Clone {
@@ -34,7 +73,18 @@ const WASM_PATH: &str = r"C:\Users\jmanu\Desktop\rust\wapc-guest\target\wasm32-u
 
 pub struct CtxData {
     auth_manager: AuthManager,
+    api_key_handler: ApiKeyHandler,
     offer_handler: OfferHandler,
+    instrument_handler: InstrumentHandler,
+    balance_handler: BalanceHandler,
+    fee_handler: FeeHandler,
+    risk_handler: RiskHandler,
+    trade_handler: TradeHandler,
+    candle_handler: CandleHandler,
+    market_data: MarketDataHandler,
+    executions: ExecutionHandler,
+    funds_check: bool,
+    client_ip: ClientIpSource,
     test_auth: bool,
     error_on: Option<u32>,
     num_errors: AtomicU32,
@@ -42,27 +92,54 @@ pub struct CtxData {
 }
 
 impl CtxData {
-    pub fn new(db: sled::Db, test_auth: bool, error_on: Option<u32>) -> Self {
+    pub fn new(db: sled::Db, config: Config, test_auth: bool, error_on: Option<u32>) -> Self {
+        let balance_handler = BalanceHandler::new(db.clone());
+        let market_data = MarketDataHandler::new();
+        let executions = ExecutionHandler::new();
+        let instrument_handler = InstrumentHandler::new(db.clone());
+        for (currency, symbol) in &config.legacy_symbols {
+            if instrument_handler.get_by_symbol(symbol).unwrap().is_none() {
+                panic!("Legacy security {} mapped to unknown {}", currency, symbol);
+            }
+        }
         CtxData {
-            auth_manager: AuthManager::new(db.clone(), jsonwebtoken::Validation::default()),
-            offer_handler: OfferHandler::new(db),
+            auth_manager: AuthManager::new(db.clone(), &config).expect("Invalid JWT keys"),
+            api_key_handler: ApiKeyHandler::new(
+                db.clone(),
+                config.api_key_encryption_key.as_deref(),
+            )
+            .expect("API keys the encryption key can't open"),
+            offer_handler: OfferHandler::new(
+                db.clone(),
+                balance_handler.clone(),
+                market_data.clone(),
+                executions.clone(),
+            ),
+            instrument_handler,
+            balance_handler,
+            fee_handler: FeeHandler::new(db.clone()),
+            risk_handler: RiskHandler::new(db.clone()),
+            trade_handler: TradeHandler::new(db.clone()),
+            candle_handler: CandleHandler::new(db),
+            market_data,
+            executions,
+            funds_check: config.funds_check,
+            client_ip: config.client_ip,
             test_auth,
             error_on,
             num_errors: AtomicU32::new(0),
-            deserializer: deserializer::FormatDeserializer::new(WASM_PATH).unwrap(),
+            deserializer: deserializer::FormatDeserializer::new(WASM_PATH, config.legacy_symbols)
+                .unwrap(),
         }
     }
 }
 
-#[derive(Deserialize)]
-pub struct IpQueryParam {
-    ip: String,
-}
-
 pub mod prelude {
     pub use super::{
         bincode_des, bincode_ser, derive_key_of, derive_monotonic_key, derive_simple_struct,
-        typed_tree::{KeyOf, MonotonicTypedTree, TypedTree},
+        typed_tree::{
+            user_prefix, KeyOf, MonotonicTypedTree, TransactionalTypedTree, TypedTree,
+        },
         with_ctx, Ctx,
     };
 }
@@ -3,8 +3,9 @@
 
 use rand::prelude::*;
 use reto2::{
-    routes,
-    test_utils::{auth_test, availability_test, flexibility_test},
+    config::{ClientIpSource, Config, PasswordPolicy},
+    routes, spawn_tasks,
+    test_utils::{auth_test, availability_test, flexibility_test, OPERATOR},
     Ctx, CtxData,
 };
 use std::sync::Arc;
@@ -13,10 +14,12 @@ use std::sync::Arc;
 async fn main() {
     let test_auth = true;
     let test_flexibility = true;
-    
+    let config = test_config();
+
     if test_auth {
         let db = sled::Config::default().temporary(true).open().unwrap(); // sled::open("database.sled")?;
-        let ctx: Ctx = Arc::new(CtxData::new(db, test_auth, None));
+        let ctx: Ctx = Arc::new(CtxData::new(db, config, test_auth, None));
+        spawn_tasks(ctx.clone());
         if test_flexibility {
             tokio::spawn(flexibility_test(10, 50));
         } else {
@@ -33,9 +36,11 @@ async fn main() {
             let db = sled::Config::default().temporary(true).open().unwrap(); // sled::open("database.sled")?;
             let ctx: Ctx = Arc::new(CtxData::new(
                 db,
+                config.clone(),
                 test_auth,
                 if i == 2 { Some(error_on) } else { None },
             ));
+            spawn_tasks(ctx.clone());
             let f = warp::serve(routes(ctx.clone())).run(([127, 0, 0, 1], 3030 + i));
             if i == 2 {
                 tokio::spawn(availability_test(10, 10));
@@ -46,3 +51,29 @@ async fn main() {
         }
     }
 }
+
+/// The test sequences trade with users that never get funds, read the
+/// counters of the servers as operator, pose as clients of several IPs,
+/// fail logins on purpose and sign up with short numeric passwords or the
+/// user id as password.
+/// Everything else, such as the admins and the JWT keys, comes from the
+/// file named by `RETO2_CONFIG`.
+fn test_config() -> Config {
+    let config = Config::load().expect("Invalid configuration");
+    let mut operators = config.operators.clone();
+    operators.push(OPERATOR.to_string());
+    Config {
+        funds_check: false,
+        operators,
+        client_ip: ClientIpSource::Query,
+        login_throttle: None,
+        password_policy: PasswordPolicy {
+            min_length: 1,
+            require_letter: false,
+            require_digit: false,
+            forbid_user_id: false,
+            ..PasswordPolicy::default()
+        },
+        ..config
+    }
+}
@@ -0,0 +1,160 @@
+use crate::engine::BookDepth;
+use crate::market_data::model::changed_levels;
+use crate::market_data::{MarketEvent, MarketMessage};
+use crate::offers::{Security, Side};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Fans out the market data of every security to its subscribers.
+#[derive(Clone, Default)]
+pub struct MarketDataHandler {
+    feeds: Arc<Mutex<HashMap<Security, Feed>>>,
+}
+
+#[derive(Default)]
+struct Feed {
+    seq: u64,
+    depth: BookDepth,
+    subscribers: Vec<UnboundedSender<String>>,
+}
+
+impl Feed {
+    fn send(&mut self, symbol: &str, event: MarketEvent) {
+        self.seq += 1;
+        let message = MarketMessage {
+            security: symbol.to_string(),
+            seq: self.seq,
+            event,
+        };
+        let message = serde_json::to_string(&message).unwrap();
+        // Closed streams are dropped on the first message they miss.
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+    }
+}
+
+impl MarketDataHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Last `seq` sent for `security`.
+    pub fn seq(&self, security: Security) -> u64 {
+        let feeds = self.feeds.lock().unwrap();
+        feeds.get(&security).map_or(0, |feed| feed.seq)
+    }
+
+    /// Stream of the JSON messages of `security`, starting with a snapshot
+    /// of its book.
+    pub fn subscribe(&self, security: Security, symbol: &str) -> UnboundedReceiver<String> {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds.entry(security).or_default();
+        let (sender, receiver) = unbounded();
+        let snapshot = MarketMessage {
+            security: symbol.to_string(),
+            seq: feed.seq,
+            event: MarketEvent::Snapshot {
+                bids: feed.depth.bids.clone(),
+                asks: feed.depth.asks.clone(),
+            },
+        };
+        sender
+            .unbounded_send(serde_json::to_string(&snapshot).unwrap())
+            .unwrap();
+        feed.subscribers.push(sender);
+        receiver
+    }
+
+    /// Sends what changed since the last depth of `security`. Depths taken
+    /// before the last one are ignored.
+    pub fn publish_depth(&self, security: Security, symbol: &str, depth: BookDepth) {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds.entry(security).or_default();
+        if depth.event < feed.depth.event {
+            return;
+        }
+        let bids = changed_levels(&feed.depth.bids, &depth.bids, Side::Buy);
+        let asks = changed_levels(&feed.depth.asks, &depth.asks, Side::Sell);
+        let top_changed = feed.depth.bids.first() != depth.bids.first()
+            || feed.depth.asks.first() != depth.asks.first();
+        let (bid, ask) = (depth.bids.first().cloned(), depth.asks.first().cloned());
+        feed.depth = depth;
+
+        if !bids.is_empty() || !asks.is_empty() {
+            feed.send(symbol, MarketEvent::Depth { bids, asks });
+        }
+        if top_changed {
+            feed.send(symbol, MarketEvent::TopOfBook { bid, ask });
+        }
+    }
+
+    pub fn publish(&self, security: Security, symbol: &str, event: MarketEvent) {
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.entry(security).or_default().send(symbol, event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PriceLevel;
+
+    #[test]
+    fn depth_deltas() {
+        let handler = MarketDataHandler::new();
+        let mut stream = handler.subscribe(Security(1), "A-B");
+        let mut next = || -> MarketMessage {
+            serde_json::from_str(&stream.try_next().unwrap().unwrap()).unwrap()
+        };
+        assert_eq!(next().seq, 0);
+
+        let level = |price, amount, orders| PriceLevel {
+            price,
+            amount,
+            orders,
+        };
+        let depth = |event, bids| BookDepth {
+            bids,
+            asks: Vec::new(),
+            event: Some(event),
+        };
+        handler.publish_depth(Security(1), "A-B", depth(2, vec![level(10, 5, 1)]));
+        assert_eq!(next().seq, 1);
+        assert_eq!(
+            next().event,
+            MarketEvent::TopOfBook {
+                bid: Some(level(10, 5, 1)),
+                ask: None
+            }
+        );
+
+        // Stale depths are dropped
+        handler.publish_depth(Security(1), "A-B", depth(1, Vec::new()));
+        handler.publish_depth(
+            Security(1),
+            "A-B",
+            depth(3, vec![level(10, 2, 1), level(9, 4, 2)]),
+        );
+        let message = next();
+        assert_eq!(message.seq, 3);
+        assert_eq!(
+            message.event,
+            MarketEvent::Depth {
+                bids: vec![level(10, 2, 1), level(9, 4, 2)],
+                asks: Vec::new()
+            }
+        );
+        next();
+
+        handler.publish_depth(Security(1), "A-B", depth(4, vec![level(9, 4, 2)]));
+        assert_eq!(
+            next().event,
+            MarketEvent::Depth {
+                bids: vec![level(10, 0, 0)],
+                asks: Vec::new()
+            }
+        );
+        assert_eq!(handler.seq(Security(1)), 6);
+    }
+}
@@ -0,0 +1,74 @@
+mod handler;
+mod model;
+
+use crate::{
+    offers::{OrderError, Security},
+    with_ctx, Ctx,
+};
+use futures::{future, StreamExt};
+use std::convert::Infallible;
+use warp::{
+    http::StatusCode,
+    reply,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+pub use handler::MarketDataHandler;
+pub use model::{BookSnapshot, MarketEvent, MarketMessage};
+
+/// Levels of each side followed by the streams, as many as a snapshot of
+/// the book holds at most. Clients keep only the best ones.
+pub const STREAM_DEPTH: usize = 100;
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    market_stream(ctx)
+}
+
+fn market_stream(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("ws" / "market" / String)
+        .and(warp::ws())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |symbol: String, ws: Ws, ctx: Ctx| -> Result<Box<dyn Reply>, Infallible> {
+                let instrument = match ctx.instrument_handler.get_by_symbol(&symbol).unwrap() {
+                    Some(instrument) => instrument,
+                    None => {
+                        return Ok(Box::new(reply::with_status(
+                            reply::json(&OrderError::UnknownSecurity),
+                            StatusCode::NOT_FOUND,
+                        )))
+                    }
+                };
+                Ok(Box::new(ws.on_upgrade(move |socket| {
+                    stream_market(socket, ctx, instrument.security, symbol)
+                })))
+            },
+        )
+}
+
+async fn stream_market(socket: WebSocket, ctx: Ctx, security: Security, symbol: String) {
+    let (sink, mut incoming) = socket.split();
+    let messages = ctx.market_data.subscribe(security, &symbol);
+    let send = messages.map(|text| Ok(Message::text(text))).forward(sink);
+    // Reading answers pings and notices when the client goes away.
+    let receive = async move { while let Some(Ok(_)) = incoming.next().await {} };
+    futures::pin_mut!(send, receive);
+    future::select(send, receive).await;
+}
+
+/// Publishes the depth of the books changed by an event, as the engine has
+/// them once it is processed. The engine keeps the levels of its books, so
+/// answering costs it a copy of the levels sent and not a walk of the offers.
+pub(crate) async fn publish_books(ctx: &Ctx, securities: Vec<Security>) {
+    for security in securities {
+        let instrument = ctx
+            .instrument_handler
+            .get(security)
+            .unwrap()
+            .expect("Book of unknown instrument");
+        let depth = ctx.offer_handler.depth(security, STREAM_DEPTH).await;
+        ctx.market_data
+            .publish_depth(security, &instrument.symbol, depth);
+    }
+}
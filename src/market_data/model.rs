@@ -0,0 +1,127 @@
+use crate::engine::{BookDepth, PriceLevel};
+use crate::market_data::STREAM_DEPTH;
+use crate::offers::Side;
+use serde::{Deserialize, Serialize};
+
+/// Message of the market data stream of a security. `seq` grows by one with
+/// every message of the security, a gap means messages were missed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MarketMessage {
+    pub security: String,
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: MarketEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum MarketEvent {
+    /// Levels of the book when the stream was opened, at `seq`.
+    Snapshot {
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    /// Levels that changed, an `amount` of zero removes the level.
+    Depth {
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    TopOfBook {
+        bid: Option<PriceLevel>,
+        ask: Option<PriceLevel>,
+    },
+    /// Execution, `side` is the side of the taker.
+    Trade {
+        id: u64,
+        side: Side,
+        price: Option<u64>,
+        amount: u64,
+        time: u64,
+    },
+}
+
+/// Depth of a book along with the last `seq` of its market data stream, the
+/// deltas after it can be applied on top.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookSnapshot {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub depth: BookDepth,
+}
+
+/// Levels of `new` that differ from `old`, and the levels of `old` gone from
+/// `new` with no amount. Levels of `side` pushed past a full `new` are still
+/// in the book, they only left the window and aren't sent.
+pub fn changed_levels(old: &[PriceLevel], new: &[PriceLevel], side: Side) -> Vec<PriceLevel> {
+    let last = match new.last() {
+        Some(last) if new.len() >= STREAM_DEPTH => Some(last.price),
+        _ => None,
+    };
+    let in_window = |price: u64| match (last, side) {
+        (None, _) => true,
+        (Some(last), Side::Buy) => price >= last,
+        (Some(last), Side::Sell) => price <= last,
+    };
+    let mut changed: Vec<PriceLevel> = new
+        .iter()
+        .filter(|level| !old.contains(level))
+        .cloned()
+        .collect();
+    changed.extend(
+        old.iter()
+            .filter(|level| in_window(level.price))
+            .filter(|level| new.iter().all(|l| l.price != level.price))
+            .map(|level| PriceLevel {
+                price: level.price,
+                amount: 0,
+                orders: 0,
+            }),
+    );
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(prices: impl Iterator<Item = u64>) -> Vec<PriceLevel> {
+        prices
+            .map(|price| PriceLevel {
+                price,
+                amount: 1,
+                orders: 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn window_removals() {
+        let removed = |price| PriceLevel {
+            price,
+            amount: 0,
+            orders: 0,
+        };
+        // A better bid pushes the last one out of a full window.
+        let old = levels((1..=STREAM_DEPTH as u64).rev());
+        let new = levels((2..=STREAM_DEPTH as u64 + 1).rev());
+        assert_eq!(
+            changed_levels(&old, &new, Side::Buy),
+            levels(std::iter::once(STREAM_DEPTH as u64 + 1))
+        );
+        // A level gone from inside the window is still removed.
+        let new: Vec<_> = old.iter().filter(|l| l.price != 5).cloned().collect();
+        assert_eq!(changed_levels(&old, &new, Side::Buy), vec![removed(5)]);
+
+        let old = levels(1..=STREAM_DEPTH as u64);
+        let new = levels(0..STREAM_DEPTH as u64);
+        assert_eq!(
+            changed_levels(&old, &new, Side::Sell),
+            levels(std::iter::once(0))
+        );
+        // Below a full window every level gone is removed.
+        assert_eq!(
+            changed_levels(&old[..2], &old[1..2], Side::Sell),
+            vec![removed(1)]
+        );
+    }
+}
@@ -1,35 +1,83 @@
 use crate::{
-    engine::{MatchResult, Matches},
-    offers::{Offer, Security},
+    balances::{BalanceHandler, LedgerTx},
+    fees::{self, FeeHandler, Liquidity},
+    candles::CandleHandler,
+    engine::{Fill, MatchResult, Matches},
+    executions::{ExecutionEvent, ExecutionHandler},
+    instruments::{Instrument, InstrumentKey},
+    market_data::{MarketDataHandler, MarketEvent},
+    offers::{
+        ExpiringOrderKey, Offer, OfferEventKey, OpenOrderKey, Order, OrderFill, OrderKey, Security,
+        Side,
+    },
     prelude::*,
+    trades::{PrintEntry, SecurityMatchKey, UserMatchEntry, UserMatchKey},
+    utils::now_in_secs,
 };
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU64, Ordering};
 use crossbeam_channel::{self, Receiver};
 use serde::{Deserialize, Serialize};
+use sled::{ConflictableTransactionResult, Transactional, TransactionalTree};
 
 pub struct Match {
     pub key: MatchKey,
     pub value: MatchValue,
 }
 
-pub struct MatchKey([u8; 8]);
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct MatchKey(pub [u8; 8]);
 derive_monotonic_key!(MatchKey);
 
+/// Index of the matches of an order: the order key followed by the match
+/// key, so a prefix scan returns them in the order they were recorded.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct OrderMatchKey([u8; 16]);
+
+impl OrderMatchKey {
+    pub fn new(order: &OfferEventKey, key: &MatchKey) -> Self {
+        let mut index = [0; 16];
+        index[..8].copy_from_slice(&order.0);
+        index[8..].copy_from_slice(&key.0);
+        OrderMatchKey(index)
+    }
+}
+
+impl std::convert::AsRef<[u8]> for OrderMatchKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// One side of an execution. `amount` is in base units and `notional` in
+/// quote units of the instrument, the latter is unknown when two market
+/// offers meet.
 #[derive(Deserialize, Serialize, Debug)]
 pub struct MatchValue {
     pub reference: [u8; 8],
     pub security: Security,
+    pub side: Side,
     pub price: Option<u64>,
     pub amount: u64,
+    pub notional: Option<u64>,
+    pub liquidity: Liquidity,
+    /// Charged in the quote asset.
+    pub fee: u64,
+    /// Seconds since the epoch.
+    pub time: u64,
 }
 
-impl From<Offer> for MatchValue {
-    fn from(o: Offer) -> Self {
+impl MatchValue {
+    pub fn new(offer: &Offer, fill: &Fill, liquidity: Liquidity, fee: u64, time: u64) -> Self {
         MatchValue {
-            amount: o.value.amount,
-            price: o.value.price,
-            reference: o.key.into(),
-            security: o.value.security,
+            reference: offer.key.clone().into(),
+            security: offer.value.security,
+            side: offer.value.side,
+            price: fill.price(),
+            amount: offer.value.amount,
+            notional: fill.notional(),
+            liquidity,
+            fee,
+            time,
         }
     }
 }
@@ -37,11 +85,38 @@ impl From<Offer> for MatchValue {
 pub struct MatchPersistor {
     receiver: Receiver<Matches>,
     db: sled::Tree,
+    order_matches_db: sled::Tree,
+    security_matches_db: sled::Tree,
+    user_matches_db: sled::Tree,
+    orders_db: sled::Tree,
+    open_orders_db: sled::Tree,
+    expiring_orders_db: sled::Tree,
+    balances: BalanceHandler,
+    fees: FeeHandler,
+    candles: CandleHandler,
+    market_data: MarketDataHandler,
+    executions: ExecutionHandler,
+    instruments_db: sled::Tree,
     atomic: AtomicU64,
 }
 
 impl MatchPersistor {
-    pub fn new(receiver: Receiver<Matches>, db: sled::Db) -> Self {
+    pub fn new(
+        receiver: Receiver<Matches>,
+        db: sled::Db,
+        balances: BalanceHandler,
+        market_data: MarketDataHandler,
+        executions: ExecutionHandler,
+    ) -> Self {
+        let order_matches_db = db.open_tree(<OrderMatchKey as KeyOf>::PREFIX).unwrap();
+        let security_matches_db = db.open_tree(<SecurityMatchKey as KeyOf>::PREFIX).unwrap();
+        let user_matches_db = db.open_tree(<UserMatchKey as KeyOf>::PREFIX).unwrap();
+        let orders_db = db.open_tree(<OrderKey as KeyOf>::PREFIX).unwrap();
+        let open_orders_db = db.open_tree(<OpenOrderKey as KeyOf>::PREFIX).unwrap();
+        let expiring_orders_db = db.open_tree(<ExpiringOrderKey as KeyOf>::PREFIX).unwrap();
+        let fees = FeeHandler::new(db.clone());
+        let candles = CandleHandler::new(db.clone());
+        let instruments_db = db.open_tree(<InstrumentKey as KeyOf>::PREFIX).unwrap();
         let mut db = db.open_tree(<MatchKey as KeyOf>::PREFIX).unwrap();
         let atomic = AtomicU64::from(
             <sled::Tree as MonotonicTypedTree<MatchKey>>::get_max_key(&mut db).unwrap(),
@@ -50,6 +125,18 @@ impl MatchPersistor {
         MatchPersistor {
             receiver,
             db,
+            order_matches_db,
+            security_matches_db,
+            user_matches_db,
+            orders_db,
+            open_orders_db,
+            expiring_orders_db,
+            balances,
+            fees,
+            candles,
+            market_data,
+            executions,
+            instruments_db,
             atomic,
         }
     }
@@ -62,22 +149,233 @@ impl MatchPersistor {
             counter += 1;
             println!("MatchPersistor: {}", counter);
 
-            if let MatchResult::Partial {
-                mut offer,
-                to_substract,
-            } = matches.result
-            {
-                offer.value.amount -= to_substract;
-                self.db
-                    .insert_monotonic_atomic(&self.atomic, offer.into())
-                    .unwrap() as (MatchKey, Option<_>);
+            match &matches.result {
+                MatchResult::Cancelled(offer) => self.record_cancel(offer),
+                MatchResult::MassCancelled(offers) => {
+                    offers.iter().for_each(|o| self.record_cancel(o))
+                }
+                _ => {}
             }
-
-            for m in matches.completed.into_iter().map(|o| o.into()) {
-                self.db.insert_monotonic_atomic(&self.atomic, m).unwrap() as (MatchKey, Option<_>);
+            for fill in matches.fills() {
+                self.record_fill(&fill);
             }
         }
     }
+
+    fn instrument(&self, security: Security) -> Instrument {
+        self.instruments_db
+            .get_typed(&InstrumentKey::from(security.0))
+            .unwrap()
+            .expect("Offer of unknown instrument")
+    }
+
+    /// Records both sides of a fill, updating their orders, charging their
+    /// fees and settling the funded ones in the same transaction. The taker
+    /// side is the public print of the fill, the owners of both get an
+    /// execution report.
+    fn record_fill(&self, fill: &Fill) {
+        let security = fill.maker.value.security;
+        let time = now_in_secs();
+        let instrument = self.instrument(security);
+        let schedule = self.fees.get(security).unwrap();
+        let notional = fill.notional().unwrap_or(0);
+        let sides = [
+            (
+                MatchKey::from(self.atomic.fetch_add(1, Ordering::SeqCst)),
+                &fill.maker,
+                Liquidity::Maker,
+            ),
+            (
+                MatchKey::from(self.atomic.fetch_add(1, Ordering::SeqCst)),
+                &fill.taker,
+                Liquidity::Taker,
+            ),
+        ];
+
+        let trees = (
+            &self.db,
+            &self.order_matches_db,
+            &self.security_matches_db,
+            &self.user_matches_db,
+            &self.orders_db,
+            &self.open_orders_db,
+            &self.fees.volumes_db,
+            &self.balances.balances_db,
+            &self.balances.journal_db,
+            &self.balances.journal_index_db,
+        );
+        let executions = trees
+            .transaction(|trees| {
+                let (
+                    db,
+                    order_matches,
+                    security_matches,
+                    user_matches,
+                    orders,
+                    open_orders,
+                    volumes,
+                    balances,
+                    journal,
+                    journal_index,
+                ) = trees;
+                let tx = LedgerTx {
+                    balances,
+                    journal,
+                    journal_index,
+                };
+                let mut executions = Vec::new();
+                for (key, offer, liquidity) in sides.iter() {
+                    let mut fee = 0;
+                    let mut filled = None;
+                    update_order(orders, open_orders, offer, |order| {
+                        let released = order.fill(offer.value.amount);
+                        filled = Some((order.owner.clone(), order.remaining));
+                        fee = fees::charge(
+                            volumes,
+                            &schedule,
+                            &order.owner,
+                            security,
+                            *liquidity,
+                            notional,
+                            order.fee_reserve_bps,
+                        )?;
+                        if order.funded {
+                            self.balances.settle(
+                                &tx, &offer.key, order, &instrument, fill, released, fee,
+                            )?;
+                        }
+                        Ok(())
+                    })?;
+                    let value = MatchValue::new(offer, fill, *liquidity, fee, time);
+                    let order_fill = OrderFill {
+                        match_id: key.clone().into(),
+                        price: value.price,
+                        amount: value.amount,
+                        notional: value.notional,
+                        liquidity: *liquidity,
+                        fee,
+                    };
+                    db.insert_typed(key, value)?;
+                    order_matches.insert_typed(&OrderMatchKey::new(&offer.key, key), key.clone())?;
+                    if let Liquidity::Taker = liquidity {
+                        security_matches.insert_typed(
+                            &SecurityMatchKey::new(security, key),
+                            PrintEntry { time },
+                        )?;
+                    }
+                    if let Some((owner, remaining)) = filled {
+                        user_matches.insert_typed(
+                            &UserMatchKey::new(&owner, key),
+                            UserMatchEntry { security, time },
+                        )?;
+                        let order = offer.key.clone().into();
+                        executions.push((owner, ExecutionEvent::fill(order, order_fill, remaining)));
+                    }
+                }
+                Ok(executions)
+            })
+            .unwrap();
+        for (owner, event) in executions {
+            self.executions.publish(&owner, event);
+        }
+
+        if let Some(price) = fill.price() {
+            self.candles
+                .record(security, price, fill.taker.value.amount, time)
+                .unwrap();
+        }
+        let (key, taker, _) = &sides[1];
+        let print = MarketEvent::Trade {
+            id: key.clone().into(),
+            side: taker.value.side,
+            price: fill.price(),
+            amount: taker.value.amount,
+            time,
+        };
+        self.market_data
+            .publish(security, &instrument.symbol, print);
+    }
+
+    fn record_cancel(&self, offer: &Offer) {
+        let instrument = self.instrument(offer.value.security);
+        let now = now_in_secs();
+
+        let trees = (
+            &self.orders_db,
+            &self.open_orders_db,
+            &self.expiring_orders_db,
+            &self.balances.balances_db,
+            &self.balances.journal_db,
+            &self.balances.journal_index_db,
+        );
+        let owner = trees
+            .transaction(
+                |(orders, open_orders, expiring, balances, journal, journal_index)| {
+                    let tx = LedgerTx {
+                        balances,
+                        journal,
+                        journal_index,
+                    };
+                    let mut owner = None;
+                    let mut expire_time = None;
+                    update_order(orders, open_orders, offer, |order| {
+                        let released = if order.is_due(now) {
+                            order.expire()
+                        } else {
+                            order.cancel()
+                        };
+                        owner = Some((order.owner.clone(), order.expired));
+                        expire_time = order.expire_time;
+                        if order.funded {
+                            self.balances.release(&tx, order, &instrument, released)?;
+                        }
+                        Ok(())
+                    })?;
+                    // Only now, so an expiry that never got here is tried again.
+                    if let Some(expire_time) = expire_time {
+                        let index = ExpiringOrderKey::new(expire_time, &OrderKey::from(&offer.key));
+                        expiring.remove(index.as_ref())?;
+                    }
+                    Ok(owner)
+                },
+            )
+            .unwrap();
+
+        if let Some((owner, expired)) = owner {
+            let order = offer.key.clone().into();
+            let remaining = offer.value.amount;
+            let event = if expired {
+                ExecutionEvent::Expired { order, remaining }
+            } else {
+                ExecutionEvent::Cancelled { order, remaining }
+            };
+            self.executions.publish(&owner, event);
+        }
+    }
+}
+
+/// Applies `f` to the order of `offer`, dropping it from the open orders of
+/// its owner once nothing remains. Offers accepted before orders were tracked
+/// are skipped.
+fn update_order<F>(
+    orders: &TransactionalTree,
+    open_orders: &TransactionalTree,
+    offer: &Offer,
+    f: F,
+) -> ConflictableTransactionResult<(), ()>
+where
+    F: FnOnce(&mut Order) -> ConflictableTransactionResult<(), ()>,
+{
+    let key = OrderKey::from(&offer.key);
+    if let Some(mut order) = orders.get_typed(&key)? {
+        f(&mut order)?;
+        if order.remaining == 0 {
+            open_orders.remove(OpenOrderKey::new(&order.owner, &key).as_ref())?;
+        }
+        orders.insert_typed(&key, order)?;
+    }
+    Ok(())
 }
 
 derive_key_of!(MatchKey, MatchValue, "Match", 3);
+derive_key_of!(OrderMatchKey, MatchKey, "OrderMatch", 16);
@@ -1,9 +1,18 @@
-use crate::engine::{Engine, KeyedBinaryHeapEngine, MatchResult, Matches};
-use crate::matches::{MatchKey, MatchPersistor};
-use crate::offers::{OfferEvent, OfferEventKey, OfferEventKeyed};
+use crate::balances::BalanceHandler;
+use crate::engine::{BookDepth, DepthRequest, Engine, KeyedBinaryHeapEngine, MatchResult, Matches};
+use crate::executions::ExecutionHandler;
+use crate::market_data::MarketDataHandler;
+use crate::matches::{MatchKey, MatchPersistor, MatchValue, OrderMatchKey};
+use crate::offers::{
+    ExpiringOrderKey, OfferEvent, OfferEventKey, OfferEventKeyed, OpenOrderKey, Order, OrderFill,
+    OrderFilter, OrderKey, OrderState, Security, UserOrderEntry, UserOrderKey,
+};
 use crate::prelude::*;
 use crossbeam_channel::{unbounded, Sender};
-use std::sync::atomic::AtomicU64;
+use futures::channel::oneshot;
+use sled::{TransactionError, Transactional};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{collections::HashMap, thread};
 use std::{
     future::Future,
@@ -14,27 +23,51 @@ use std::{
 
 pub struct OfferHandler {
     offers_db: sled::Tree,
+    orders_db: sled::Tree,
+    open_orders_db: sled::Tree,
+    user_orders_db: sled::Tree,
+    expiring_orders_db: sled::Tree,
+    matches_db: sled::Tree,
+    order_matches_db: sled::Tree,
     pub offer_counter: AtomicU64,
     sender_offer: Sender<OfferEventKeyed>,
+    sender_depth: Sender<DepthRequest>,
     s_matches: Sender<Matches>,
     subscriptions: Arc<Mutex<HashMap<OfferEventKey, WaitMatches>>>,
 }
 
 impl OfferHandler {
-    pub fn new(db: sled::Db) -> Self {
+    pub fn new(
+        db: sled::Db,
+        balances: BalanceHandler,
+        market_data: MarketDataHandler,
+        executions: ExecutionHandler,
+    ) -> Self {
         let (s_offer, r_offer) = unbounded::<OfferEventKeyed>();
         let (s_matches, r_matches) = unbounded::<Matches>();
         let (s_matches2, r_matches2) = unbounded::<Matches>();
+        let (s_depth, r_depth) = unbounded::<DepthRequest>();
 
-        let mut engine = Engine::<KeyedBinaryHeapEngine>::new(r_offer, s_matches);
+        let mut engine =
+            Engine::<KeyedBinaryHeapEngine>::new(r_offer, s_matches).with_depth_requests(r_depth);
 
         let _engine_handle = thread::spawn(move || engine.start());
-        let mut persistor = MatchPersistor::new(r_matches2, db.clone());
+        let mut persistor =
+            MatchPersistor::new(r_matches2, db.clone(), balances, market_data, executions);
         let _persistor_handle = thread::spawn(move || persistor.start());
 
-        let mut offers_db = db.open_tree(<MatchKey as KeyOf>::PREFIX).unwrap();
+        let mut offers_db = db.open_tree(<OfferEventKey as KeyOf>::PREFIX).unwrap();
+        let orders_db = db.open_tree(<OrderKey as KeyOf>::PREFIX).unwrap();
+        let open_orders_db = db.open_tree(<OpenOrderKey as KeyOf>::PREFIX).unwrap();
+        let user_orders_db = db.open_tree(<UserOrderKey as KeyOf>::PREFIX).unwrap();
+        if user_orders_db.is_empty() {
+            index_user_orders(&orders_db, &user_orders_db).unwrap();
+        }
+        let expiring_orders_db = db.open_tree(<ExpiringOrderKey as KeyOf>::PREFIX).unwrap();
+        let matches_db = db.open_tree(<MatchKey as KeyOf>::PREFIX).unwrap();
+        let order_matches_db = db.open_tree(<OrderMatchKey as KeyOf>::PREFIX).unwrap();
         let offer_counter = AtomicU64::from(
-            <sled::Tree as MonotonicTypedTree<MatchKey>>::get_max_key(&mut offers_db).unwrap(),
+            <sled::Tree as MonotonicTypedTree<OfferEventKey>>::get_max_key(&mut offers_db).unwrap(),
         );
 
         let subscriptions = Arc::new(Mutex::from(HashMap::<OfferEventKey, WaitMatches>::new()));
@@ -52,21 +85,154 @@ impl OfferHandler {
 
         Self {
             offers_db,
+            orders_db,
+            open_orders_db,
+            user_orders_db,
+            expiring_orders_db,
+            matches_db,
+            order_matches_db,
             offer_counter,
             sender_offer: s_offer,
+            sender_depth: s_depth,
             s_matches: s_matches2,
             subscriptions,
         }
     }
 
-    pub async fn persist_offer(&self, event: OfferEvent) -> sled::Result<OfferEventKey> {
-        let (key, _): (OfferEventKey, _) = self
-            .offers_db
-            .insert_monotonic_atomic(&self.offer_counter, event.clone())?;
+    /// Persists the event along with the order it opens, if any.
+    pub async fn persist_offer(
+        &self,
+        event: OfferEvent,
+        order: Option<Order>,
+    ) -> sled::Result<OfferEventKey> {
+        let key = OfferEventKey::from(self.offer_counter.fetch_add(1, Ordering::SeqCst));
+        let trees = (
+            &self.offers_db,
+            &self.orders_db,
+            &self.open_orders_db,
+            &self.user_orders_db,
+            &self.expiring_orders_db,
+        );
+        trees
+            .transaction(|(offers, orders, open_orders, user_orders, expiring)| {
+                offers.insert_typed(&key, event.clone())?;
+                if let Some(order) = &order {
+                    let order_key = OrderKey::from(&key);
+                    let index = OpenOrderKey::new(&order.owner, &order_key);
+                    open_orders.insert_typed(&index, order_key.clone())?;
+                    let entry = UserOrderEntry {
+                        security: order.value.security,
+                        side: order.value.side,
+                    };
+                    user_orders
+                        .insert_typed(&UserOrderKey::new(&order.owner, &order_key), entry)?;
+                    if let Some(expire_time) = order.expire_time {
+                        let index = ExpiringOrderKey::new(expire_time, &order_key);
+                        expiring.insert_typed(&index, order_key.clone())?;
+                    }
+                    orders.insert_typed(&order_key, order.clone())?;
+                }
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => e,
+                TransactionError::Abort(()) => unreachable!(),
+            })?;
         self.offers_db.flush_async().await?;
         Ok(key)
     }
 
+    pub fn get_order(&self, key: &OfferEventKey) -> sled::Result<Option<Order>> {
+        self.orders_db.get_typed(&OrderKey::from(key))
+    }
+
+    /// Fills recorded for an order, oldest first.
+    pub fn order_fills(&self, key: &OfferEventKey) -> sled::Result<Vec<OrderFill>> {
+        self.order_matches_db
+            .scan_prefix(key.0)
+            .values()
+            .map(|v| {
+                let key = MatchKey::try_from(v?)?;
+                let value: MatchValue = self
+                    .matches_db
+                    .get_typed(&key)?
+                    .expect("Order match index without match");
+                Ok(OrderFill {
+                    match_id: key.into(),
+                    price: value.price,
+                    amount: value.amount,
+                    notional: value.notional,
+                    liquidity: value.liquidity,
+                    fee: value.fee,
+                })
+            })
+            .collect()
+    }
+
+    /// Orders of `user` accepted by `filter` with keys above `cursor`, oldest
+    /// first and at most `limit` of them.
+    pub fn user_orders(
+        &self,
+        user: &str,
+        filter: &OrderFilter,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> sled::Result<Vec<(OfferEventKey, Order)>> {
+        let prefix = user_prefix(user);
+        let first = OrderKey::from(cursor.map_or(0, |c| c.saturating_add(1)));
+        let start = UserOrderKey::new(user, &first);
+
+        let mut orders = Vec::new();
+        for entry in self.user_orders_db.range(start.as_ref()..) {
+            let (index, entry) = entry?;
+            if !index.starts_with(&prefix) || orders.len() == limit {
+                break;
+            }
+            if !filter.accepts_entry(&UserOrderEntry::try_from(entry)?) {
+                continue;
+            }
+            let mut key = [0; 8];
+            key.copy_from_slice(&index[prefix.len()..]);
+            let key = OfferEventKey(key);
+            if let Some(order) = self.get_order(&key)? {
+                if filter.accepts(&order) {
+                    orders.push((key, order));
+                }
+            }
+        }
+        Ok(orders)
+    }
+
+    /// Keys of the orders of `user` that can still execute, oldest first.
+    pub fn open_orders(&self, user: &str) -> sled::Result<Vec<OrderKey>> {
+        self.open_orders_db
+            .scan_prefix(user_prefix(user))
+            .values()
+            .map(|v| v.and_then(OrderKey::try_from))
+            .collect()
+    }
+
+    /// Orders due to expire at `now` that still rest, oldest expire time
+    /// first. Their entries stay in the index until the cancel is recorded,
+    /// the ones of orders that no longer rest are dropped.
+    pub fn due_orders(&self, now: u64) -> sled::Result<Vec<OfferEventKey>> {
+        let mut due = Vec::new();
+        for entry in self
+            .expiring_orders_db
+            .range(..ExpiringOrderKey::due_until(now).as_ref())
+        {
+            let (index, key) = entry?;
+            let key = OfferEventKey::from(OrderKey::try_from(key)?);
+            match self.get_order(&key)?.map(|order| order.state()) {
+                Some(OrderState::Open) | Some(OrderState::PartiallyFilled) => due.push(key),
+                _ => {
+                    self.expiring_orders_db.remove(index)?;
+                }
+            }
+        }
+        Ok(due)
+    }
+
     pub fn send_offer(&self, event: OfferEventKeyed) -> impl Future<Output = Matches> {
         let fut = WaitMatches::new();
         {
@@ -79,6 +245,20 @@ impl OfferHandler {
         fut
     }
 
+    /// Depth of the book of `security` as the engine has it between two
+    /// events.
+    pub async fn depth(&self, security: Security, depth: usize) -> BookDepth {
+        let (reply, receiver) = oneshot::channel();
+        self.sender_depth
+            .send(DepthRequest {
+                security,
+                depth,
+                reply,
+            })
+            .expect("Error on send depth request through channel.");
+        receiver.await.expect("Engine stopped")
+    }
+
     pub fn send_matches(&self, matches: Matches) {
         if let MatchResult::None = matches.result {
         } else {
@@ -87,6 +267,25 @@ impl OfferHandler {
     }
 }
 
+/// Indexes the orders placed before [`UserOrderKey`] existed, in a single
+/// batch so a crash can't leave the index half built.
+fn index_user_orders(orders_db: &sled::Tree, user_orders_db: &sled::Tree) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for entry in orders_db.iter() {
+        let (index, order) = entry?;
+        let mut key = [0; 8];
+        key.copy_from_slice(&index);
+        let key = OrderKey(key);
+        let order = Order::try_from(order)?;
+        let entry = UserOrderEntry {
+            security: order.value.security,
+            side: order.value.side,
+        };
+        batch.insert(UserOrderKey::new(&order.owner, &key).as_ref(), entry);
+    }
+    user_orders_db.apply_batch(batch)
+}
+
 #[derive(Clone)]
 struct WaitMatches {
     state: Arc<Mutex<WaitMatchesState>>,
@@ -143,3 +342,254 @@ impl Future for WaitMatches {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::Liquidity;
+    use crate::instruments::InstrumentHandler;
+    use crate::offers::{MassCancel, OfferValue, Side};
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    fn offer_handler(db: &sled::Db) -> OfferHandler {
+        OfferHandler::new(
+            db.clone(),
+            BalanceHandler::new(db.clone()),
+            MarketDataHandler::new(),
+            ExecutionHandler::new(),
+        )
+    }
+
+    /// Places an order of `owner` on the engine, its matches are sent to
+    /// the persistor.
+    fn place(handler: &OfferHandler, owner: &str, value: OfferValue) -> OfferEventKey {
+        let order = Order::new(owner, value.clone(), false, 0);
+        let event = OfferEvent::Add(value);
+        let key = block_on(handler.persist_offer(event.clone(), Some(order))).unwrap();
+        let event = OfferEventKeyed::from_event(key.clone(), event, Some(owner.to_string()));
+        let matches = block_on(handler.send_offer(event));
+        handler.send_matches(matches);
+        key
+    }
+
+    /// Waits for the persistor to record what `done` checks.
+    fn wait_for(done: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Matches not recorded");
+    }
+
+    #[test]
+    fn user_orders_backfill() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let orders_db = db.open_tree(<OrderKey as KeyOf>::PREFIX).unwrap();
+        let user_orders_db = db.open_tree(<UserOrderKey as KeyOf>::PREFIX).unwrap();
+        let value = OfferValue {
+            security: Security(1),
+            side: Side::Sell,
+            amount: 5,
+            price: Some(10),
+        };
+        let key = OrderKey::from(3);
+        orders_db
+            .insert_typed(&key, Order::new("alice", value, false, 0))
+            .unwrap();
+
+        index_user_orders(&orders_db, &user_orders_db).unwrap();
+        let entry = user_orders_db
+            .get_typed(&UserOrderKey::new("alice", &key))
+            .unwrap();
+        assert_eq!(
+            entry,
+            Some(UserOrderEntry {
+                security: Security(1),
+                side: Side::Sell,
+            })
+        );
+        assert_eq!(user_orders_db.len(), 1);
+    }
+
+    #[test]
+    fn user_order_pages() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = offer_handler(&db);
+        let persist = |owner: &str, security, side| {
+            let value = OfferValue {
+                security: Security(security),
+                side,
+                amount: 5,
+                price: Some(10),
+            };
+            let order = Order::new(owner, value.clone(), false, 0);
+            block_on(handler.persist_offer(OfferEvent::Add(value), Some(order))).unwrap()
+        };
+        let first = persist("alice", 1, Side::Buy);
+        let other = persist("bob", 1, Side::Buy);
+        let second = persist("alice", 2, Side::Sell);
+        let cancelled = persist("alice", 1, Side::Buy);
+        let third = persist("alice", 1, Side::Sell);
+        let mut order = handler.get_order(&cancelled).unwrap().unwrap();
+        order.cancel();
+        let key = OrderKey::from(&cancelled);
+        handler.orders_db.insert_typed(&key, order).unwrap();
+
+        let keys = |user, filter: &OrderFilter, cursor: Option<u64>, limit| {
+            let orders = handler.user_orders(user, filter, cursor, limit).unwrap();
+            orders.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        };
+        // Only the open orders of the user by default.
+        let open = OrderFilter::default();
+        let all = vec![first.clone(), second.clone(), third.clone()];
+        assert_eq!(keys("alice", &open, None, 10), all);
+        assert_eq!(keys("bob", &open, None, 10), vec![other]);
+
+        assert_eq!(keys("alice", &open, None, 2), all[..2].to_vec());
+        let cursor = Some(second.clone().into());
+        assert_eq!(keys("alice", &open, cursor, 2), vec![third.clone()]);
+        assert_eq!(keys("alice", &open, Some(third.clone().into()), 2), vec![]);
+
+        let btc = OrderFilter {
+            security: Some(Security(1)),
+            ..OrderFilter::default()
+        };
+        assert_eq!(keys("alice", &btc, None, 10), vec![first, third.clone()]);
+        let sells = OrderFilter {
+            side: Some(Side::Sell),
+            ..btc
+        };
+        assert_eq!(keys("alice", &sells, None, 10), vec![third]);
+        let cancelled_only = OrderFilter {
+            state: Some(OrderState::Cancelled),
+            ..OrderFilter::default()
+        };
+        assert_eq!(keys("alice", &cancelled_only, None, 10), vec![cancelled]);
+    }
+
+    #[test]
+    fn due_orders() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = offer_handler(&db);
+        let value = OfferValue {
+            security: Security(1),
+            side: Side::Buy,
+            amount: 5,
+            price: Some(10),
+        };
+        let place = |expire_time| {
+            let mut order = Order::new("alice", value.clone(), false, 0);
+            order.expire_time = expire_time;
+            let event = OfferEvent::Add(value.clone());
+            block_on(handler.persist_offer(event, Some(order))).unwrap()
+        };
+        let first = place(Some(100));
+        place(None);
+        let cancelled = place(Some(90));
+        let last = place(Some(200));
+        let mut order = handler.get_order(&cancelled).unwrap().unwrap();
+        order.cancel();
+        let key = OrderKey::from(&cancelled);
+        handler.orders_db.insert_typed(&key, order).unwrap();
+
+        assert_eq!(handler.due_orders(99).unwrap(), vec![]);
+        assert_eq!(handler.due_orders(100).unwrap(), vec![first.clone()]);
+        // Until the cancel is recorded the order stays due
+        assert_eq!(handler.due_orders(150).unwrap(), vec![first.clone()]);
+        assert_eq!(handler.expiring_orders_db.len(), 2);
+
+        let mut order = handler.get_order(&first).unwrap().unwrap();
+        order.expire();
+        let key = OrderKey::from(&first);
+        handler.orders_db.insert_typed(&key, order).unwrap();
+        assert_eq!(handler.due_orders(150).unwrap(), vec![]);
+        assert_eq!(handler.due_orders(300).unwrap(), vec![last]);
+        assert_eq!(handler.expiring_orders_db.len(), 1);
+    }
+
+    #[test]
+    fn recorded_expiry_leaves_index() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let instruments = InstrumentHandler::new(db.clone());
+        let security = instruments
+            .get_by_symbol("BTC/USD")
+            .unwrap()
+            .unwrap()
+            .security;
+        let handler = offer_handler(&db);
+        let value = OfferValue {
+            security,
+            side: Side::Buy,
+            amount: 5,
+            price: Some(10),
+        };
+        let mut order = Order::new("alice", value.clone(), false, 0);
+        order.expire_time = Some(100);
+        let event = OfferEvent::Add(value);
+        let key = block_on(handler.persist_offer(event.clone(), Some(order))).unwrap();
+        let event = OfferEventKeyed::from_event(key.clone(), event, Some("alice".to_string()));
+        handler.send_matches(block_on(handler.send_offer(event)));
+        assert_eq!(handler.due_orders(100).unwrap(), vec![key.clone()]);
+
+        let event = OfferEvent::MassCancel(MassCancel::Offers(vec![key.clone()]));
+        let cancel = block_on(handler.persist_offer(event.clone(), None)).unwrap();
+        let event = OfferEventKeyed::from_event(cancel, event, None);
+        handler.send_matches(block_on(handler.send_offer(event)));
+
+        let state = || handler.get_order(&key).unwrap().unwrap().state();
+        wait_for(|| state() == OrderState::Expired);
+        assert!(handler.expiring_orders_db.is_empty());
+    }
+
+    #[test]
+    fn fills_oldest_first() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let instruments = InstrumentHandler::new(db.clone());
+        let security = instruments
+            .get_by_symbol("BTC/USD")
+            .unwrap()
+            .unwrap()
+            .security;
+        let handler = offer_handler(&db);
+        let sell = |amount, price| OfferValue {
+            security,
+            side: Side::Sell,
+            amount,
+            price: Some(price),
+        };
+        let cheap = place(&handler, "alice", sell(4, 5));
+        let dear = place(&handler, "alice", sell(6, 6));
+        let buy = OfferValue {
+            side: Side::Buy,
+            ..sell(8, 6)
+        };
+        let taker = place(&handler, "bob", buy);
+
+        let order = |key| handler.get_order(key).unwrap().unwrap();
+        wait_for(|| order(&dear).remaining == 2);
+        let fills = handler.order_fills(&taker).unwrap();
+        let executed: Vec<_> = fills
+            .iter()
+            .map(|fill| (fill.price, fill.amount, fill.liquidity))
+            .collect();
+        assert_eq!(
+            executed,
+            vec![
+                (Some(5), 4, Liquidity::Taker),
+                (Some(6), 4, Liquidity::Taker)
+            ]
+        );
+        assert!(fills[0].match_id < fills[1].match_id);
+        let maker_fills = handler.order_fills(&dear).unwrap();
+        assert_eq!(maker_fills.len(), 1);
+        assert_eq!(maker_fills[0].liquidity, Liquidity::Maker);
+        assert_eq!(maker_fills[0].notional, Some(24));
+
+        assert_eq!(order(&cheap).state(), OrderState::Filled);
+        assert_eq!(order(&dear).state(), OrderState::PartiallyFilled);
+        assert_eq!(order(&taker).state(), OrderState::Filled);
+    }
+}
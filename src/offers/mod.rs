@@ -2,9 +2,13 @@ mod handler;
 mod model;
 
 use crate::{
+    api_keys::{self, Caller, Scope},
     auth,
-    utils::{bytes_body, json_body},
-    with_ctx, Ctx, IpQueryParam,
+    executions::ExecutionEvent,
+    market_data::{self, BookSnapshot},
+    user::Role,
+    utils::{json_body, now_in_secs},
+    with_ctx, Ctx,
 };
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -15,16 +19,21 @@ use warp::{
     Filter, Rejection, Reply,
 };
 pub use {
-    crate::engine::{MatchResult, Matches},
+    crate::engine::{BookDepth, MatchResult, Matches, PriceLevel},
     handler::OfferHandler,
     model::{
-        Offer, OfferEvent, OfferEventKey, OfferEventKeyed, OfferEventRequest, OfferValue, Security,
-        Side,
+        ExpiringOrderKey, MassCancel, Offer, OfferEvent, OfferEventKey, OfferEventKeyed,
+        OfferEventRequest, OfferValue, OfferValueRequest, OpenOrderKey, Order, OrderError,
+        OrderFill, OrderFilter, OrderKey, OrderOptions, OrderPage, OrderQuery, OrderReport,
+        OrderState, Security, Side, UserOrderEntry, UserOrderKey,
     },
 };
 
 pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     make_offer(ctx.clone())
+        .or(get_offer(ctx.clone()))
+        .or(list_offers(ctx.clone()))
+        .or(get_book(ctx.clone()))
         .or(inner_make_offer(ctx.clone()))
         .or(set_cookie(ctx.clone()))
         .or(num_errors(ctx))
@@ -33,25 +42,34 @@ pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection>
 fn make_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("offers")
         .and(warp::post())
-        .and(warp::cookie(auth::JWT_COOKIE_NAME))
-        .and(warp::query::<IpQueryParam>())
+        .and(api_keys::with_caller(ctx.clone(), Scope::Trade, 6))
         .and(warp::header::<String>(header::CONTENT_TYPE.as_str()))
-        .and(bytes_body(6))
+        .and(warp::query::<OrderOptions>())
         .and(with_ctx(ctx))
         .and_then(
-            async move |cookie: String,
-                        ip: IpQueryParam,
-                        content_type: String,
+            async move |caller: Result<Caller, auth::AuthorizeError>,
                         event: bytes::Bytes,
+                        content_type: String,
+                        options: OrderOptions,
                         ctx: Ctx|
                         -> Result<Response<_>, Infallible> {
-                if let Err(_e) = ctx.auth_manager.authorize(ip.ip.as_str(), cookie.as_str()) {
-                    return Ok(Response::builder()
-                        .status(StatusCode::UNAUTHORIZED)
-                        .header(header::SET_COOKIE, auth::DELETE_JWT_COOKIE)
-                        .body("".into())
-                        .unwrap());
-                }
+                let caller = match caller {
+                    Ok(caller) => caller,
+                    Err(auth::AuthorizeError::Forbidden) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::FORBIDDEN)
+                            .body("".into())
+                            .unwrap())
+                    }
+                    Err(_e) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::UNAUTHORIZED)
+                            .header(header::SET_COOKIE, auth::DELETE_JWT_COOKIE)
+                            .body("".into())
+                            .unwrap())
+                    }
+                };
+                let user_id = caller.user_id();
                 let event_raw = match ctx.deserializer.deserialize(&content_type, &event.to_vec()) {
                     Ok(v) => v,
                     Err(e) => match ctx.deserializer.deserialize(&content_type, &event.to_vec()) {
@@ -66,83 +84,404 @@ fn make_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection>
                     },
                 };
 
-                if ctx.test_auth {
-                    let event = OfferEvent::from(event_raw.clone());
-                    let key = ctx
-                        .offer_handler
-                        .persist_offer(event.clone())
-                        .await
-                        .unwrap();
-
-                    let event = OfferEventKeyed::from_event(key, event);
-                    let ans3 = ctx.offer_handler.send_offer(event).await;
-                    ctx.offer_handler.send_matches(ans3);
-                    Ok(Response::builder()
-                        .status(StatusCode::OK)
-                        .body(serde_json::ser::to_string(&event_raw).unwrap())
-                        .unwrap())
-                } else {
-                    let event = OfferEvent::from(event_raw);
-                    let key = ctx
-                        .offer_handler
-                        .persist_offer(event.clone())
-                        .await
-                        .unwrap();
-
-                    let event = OfferEventKeyed::from_event(key, event);
-                    let ans3 = ctx.offer_handler.send_offer(event.clone());
-
-                    let client = reqwest::Client::new();
-                    let (r1, r2) = futures::future::join(
-                        client
-                            .post("http://127.0.0.1:3031/offers_inner")
-                            .json(&event.clone())
-                            .send(),
-                        client
-                            .post("http://127.0.0.1:3032/offers_inner")
-                            .json(&event)
-                            .send(),
-                    )
-                    .await;
-
-                    let ((ans1, ans2), ans3) = futures::future::join(
-                        futures::future::join(
-                            r1.unwrap().json::<Matches>(),
-                            r2.unwrap().json::<Matches>(),
-                        ),
-                        ans3,
-                    )
-                    .await;
-
-                    let (ans1, ans2) = (ans1.unwrap(), ans2.unwrap());
-
-                    if ans1 != ans2 || ans2 != ans3 || ans3 != ans1 {
-                        println!("ERROR in offer processing");
-                        if ans1 != ans3 {
-                            ctx.num_errors
-                                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let event = match ctx.instrument_handler.resolve(event_raw.clone()) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        if let OfferEventRequest::Add(request) = &event_raw {
+                            reject(&ctx, user_id, request.clone(), &e);
                         }
-                        if ans2 != ans3 {
-                            ctx.num_errors
-                                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        return Ok(json_response(StatusCode::BAD_REQUEST, &e));
+                    }
+                };
+
+                let order = match &event {
+                    OfferEvent::Add(value) => {
+                        if let Some(expire_time) = options.expire_time {
+                            if value.price.is_none() || expire_time <= now_in_secs() {
+                                let e = OrderError::InvalidExpireTime;
+                                reject(&ctx, user_id, offer_request(&ctx, value), &e);
+                                return Ok(json_response(StatusCode::BAD_REQUEST, &e));
+                            }
                         }
-                        Ok(Response::builder()
-                            .status(StatusCode::OK)
-                            .body("error".into())
-                            .unwrap())
-                    } else {
-                        println!("Good match");
-                        ctx.offer_handler.send_matches(ans3);
-                        Ok(Response::builder()
-                            .status(StatusCode::OK)
-                            .body("ok".into())
-                            .unwrap())
+                        let open_orders = ctx.offer_handler.open_orders(user_id).unwrap();
+                        let opposite = match value.price {
+                            Some(_) => Vec::new(),
+                            None => {
+                                let depth = ctx.offer_handler.depth(value.security, MAX_DEPTH).await;
+                                match value.side {
+                                    Side::Buy => depth.asks,
+                                    Side::Sell => depth.bids,
+                                }
+                            }
+                        };
+                        let risk = ctx
+                            .risk_handler
+                            .check(user_id, value, open_orders.len(), &opposite);
+                        if let Err(e) = risk {
+                            reject(&ctx, user_id, offer_request(&ctx, value), &e);
+                            return Ok(json_response(e.status(), &e));
+                        }
+                        let fee_reserve_bps =
+                            ctx.fee_handler.get(value.security).unwrap().max_rate();
+                        let mut order =
+                            Order::new(user_id, value.clone(), ctx.funds_check, fee_reserve_bps);
+                        order.session = caller.cancelling_session().map(str::to_string);
+                        order.expire_time = options.expire_time;
+                        if ctx.funds_check {
+                            let instrument =
+                                ctx.instrument_handler.get(value.security).unwrap().unwrap();
+                            if let Err(e) = ctx.balance_handler.hold(&instrument, &order) {
+                                reject(&ctx, user_id, offer_request(&ctx, value), &e);
+                                return Ok(json_response(StatusCode::BAD_REQUEST, &e));
+                            }
+                        }
+                        Some(order)
                     }
+                    OfferEvent::Delete(k) => match ctx.offer_handler.get_order(k).unwrap() {
+                        None => {
+                            return Ok(json_response(
+                                StatusCode::NOT_FOUND,
+                                &OrderError::UnknownOrder,
+                            ))
+                        }
+                        Some(order) if order.owner != user_id => {
+                            return Ok(json_response(
+                                StatusCode::FORBIDDEN,
+                                &OrderError::NotOrderOwner,
+                            ))
+                        }
+                        Some(_) => None,
+                    },
+                    OfferEvent::MassCancel(_) => unreachable!("Clients can't send mass cancels"),
+                };
+
+                match submit(&ctx, event, order).await {
+                    Some(_) if ctx.test_auth => Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .body(serde_json::ser::to_string(&event_raw).unwrap())
+                        .unwrap()),
+                    Some(_) => Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .body("ok".into())
+                        .unwrap()),
+                    None => Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .body("error".into())
+                        .unwrap()),
                 }
             },
         )
 }
 
+/// Cancels the open orders placed in a session that asked for it, called
+/// once the session is over.
+pub(crate) async fn end_session(ctx: &Ctx, claims: &auth::Claims) {
+    if claims.cancel_on_disconnect {
+        cancel_session(ctx, &claims.user_id, &claims.session).await;
+    }
+}
+
+/// Cancels the orders that reached their expire time, checked every second.
+pub async fn expire_orders(ctx: Ctx) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let keys = ctx.offer_handler.due_orders(now_in_secs()).unwrap();
+        if !keys.is_empty() {
+            submit(&ctx, OfferEvent::MassCancel(MassCancel::Offers(keys)), None).await;
+        }
+    }
+}
+
+/// Cancels the open orders of `user_id` placed in `session`.
+pub(crate) async fn cancel_session(ctx: &Ctx, user_id: &str, session: &str) {
+    cancel_session_orders(ctx, user_id, |s| s == session).await;
+}
+
+/// Cancels the open orders of `user_id` placed in any session that asked
+/// for it, called once every session of the user is over.
+pub(crate) async fn cancel_sessions(ctx: &Ctx, user_id: &str) {
+    cancel_session_orders(ctx, user_id, |_| true).await;
+}
+
+/// Cancels the open orders of `user_id` placed in the sessions `ended`
+/// accepts, among the ones that asked for it.
+async fn cancel_session_orders<F: Fn(&str) -> bool>(ctx: &Ctx, user_id: &str, ended: F) {
+    let keys: Vec<OfferEventKey> = ctx
+        .offer_handler
+        .open_orders(user_id)
+        .unwrap()
+        .into_iter()
+        .map(OfferEventKey::from)
+        .filter(|key| {
+            let order = ctx.offer_handler.get_order(key).unwrap();
+            order.map_or(false, |o| o.session.as_deref().map_or(false, &ended))
+        })
+        .collect();
+    if !keys.is_empty() {
+        submit(ctx, OfferEvent::MassCancel(MassCancel::Offers(keys)), None).await;
+    }
+}
+
+/// Persists an event and runs it through the engine, and through the
+/// replicas unless `test_auth` is set. Returns `None` when the replicas
+/// disagree on the result.
+pub(crate) async fn submit(ctx: &Ctx, event: OfferEvent, order: Option<Order>) -> Option<Matches> {
+    let owner = order.as_ref().map(|o| o.owner.clone());
+    let accepted = order.as_ref().map(|o| (o.owner.clone(), o.value.clone()));
+    let key = ctx
+        .offer_handler
+        .persist_offer(event.clone(), order)
+        .await
+        .unwrap();
+    // Before the engine sees it, so it comes ahead of the fills.
+    if let Some((owner, value)) = accepted {
+        let event = ExecutionEvent::Accepted {
+            order: key.clone().into(),
+            request: offer_request(ctx, &value),
+        };
+        ctx.executions.publish(&owner, event);
+    }
+    let event = OfferEventKeyed::from_event(key, event, owner);
+
+    if ctx.test_auth {
+        let ans3 = ctx.offer_handler.send_offer(event.clone()).await;
+        market_data::publish_books(ctx, touched_books(&event, &ans3)).await;
+        ctx.offer_handler.send_matches(ans3.clone());
+        return Some(ans3);
+    }
+
+    let ans3 = ctx.offer_handler.send_offer(event.clone());
+
+    let client = reqwest::Client::new();
+    let (r1, r2) = futures::future::join(
+        client
+            .post("http://127.0.0.1:3031/offers_inner")
+            .json(&event.clone())
+            .send(),
+        client
+            .post("http://127.0.0.1:3032/offers_inner")
+            .json(&event)
+            .send(),
+    )
+    .await;
+
+    let ((ans1, ans2), ans3) = futures::future::join(
+        futures::future::join(r1.unwrap().json::<Matches>(), r2.unwrap().json::<Matches>()),
+        ans3,
+    )
+    .await;
+
+    let (ans1, ans2) = (ans1.unwrap(), ans2.unwrap());
+    market_data::publish_books(ctx, touched_books(&event, &ans3)).await;
+
+    if ans1 != ans2 || ans2 != ans3 || ans3 != ans1 {
+        println!("ERROR in offer processing");
+        if ans1 != ans3 {
+            ctx.num_errors
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        if ans2 != ans3 {
+            ctx.num_errors
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        None
+    } else {
+        println!("Good match");
+        ctx.offer_handler.send_matches(ans3.clone());
+        Some(ans3)
+    }
+}
+
+/// Securities whose book may have changed with `event`.
+fn touched_books(event: &OfferEventKeyed, matches: &Matches) -> Vec<Security> {
+    let mut securities: Vec<Security> = match (event, &matches.result) {
+        (OfferEventKeyed::Add(offer, _), _) => vec![offer.value.security],
+        (_, MatchResult::Cancelled(offer)) => vec![offer.value.security],
+        (_, MatchResult::MassCancelled(offers)) => {
+            offers.iter().map(|o| o.value.security).collect()
+        }
+        _ => Vec::new(),
+    };
+    securities.sort_by_key(|s| s.0);
+    securities.dedup();
+    securities
+}
+
+fn get_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("offers" / u64)
+        .and(warp::get())
+        .and(api_keys::with_bodyless_caller(ctx.clone(), Scope::Read))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |id: u64,
+                        caller: Result<Caller, auth::AuthorizeError>,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let caller = match caller {
+                    Ok(caller) => caller,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                let key = OfferEventKey::from(id);
+                Ok(match ctx.offer_handler.get_order(&key).unwrap() {
+                    None => Box::new(json_response(
+                        StatusCode::NOT_FOUND,
+                        &OrderError::UnknownOrder,
+                    )),
+                    Some(order) if order.owner != caller.user_id() => Box::new(json_response(
+                        StatusCode::FORBIDDEN,
+                        &OrderError::NotOrderOwner,
+                    )),
+                    Some(order) => Box::new(json_response(
+                        StatusCode::OK,
+                        &order_report(&ctx, key, order),
+                    )),
+                })
+            },
+        )
+}
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+fn list_offers(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("offers")
+        .and(warp::get())
+        .and(api_keys::with_bodyless_caller(ctx.clone(), Scope::Read))
+        .and(warp::query::<OrderQuery>())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |caller: Result<Caller, auth::AuthorizeError>,
+                        query: OrderQuery,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let caller = match caller {
+                    Ok(caller) => caller,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                let security = match &query.security {
+                    None => None,
+                    Some(symbol) => match ctx.instrument_handler.get_by_symbol(symbol).unwrap() {
+                        Some(instrument) => Some(instrument.security),
+                        None => {
+                            return Ok(Box::new(json_response(
+                                StatusCode::BAD_REQUEST,
+                                &OrderError::UnknownSecurity,
+                            )))
+                        }
+                    },
+                };
+                let filter = OrderFilter {
+                    security,
+                    side: query.side,
+                    state: query.state,
+                };
+                let limit = query
+                    .limit
+                    .unwrap_or(DEFAULT_PAGE_SIZE)
+                    .max(1)
+                    .min(MAX_PAGE_SIZE);
+
+                let orders = ctx
+                    .offer_handler
+                    .user_orders(caller.user_id(), &filter, query.cursor, limit)
+                    .unwrap();
+                let next_cursor = match orders.last() {
+                    Some((key, _)) if orders.len() == limit => Some(key.clone().into()),
+                    _ => None,
+                };
+                let page = OrderPage {
+                    orders: orders
+                        .into_iter()
+                        .map(|(key, order)| order_report(&ctx, key, order))
+                        .collect(),
+                    next_cursor,
+                };
+                Ok(Box::new(json_response(StatusCode::OK, &page)))
+            },
+        )
+}
+
+const DEFAULT_DEPTH: usize = 10;
+/// The streams follow no further, see [`market_data::STREAM_DEPTH`].
+const MAX_DEPTH: usize = market_data::STREAM_DEPTH;
+
+#[derive(Deserialize)]
+struct DepthQuery {
+    depth: Option<usize>,
+}
+
+fn get_book(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("book" / String)
+        .and(warp::get())
+        .and(warp::query::<DepthQuery>())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |symbol: String,
+                        query: DepthQuery,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let instrument = match ctx.instrument_handler.get_by_symbol(&symbol).unwrap() {
+                    Some(instrument) => instrument,
+                    None => {
+                        return Ok(Box::new(json_response(
+                            StatusCode::NOT_FOUND,
+                            &OrderError::UnknownSecurity,
+                        )))
+                    }
+                };
+                let depth = query.depth.unwrap_or(DEFAULT_DEPTH).max(1).min(MAX_DEPTH);
+                // Read first, the stream may only move past the depth.
+                let seq = ctx.market_data.seq(instrument.security);
+                let depth = ctx.offer_handler.depth(instrument.security, depth).await;
+                let book = BookSnapshot { seq, depth };
+                Ok(Box::new(json_response(StatusCode::OK, &book)))
+            },
+        )
+}
+
+/// Reports an offer that didn't pass the pre-trade checks to its owner.
+fn reject<E: Serialize>(ctx: &Ctx, user: &str, request: OfferValueRequest, reason: &E) {
+    let event = ExecutionEvent::Rejected {
+        request,
+        reason: serde_json::to_value(reason).unwrap(),
+    };
+    ctx.executions.publish(user, event);
+}
+
+fn offer_request(ctx: &Ctx, value: &OfferValue) -> OfferValueRequest {
+    let instrument = ctx
+        .instrument_handler
+        .get(value.security)
+        .unwrap()
+        .expect("Order of unknown instrument");
+    OfferValueRequest {
+        security: instrument.symbol,
+        side: value.side,
+        amount: value.amount,
+        price: value.price,
+    }
+}
+
+fn order_report(ctx: &Ctx, key: OfferEventKey, order: Order) -> OrderReport {
+    OrderReport {
+        fills: ctx.offer_handler.order_fills(&key).unwrap(),
+        id: key.into(),
+        request: offer_request(ctx, &order.value),
+        state: order.state(),
+        remaining: order.remaining,
+        owner: order.owner,
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::ser::to_string(body).unwrap())
+        .unwrap()
+}
+
 fn inner_make_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("offers_inner")
         .and(warp::post())
@@ -150,7 +489,9 @@ fn inner_make_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejec
         .and(with_ctx(ctx))
         .and_then(
             async move |event: OfferEventKeyed, ctx: Ctx| -> Result<_, Infallible> {
-                let mut m = ctx.offer_handler.send_offer(event).await;
+                let mut m = ctx.offer_handler.send_offer(event.clone()).await;
+                // The streams of the replica follow its own engine.
+                market_data::publish_books(&ctx, touched_books(&event, &m)).await;
                 let mut r = rand::thread_rng();
                 if r.gen_bool(0.01) {
                     ctx.num_errors
@@ -159,6 +500,8 @@ fn inner_make_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejec
                         MatchResult::Complete => MatchResult::None,
                         MatchResult::None => MatchResult::Complete,
                         MatchResult::Partial { .. } => MatchResult::None,
+                        MatchResult::Cancelled(_) => MatchResult::None,
+                        MatchResult::MassCancelled(_) => MatchResult::None,
                     };
                 }
                 Ok(warp::reply::json(&m))
@@ -169,12 +512,15 @@ fn inner_make_offer(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejec
 fn num_errors(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path("num_errors")
         .and(warp::get())
+        .and(auth::with_role(ctx.clone(), Role::Operator))
         .and(with_ctx(ctx))
-        .and_then(async move |ctx: Ctx| -> Result<Response<_>, Infallible> {
-            Ok(Response::builder()
-                .body(ctx.num_errors.load(atomic::Ordering::SeqCst).to_string())
-                .unwrap())
-        })
+        .and_then(
+            async move |_: auth::Claims, ctx: Ctx| -> Result<Response<_>, Infallible> {
+                Ok(Response::builder()
+                    .body(ctx.num_errors.load(atomic::Ordering::SeqCst).to_string())
+                    .unwrap())
+            },
+        )
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1,6 +1,7 @@
 use crate::{
     bincode_des, bincode_ser, derive_key_of, derive_monotonic_key, derive_simple_struct,
-    typed_tree::KeyOf,
+    fees::{fee, Liquidity},
+    typed_tree::{user_key, KeyOf},
 };
 use serde::{Deserialize, Serialize};
 
@@ -8,31 +9,46 @@ use serde::{Deserialize, Serialize};
 pub struct OfferEventKey(pub [u8; 8]);
 derive_monotonic_key!(OfferEventKey);
 
+/// Offer event as sent by clients, before its security symbol is resolved
+/// against the instrument registry.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum OfferEventRequest {
     Delete(u64),
-    Add(OfferValue),
+    Add(OfferValueRequest),
 }
 
-impl From<OfferEventRequest> for OfferEvent {
-    fn from(o: OfferEventRequest) -> Self {
-        match o {
-            OfferEventRequest::Add(v) => OfferEvent::Add(v),
-            OfferEventRequest::Delete(v) => OfferEvent::Delete(OfferEventKey(v.to_be_bytes())),
-        }
-    }
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct OfferValueRequest {
+    pub security: String,
+    pub side: Side,
+    pub amount: u64,
+    pub price: Option<u64>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum OfferEvent {
     Delete(OfferEventKey),
     Add(OfferValue),
+    MassCancel(MassCancel),
+}
+
+/// Resting offers removed at once by a risk officer.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum MassCancel {
+    /// The given offers, the open orders of a user when they were issued.
+    Offers(Vec<OfferEventKey>),
+    /// Every offer in the book of a security.
+    Security(Security),
+    /// Every resting offer of a user, as the engine has them.
+    User(String),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum OfferEventKeyed {
     Delete(OfferEventKey, OfferEventKey),
-    Add(Offer),
+    /// Offer along with its owner.
+    Add(Offer, String),
+    MassCancel(OfferEventKey, MassCancel),
 }
 
 impl PartialEq for OfferEventKeyed {
@@ -42,16 +58,19 @@ impl PartialEq for OfferEventKeyed {
 }
 
 impl OfferEventKeyed {
-    pub fn from_event(key: OfferEventKey, event: OfferEvent) -> Self {
+    /// `owner` is the one of the order an added offer opens.
+    pub fn from_event(key: OfferEventKey, event: OfferEvent, owner: Option<String>) -> Self {
         match event {
-            OfferEvent::Add(value) => Self::Add(Offer { key, value }),
+            OfferEvent::Add(value) => Self::Add(Offer { key, value }, owner.unwrap_or_default()),
             OfferEvent::Delete(k) => Self::Delete(key, k),
+            OfferEvent::MassCancel(scope) => Self::MassCancel(key, scope),
         }
     }
     pub fn key(&self) -> &OfferEventKey {
         match self {
-            OfferEventKeyed::Add(o) => &o.key,
+            OfferEventKeyed::Add(o, _) => &o.key,
             OfferEventKeyed::Delete(k, _) => k,
+            OfferEventKeyed::MassCancel(k, _) => k,
         }
     }
 }
@@ -85,12 +104,10 @@ pub struct OfferValue {
     pub price: Option<u64>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Security {
-    BTC,
-    USD,
-    COP,
-}
+/// Registry id of an instrument.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Security(pub u64);
+derive_simple_struct!(Security, u64);
 
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Side {
@@ -107,4 +124,268 @@ impl Side {
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub struct OrderKey(pub [u8; 8]);
+derive_monotonic_key!(OrderKey);
+
+impl From<&OfferEventKey> for OrderKey {
+    fn from(key: &OfferEventKey) -> Self {
+        OrderKey(key.0)
+    }
+}
+
+impl From<OrderKey> for OfferEventKey {
+    fn from(key: OrderKey) -> Self {
+        OfferEventKey(key.0)
+    }
+}
+
+/// Index of the orders of a user that can still execute: user id, a zero
+/// byte and the order key.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct OpenOrderKey(Vec<u8>);
+
+impl OpenOrderKey {
+    pub fn new(user: &str, key: &OrderKey) -> Self {
+        OpenOrderKey(user_key(user, &key.0))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for OpenOrderKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Index of every order of a user, same layout as [`OpenOrderKey`] but
+/// entries are never removed.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserOrderKey(Vec<u8>);
+
+impl UserOrderKey {
+    pub fn new(user: &str, key: &OrderKey) -> Self {
+        UserOrderKey(user_key(user, &key.0))
+    }
+}
+
+/// What listings filter on, so they only load the orders they return.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserOrderEntry {
+    pub security: Security,
+    pub side: Side,
+}
+
+impl std::convert::AsRef<[u8]> for UserOrderKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// State of an added offer, stored under the key of its event.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Order {
+    pub owner: String,
+    pub value: OfferValue,
+    pub remaining: u64,
+    /// Whether the order holds its funds in the balances ledger.
+    pub funded: bool,
+    /// Fee rate, in basis points, reserved by buys on top of their notional.
+    pub fee_reserve_bps: u32,
+    pub cancelled: bool,
+    /// Session that placed the order when it must be cancelled as the
+    /// session ends.
+    pub session: Option<String>,
+    /// Seconds since the epoch the order is cancelled at.
+    pub expire_time: Option<u64>,
+    /// Whether the order was cancelled because it reached `expire_time`.
+    pub expired: bool,
+}
+
+impl Order {
+    pub fn new(owner: &str, value: OfferValue, funded: bool, fee_reserve_bps: u32) -> Self {
+        Order {
+            owner: owner.to_string(),
+            remaining: value.amount,
+            value,
+            funded,
+            fee_reserve_bps,
+            cancelled: false,
+            session: None,
+            expire_time: None,
+            expired: false,
+        }
+    }
+
+    /// Funds reserved for the remaining amount: base units when selling,
+    /// quote units, fees included, when buying.
+    pub fn hold(&self) -> u64 {
+        match self.value.side {
+            Side::Sell => self.remaining,
+            Side::Buy => {
+                let notional = self.value.price.unwrap_or(0) * self.remaining;
+                notional + fee(notional, self.fee_reserve_bps)
+            }
+        }
+    }
+
+    /// Substracts an executed amount and returns the hold it frees.
+    pub fn fill(&mut self, amount: u64) -> u64 {
+        let hold = self.hold();
+        self.remaining = self.remaining.saturating_sub(amount);
+        hold - self.hold()
+    }
+
+    pub fn state(&self) -> OrderState {
+        if self.expired {
+            OrderState::Expired
+        } else if self.cancelled {
+            OrderState::Cancelled
+        } else if self.remaining == 0 {
+            OrderState::Filled
+        } else if self.remaining < self.value.amount {
+            OrderState::PartiallyFilled
+        } else {
+            OrderState::Open
+        }
+    }
+
+    /// Drops the remaining amount and returns the hold it frees.
+    pub fn cancel(&mut self) -> u64 {
+        let hold = self.hold();
+        self.remaining = 0;
+        self.cancelled = true;
+        hold
+    }
+
+    /// Whether the order is due to expire at `now`.
+    pub fn is_due(&self, now: u64) -> bool {
+        self.expire_time.map_or(false, |time| time <= now)
+    }
+
+    /// Cancels the order as expired and returns the hold it frees.
+    pub fn expire(&mut self) -> u64 {
+        self.expired = true;
+        self.cancel()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderState {
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Expired,
+}
+
+/// Execution of an order as recorded by the match persistor.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderFill {
+    pub match_id: u64,
+    pub price: Option<u64>,
+    pub amount: u64,
+    pub notional: Option<u64>,
+    pub liquidity: Liquidity,
+    pub fee: u64,
+}
+
+/// An order as reported to its owner.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderReport {
+    pub id: u64,
+    pub owner: String,
+    pub request: OfferValueRequest,
+    pub state: OrderState,
+    pub remaining: u64,
+    pub fills: Vec<OrderFill>,
+}
+
+/// Filters of a listing of the caller's orders. Without `state` only the
+/// orders that can still execute are returned.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OrderQuery {
+    /// Instrument symbol.
+    pub security: Option<String>,
+    pub side: Option<Side>,
+    pub state: Option<OrderState>,
+    /// Id of the last order of the previous page.
+    pub cursor: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// [`OrderQuery`] with its symbol resolved.
+#[derive(Debug, Clone, Default)]
+pub struct OrderFilter {
+    pub security: Option<Security>,
+    pub side: Option<Side>,
+    pub state: Option<OrderState>,
+}
+
+impl OrderFilter {
+    pub fn accepts_entry(&self, entry: &UserOrderEntry) -> bool {
+        self.security.map_or(true, |s| s == entry.security)
+            && self.side.map_or(true, |s| s == entry.side)
+    }
+
+    pub fn accepts(&self, order: &Order) -> bool {
+        match (self.state, order.state()) {
+            (Some(state), order_state) => state == order_state,
+            (None, OrderState::Open) | (None, OrderState::PartiallyFilled) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderPage {
+    pub orders: Vec<OrderReport>,
+    /// Cursor of the next page, `None` on the last one.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum OrderError {
+    UnknownOrder,
+    NotOrderOwner,
+    UnknownSecurity,
+    /// Expire times must be in the future and only limit orders rest.
+    InvalidExpireTime,
+}
+
+/// Options of a new order, given as query parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OrderOptions {
+    /// Seconds since the epoch the order is cancelled at if it still rests.
+    pub expire_time: Option<u64>,
+}
+
+/// Index of the orders with an expire time: the time, big endian so they
+/// sort by it, and the order key.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct ExpiringOrderKey(Vec<u8>);
+
+impl ExpiringOrderKey {
+    pub fn new(expire_time: u64, key: &OrderKey) -> Self {
+        let mut bytes = expire_time.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&key.0);
+        ExpiringOrderKey(bytes)
+    }
+
+    /// Key before which every order due at `now` is found.
+    pub fn due_until(now: u64) -> Self {
+        Self::new(now + 1, &OrderKey([0; 8]))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for ExpiringOrderKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
 derive_key_of!(OfferEventKey, OfferEvent, "OfferEvent", 2);
+derive_key_of!(OrderKey, Order, "Order", 7);
+derive_key_of!(OpenOrderKey, OrderKey, "OpenOrder", 13);
+derive_key_of!(UserOrderKey, UserOrderEntry, "UserOrder", 17);
+derive_key_of!(ExpiringOrderKey, OrderKey, "ExpiringOrder", 30);
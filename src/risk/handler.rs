@@ -0,0 +1,268 @@
+use crate::engine::PriceLevel;
+use crate::offers::OfferValue;
+use crate::prelude::*;
+use crate::risk::{KillSwitch, KillSwitchKey, RiskError, RiskLimits, RiskLimitsKey};
+use crate::utils::now_in_secs;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+pub struct RiskHandler {
+    limits_db: sled::Tree,
+    kill_switches_db: sled::Tree,
+    /// Second and number of orders accepted in it, per user.
+    rates: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl RiskHandler {
+    pub fn new(db: sled::Db) -> Self {
+        Self {
+            limits_db: db.open_tree(<RiskLimitsKey as KeyOf>::PREFIX).unwrap(),
+            kill_switches_db: db.open_tree(<KillSwitchKey as KeyOf>::PREFIX).unwrap(),
+            rates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, user: &str) -> sled::Result<RiskLimits> {
+        self.limits_db
+            .get_typed(&RiskLimitsKey(user))
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn set(&self, user: &str, limits: RiskLimits) -> sled::Result<RiskLimits> {
+        self.limits_db
+            .insert_typed(&RiskLimitsKey(user), limits.clone())?;
+        Ok(limits)
+    }
+
+    pub fn kill_switch(&self, user: &str) -> sled::Result<Option<KillSwitch>> {
+        self.kill_switches_db.get_typed(&KillSwitchKey(user))
+    }
+
+    /// Blocks the new offers of `user`. The flag is flushed before returning
+    /// so it survives a crash during the mass cancel that follows.
+    pub fn engage_kill_switch(&self, user: &str, engaged_by: &str) -> sled::Result<KillSwitch> {
+        let kill_switch = KillSwitch {
+            engaged_by: engaged_by.to_string(),
+            since: now_in_secs(),
+        };
+        self.kill_switches_db
+            .insert_typed(&KillSwitchKey(user), kill_switch.clone())?;
+        self.kill_switches_db.flush()?;
+        Ok(kill_switch)
+    }
+
+    pub fn release_kill_switch(&self, user: &str) -> sled::Result<Option<KillSwitch>> {
+        self.kill_switches_db
+            .remove(KillSwitchKey(user))?
+            .map(KillSwitch::try_from)
+            .transpose()
+    }
+
+    /// Checks a new offer of `user`, who has `open_orders` orders resting,
+    /// against their limits. Market offers are valued at the `opposite`
+    /// levels of the book they would take. An offer that passes is counted
+    /// towards the rate limit under the same lock it is checked with, so
+    /// concurrent offers can't all pass, and still counts if it is refused
+    /// afterwards. `open_orders` is read before the offer is stored, so
+    /// concurrent offers may exceed the open orders limit.
+    pub fn check(
+        &self,
+        user: &str,
+        value: &OfferValue,
+        open_orders: usize,
+        opposite: &[PriceLevel],
+    ) -> Result<(), RiskError> {
+        self.check_at(user, value, open_orders, opposite, now_in_secs())
+    }
+
+    fn check_at(
+        &self,
+        user: &str,
+        value: &OfferValue,
+        open_orders: usize,
+        opposite: &[PriceLevel],
+        now: u64,
+    ) -> Result<(), RiskError> {
+        if self.kill_switch(user).unwrap().is_some() {
+            return Err(RiskError::RiskKillSwitchEngaged);
+        }
+        let limits = self.get(user).unwrap();
+        if let Some(max_order_amount) = limits.max_order_amount {
+            if value.amount > max_order_amount {
+                return Err(RiskError::RiskOrderAmountExceeded { max_order_amount });
+            }
+        }
+        if let Some(max_notional) = limits.max_notional {
+            // A market offer the book can't fill has no bound, it is refused.
+            let price = value.price.or_else(|| worst_price(opposite, value.amount));
+            if price.map_or(true, |p| p.saturating_mul(value.amount) > max_notional) {
+                return Err(RiskError::RiskNotionalExceeded { max_notional });
+            }
+        }
+        if let Some(max_open_orders) = limits.max_open_orders {
+            if open_orders as u64 >= max_open_orders {
+                return Err(RiskError::RiskOpenOrdersExceeded { max_open_orders });
+            }
+        }
+        let mut rates = self.rates.lock().unwrap();
+        let (second, count) = rates.entry(user.to_string()).or_insert((now, 0));
+        if *second != now {
+            *second = now;
+            *count = 0;
+        }
+        if let Some(max_orders_per_second) = limits.max_orders_per_second {
+            if *count >= max_orders_per_second {
+                return Err(RiskError::RiskRateExceeded {
+                    max_orders_per_second,
+                });
+            }
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// Price of the last of the `levels` an offer of `amount` would reach, the
+/// worst it could execute at. `None` when they don't hold enough.
+fn worst_price(levels: &[PriceLevel], amount: u64) -> Option<u64> {
+    let mut left = amount;
+    for level in levels {
+        if level.amount >= left {
+            return Some(level.price);
+        }
+        left -= level.amount;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offers::{Security, Side};
+
+    #[test]
+    fn limits() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = RiskHandler::new(db);
+        let value = OfferValue {
+            security: Security(1),
+            side: Side::Buy,
+            amount: 10,
+            price: Some(5),
+        };
+        assert_eq!(handler.check("user", &value, 100, &[]), Ok(()));
+
+        let limits = RiskLimits {
+            max_order_amount: Some(10),
+            max_notional: Some(40),
+            max_open_orders: Some(2),
+            max_orders_per_second: Some(1),
+        };
+        handler.set("user", limits).unwrap();
+        let value = OfferValue {
+            price: Some(4),
+            ..value
+        };
+        assert_eq!(
+            handler.check(
+                "user",
+                &OfferValue {
+                    amount: 11,
+                    ..value.clone()
+                },
+                0,
+                &[]
+            ),
+            Err(RiskError::RiskOrderAmountExceeded {
+                max_order_amount: 10
+            })
+        );
+        assert_eq!(
+            handler.check(
+                "user",
+                &OfferValue {
+                    price: Some(5),
+                    ..value.clone()
+                },
+                0,
+                &[]
+            ),
+            Err(RiskError::RiskNotionalExceeded { max_notional: 40 })
+        );
+        assert_eq!(
+            handler.check("user", &value, 2, &[]),
+            Err(RiskError::RiskOpenOrdersExceeded { max_open_orders: 2 })
+        );
+        // Rejected offers don't count towards the rate.
+        assert_eq!(handler.check_at("user", &value, 1, &[], 60), Ok(()));
+        assert_eq!(
+            handler.check_at("user", &value, 1, &[], 60),
+            Err(RiskError::RiskRateExceeded {
+                max_orders_per_second: 1
+            })
+        );
+        assert_eq!(handler.check_at("user", &value, 1, &[], 61), Ok(()));
+
+        let market = OfferValue {
+            price: None,
+            ..value.clone()
+        };
+        let level = |price, amount| PriceLevel {
+            price,
+            amount,
+            orders: 1,
+        };
+        assert_eq!(
+            handler.check_at("user", &market, 1, &[level(3, 6), level(4, 6)], 62),
+            Ok(())
+        );
+        assert_eq!(
+            handler.check_at("user", &market, 1, &[level(3, 6), level(5, 6)], 62),
+            Err(RiskError::RiskNotionalExceeded { max_notional: 40 })
+        );
+        assert_eq!(
+            handler.check_at("user", &market, 1, &[level(3, 6)], 62),
+            Err(RiskError::RiskNotionalExceeded { max_notional: 40 })
+        );
+
+        handler.engage_kill_switch("user", "officer").unwrap();
+        assert_eq!(
+            handler.check_at("user", &value, 0, &[], 62),
+            Err(RiskError::RiskKillSwitchEngaged)
+        );
+        assert!(handler.release_kill_switch("user").unwrap().is_some());
+        assert_eq!(handler.check_at("user", &value, 0, &[], 62), Ok(()));
+    }
+
+    #[test]
+    fn concurrent_rate() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = std::sync::Arc::new(RiskHandler::new(db));
+        let limits = RiskLimits {
+            max_orders_per_second: Some(1),
+            ..RiskLimits::default()
+        };
+        handler.set("user", limits).unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    let value = OfferValue {
+                        security: Security(1),
+                        side: Side::Buy,
+                        amount: 1,
+                        price: Some(1),
+                    };
+                    handler.check_at("user", &value, 0, &[], 60).is_ok()
+                })
+            })
+            .collect();
+        let passed = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|passed| *passed)
+            .count();
+        assert_eq!(passed, 1);
+    }
+}
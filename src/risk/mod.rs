@@ -0,0 +1,203 @@
+mod handler;
+mod model;
+
+use crate::{
+    auth,
+    offers::{self, MassCancel, MatchResult, OfferEvent, Security},
+    utils::json_body,
+    with_client_ip, with_ctx, ClientIp, Ctx,
+};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub use handler::RiskHandler;
+pub use model::{KillSwitch, KillSwitchKey, KillSwitchReply, RiskError, RiskLimits, RiskLimitsKey};
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    get_limits(ctx.clone())
+        .or(set_limits(ctx.clone()))
+        .or(engage_user_kill_switch(ctx.clone()))
+        .or(release_user_kill_switch(ctx.clone()))
+        .or(engage_security_kill_switch(ctx.clone()))
+        .or(release_security_kill_switch(ctx))
+}
+
+impl RiskError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RiskError::RiskRateExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+fn get_limits(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("risk" / "limits" / String)
+        .and(warp::get())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                Ok(Box::new(reply::json(&ctx.risk_handler.get(&user).unwrap())))
+            },
+        )
+}
+
+fn set_limits(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("risk" / "limits" / String)
+        .and(warp::put())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(json_body::<RiskLimits>(4))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String,
+                        cookie: String,
+                        ip: ClientIp,
+                        limits: RiskLimits,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                let limits = ctx.risk_handler.set(&user, limits).unwrap();
+                Ok(Box::new(reply::json(&limits)))
+            },
+        )
+}
+
+/// Runs a mass cancel through the sequenced path and replies with the keys
+/// of the cancelled orders.
+async fn mass_cancel(ctx: &Ctx, scope: MassCancel) -> Box<dyn Reply> {
+    let matches = match offers::submit(ctx, OfferEvent::MassCancel(scope), None).await {
+        Some(matches) => matches,
+        None => {
+            return Box::new(auth::reply_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Replicas disagree on the mass cancel",
+            ))
+        }
+    };
+    let cancelled = match matches.result {
+        MatchResult::MassCancelled(offers) => offers.into_iter().map(|o| o.key.into()).collect(),
+        _ => Vec::new(),
+    };
+    Box::new(reply::json(&KillSwitchReply { cancelled }))
+}
+
+fn engage_user_kill_switch(
+    ctx: Ctx,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("risk" / "kill-switch" / "users" / String)
+        .and(warp::post())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let claims = match ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    Ok(claims) => claims,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                ctx.risk_handler
+                    .engage_kill_switch(&user, &claims.user_id)
+                    .unwrap();
+                // The engine picks the offers, so orders placed while the
+                // switch was engaged go as well.
+                Ok(mass_cancel(&ctx, MassCancel::User(user)).await)
+            },
+        )
+}
+
+fn release_user_kill_switch(
+    ctx: Ctx,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("risk" / "kill-switch" / "users" / String)
+        .and(warp::delete())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |user: String,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                Ok(match ctx.risk_handler.release_kill_switch(&user).unwrap() {
+                    Some(kill_switch) => Box::new(reply::json(&kill_switch)),
+                    None => Box::new(auth::reply_error(
+                        StatusCode::NOT_FOUND,
+                        "Kill switch not engaged",
+                    )),
+                })
+            },
+        )
+}
+
+/// Halts the instrument, which rejects its new offers, and empties its
+/// book. Only releasing the switch resumes the instrument.
+fn engage_security_kill_switch(
+    ctx: Ctx,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("risk" / "kill-switch" / "instruments" / u64)
+        .and(warp::post())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |id: u64,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                let security = Security(id);
+                if let Err(e) = ctx.instrument_handler.halt(security) {
+                    return Ok(Box::new(reply::with_status(reply::json(&e), e.status())));
+                }
+                Ok(mass_cancel(&ctx, MassCancel::Security(security)).await)
+            },
+        )
+}
+
+fn release_security_kill_switch(
+    ctx: Ctx,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("risk" / "kill-switch" / "instruments" / u64)
+        .and(warp::delete())
+        .and(warp::cookie(auth::JWT_COOKIE_NAME))
+        .and(with_client_ip(ctx.clone()))
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |id: u64,
+                        cookie: String,
+                        ip: ClientIp,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                if let Err(e) = ctx.auth_manager.authorize_admin(&ip.ip, &cookie) {
+                    return Ok(Box::new(auth::reply_authorize_error(e)));
+                }
+                Ok(match ctx.instrument_handler.release_halt(Security(id)) {
+                    Ok(instrument) => Box::new(reply::json(&instrument)),
+                    Err(e) => Box::new(reply::with_status(reply::json(&e), e.status())),
+                })
+            },
+        )
+}
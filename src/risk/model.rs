@@ -0,0 +1,60 @@
+use crate::{bincode_des, bincode_ser, derive_key_of, typed_tree::KeyOf};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct RiskLimitsKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for RiskLimitsKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Pre-trade limits of a user, `None` leaves the dimension unlimited.
+/// Amounts are in base units and notionals in quote units of the offer's
+/// instrument.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct RiskLimits {
+    pub max_order_amount: Option<u64>,
+    pub max_notional: Option<u64>,
+    pub max_open_orders: Option<u64>,
+    pub max_orders_per_second: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct KillSwitchKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for KillSwitchKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Engaged kill switch of a user, their new offers are rejected until it is
+/// released.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct KillSwitch {
+    pub engaged_by: String,
+    pub since: u64,
+}
+
+/// Orders cancelled by engaging a kill switch.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct KillSwitchReply {
+    pub cancelled: Vec<u64>,
+}
+
+/// Rejections of the risk check. They are reported with their own status
+/// codes so clients can tell them apart from validation errors.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum RiskError {
+    RiskOrderAmountExceeded { max_order_amount: u64 },
+    RiskNotionalExceeded { max_notional: u64 },
+    RiskOpenOrdersExceeded { max_open_orders: u64 },
+    RiskRateExceeded { max_orders_per_second: u32 },
+    RiskKillSwitchEngaged,
+}
+
+derive_key_of!(RiskLimitsKey<'_>, RiskLimits, "RiskLimits", 14);
+derive_key_of!(KillSwitchKey<'_>, KillSwitch, "KillSwitch", 15);
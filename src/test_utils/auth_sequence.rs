@@ -1,7 +1,7 @@
 use super::*;
 use crate::{
-  offers::{OfferEventRequest, OfferValue, Security, Side},
-  user::User,
+  offers::{OfferEventRequest, OfferValueRequest, Side},
+  user::{Role, User},
 };
 
 pub async fn start_petitions_auth() {
@@ -10,14 +10,16 @@ pub async fn start_petitions_auth() {
   let user1 = User {
       id: "user1".to_string(),
       password: "user1".to_string(),
+      role: Role::Trader,
   };
   let ip2 = "2";
   let user2 = User {
       id: "user2".to_string(),
       password: "user2".to_string(),
+      role: Role::Trader,
   };
-  let offer_event = OfferEventRequest::Add(OfferValue {
-      security: Security::BTC,
+  let offer_event = OfferEventRequest::Add(OfferValueRequest {
+      security: "BTC/USD".to_string(),
       side: Side::Buy,
       amount: 8,
       price: Some(5),
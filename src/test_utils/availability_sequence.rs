@@ -1,7 +1,7 @@
 use super::*;
 use crate::{
-    offers::{OfferEventRequest, OfferValue, Security, Side},
-    user::User,
+    offers::{OfferEventRequest, OfferValueRequest, Side},
+    user::{Role, User},
 };
 
 async fn start_petitions_disp(error_on: u32) {
@@ -11,9 +11,10 @@ async fn start_petitions_disp(error_on: u32) {
     let user1 = User {
         id: "user1".to_string(),
         password: "user1".to_string(),
+        role: Role::Trader,
     };
-    let mut offer_event = OfferValue {
-        security: Security::BTC,
+    let mut offer_event = OfferValueRequest {
+        security: "BTC/USD".to_string(),
         side: Side::Sell,
         amount: 8,
         price: Some(5),
@@ -91,7 +92,7 @@ async fn start_petitions_disp(error_on: u32) {
 async fn create_offer(
     client: &reqwest::Client,
     ip: &str,
-    offer_event: &OfferValue,
+    offer_event: &OfferValueRequest,
 ) -> reqwest::Response {
     let r = client
         .post(&format!("{}{}", OFFERS_ROUTE, ip))
@@ -5,7 +5,7 @@ pub mod requester;
 pub use auth_sequence::start_petitions_auth;
 
 use crate::auth::PathBody;
-use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use futures::future::{BoxFuture, FutureExt};
 use requester::SerType;
 use reqwest::Response;
 
@@ -13,10 +13,14 @@ const LOGIN_ROUTE: &str = "http://127.0.0.1:3030/login?ip=";
 const SIGNUP_ROUTE: &str = "http://127.0.0.1:3030/signup?ip=";
 const LOGOUT_ROUTE: &str = "http://127.0.0.1:3030/logout";
 const OFFERS_ROUTE: &str = "http://127.0.0.1:3030/offers?ip=";
-const CONFIG_PATH_ROUTE: &str = "http://127.0.0.1:3030/config_path";
+const CONFIG_PATH_ROUTE: &str = "http://127.0.0.1:3030/config_path?ip=";
 const CONFIG_ROUTE: &str = "http://127.0.0.1:3030/config";
 const SET_COOKIE_ROUTE: &str = "http://127.0.0.1:3030/set_cookie?cookie=";
 
+/// User the test configurations must list as operator, the sequences read
+/// the counters of the servers with it.
+pub const OPERATOR: &str = "operator";
+
 pub async fn auth_test(n_processes: u32, n_requests: u32) {
     std::thread::sleep(std::time::Duration::from_millis(3000));
     let futs: Vec<BoxFuture<'_, ()>> = (0..n_processes)
@@ -28,10 +32,10 @@ pub async fn auth_test(n_processes: u32, n_requests: u32) {
 
     futures::future::join_all(futs).await;
     println!("Ended testing");
-    let resp = reqwest::get("http://127.0.0.1:3030/num_users")
-        .await
-        .unwrap();
-    println!("number users: {}", resp.text().await.unwrap());
+    let mut operator = requester::Requester::new(None);
+    operator.login_operator().await;
+    let num_users = operator.get("http://127.0.0.1:3030/num_users").await;
+    println!("number users: {}", num_users);
 }
 
 pub async fn flexibility_test(n_processes: u32, n_requests: u32) {
@@ -39,6 +43,7 @@ pub async fn flexibility_test(n_processes: u32, n_requests: u32) {
     println!("Test Started //////////////");
 
     let mut main_requester = requester::Requester::new(None);
+    main_requester.login_operator().await;
 
     println!("Json /////////////////////////////////");
     main_requester.config_path("./assets/json.wasm").await;
@@ -132,15 +137,18 @@ pub async fn availability_test(n_processes: u32, n_requests: u32) {
 
     futures::future::join_all(futs).await;
     println!("Ended testing");
+    // The servers share the signing key, the token of one is valid on all.
+    let mut operator = requester::Requester::new(None);
+    operator.login_operator().await;
     let (resp1, resp2, resp3) = futures::future::join3(
-        reqwest::get("http://127.0.0.1:3030/num_errors").and_then(|f| f.text()),
-        reqwest::get("http://127.0.0.1:3031/num_errors").and_then(|f| f.text()),
-        reqwest::get("http://127.0.0.1:3032/num_errors").and_then(|f| f.text()),
+        operator.get("http://127.0.0.1:3030/num_errors"),
+        operator.get("http://127.0.0.1:3031/num_errors"),
+        operator.get("http://127.0.0.1:3032/num_errors"),
     )
     .await;
-    let resp1: u32 = resp1.unwrap().parse().unwrap();
-    let resp2: u32 = resp2.unwrap().parse().unwrap();
-    let resp3: u32 = resp3.unwrap().parse().unwrap();
+    let resp1: u32 = resp1.parse().unwrap();
+    let resp2: u32 = resp2.parse().unwrap();
+    let resp3: u32 = resp3.parse().unwrap();
 
     assert_eq!(resp3 + resp2, resp1);
     println!("number generated errors: {}", resp1);
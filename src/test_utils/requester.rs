@@ -1,8 +1,8 @@
 use super::*;
 use crate::{
     auth::PathBody,
-    offers::{OfferEventRequest, OfferValue, Security, Side},
-    user::User,
+    offers::{OfferEventRequest, OfferValueRequest, Side},
+    user::{Role, User},
 };
 use futures::future::{BoxFuture, FutureExt};
 use rand::prelude::*;
@@ -116,7 +116,7 @@ impl Requester {
     pub async fn config_path(&mut self, path: &str) -> u64 {
         let resp: Response = self
             .client
-            .post(CONFIG_PATH_ROUTE)
+            .post(&format!("{}{}", CONFIG_PATH_ROUTE, self.valid_ip))
             .json(&PathBody {
                 path: path.to_string(),
             })
@@ -129,6 +129,35 @@ impl Requester {
         millis.parse::<u64>().unwrap()
     }
 
+    /// Logs in as [`OPERATOR`], signing it up on the first call.
+    pub async fn login_operator(&mut self) {
+        let user = User {
+            id: OPERATOR.to_string(),
+            password: OPERATOR.to_string(),
+            role: Role::Trader,
+        };
+        self.client
+            .post(&format!("{}{}", SIGNUP_ROUTE, self.valid_ip))
+            .json(&user)
+            .send()
+            .await
+            .unwrap();
+        self.user_cred = Some(user);
+        self.login().await;
+    }
+
+    /// Body of a GET to a route that needs a session.
+    pub async fn get(&self, route: &str) -> String {
+        let response = self
+            .client
+            .get(&format!("{}?ip={}", route, self.valid_ip))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.text().await.unwrap()
+    }
+
     async fn signup(&mut self) -> () {
         let user = random_user();
         let response = self
@@ -350,6 +379,7 @@ fn random_user() -> User {
     User {
         id: id.to_string(),
         password: password.to_string(),
+        role: Role::Trader,
     }
 }
 
@@ -357,8 +387,8 @@ fn random_offer() -> OfferEventRequest {
     let mut rng = rand::thread_rng();
     let (is_buy, with_price): (bool, bool) = rng.gen();
 
-    OfferEventRequest::Add(OfferValue {
-        security: Security::BTC,
+    OfferEventRequest::Add(OfferValueRequest {
+        security: "BTC/USD".to_string(),
         side: if is_buy { Side::Buy } else { Side::Sell },
         amount: rng.gen_range(50u64, 100),
         price: if with_price {
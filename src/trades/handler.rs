@@ -0,0 +1,222 @@
+use crate::matches::{MatchKey, MatchValue};
+use crate::offers::Security;
+use crate::prelude::*;
+use crate::trades::{PrintEntry, SecurityMatchKey, TradeFilter, UserMatchEntry, UserMatchKey};
+use std::convert::TryFrom;
+
+pub struct TradeHandler {
+    matches_db: sled::Tree,
+    security_matches_db: sled::Tree,
+    user_matches_db: sled::Tree,
+}
+
+impl TradeHandler {
+    pub fn new(db: sled::Db) -> Self {
+        Self {
+            matches_db: db.open_tree(<MatchKey as KeyOf>::PREFIX).unwrap(),
+            security_matches_db: db.open_tree(<SecurityMatchKey as KeyOf>::PREFIX).unwrap(),
+            user_matches_db: db.open_tree(<UserMatchKey as KeyOf>::PREFIX).unwrap(),
+        }
+    }
+
+    /// Prints of `security`, one per fill, with keys above `cursor`.
+    pub fn security_trades(
+        &self,
+        security: Security,
+        filter: &TradeFilter,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> sled::Result<Vec<(MatchKey, MatchValue)>> {
+        let prefix = SecurityMatchKey::prefix(security);
+        self.scan(
+            &self.security_matches_db,
+            &prefix,
+            filter,
+            cursor,
+            limit,
+            |entry| Ok((security, PrintEntry::try_from(entry)?.time)),
+        )
+    }
+
+    /// Fills of the orders of `user` with keys above `cursor`.
+    pub fn user_trades(
+        &self,
+        user: &str,
+        filter: &TradeFilter,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> sled::Result<Vec<(MatchKey, MatchValue)>> {
+        let prefix = user_prefix(user);
+        self.scan(
+            &self.user_matches_db,
+            &prefix,
+            filter,
+            cursor,
+            limit,
+            |entry| {
+                let entry = UserMatchEntry::try_from(entry)?;
+                Ok((entry.security, entry.time))
+            },
+        )
+    }
+
+    /// Walks the entries of `index` under `prefix`, which end with a match
+    /// key, from the one after `cursor` until `limit` matches are accepted
+    /// or they get past the end of the filter.
+    fn scan<F>(
+        &self,
+        index: &sled::Tree,
+        prefix: &[u8],
+        filter: &TradeFilter,
+        cursor: Option<u64>,
+        limit: usize,
+        entry: F,
+    ) -> sled::Result<Vec<(MatchKey, MatchValue)>>
+    where
+        F: Fn(sled::IVec) -> sled::Result<(Security, u64)>,
+    {
+        let mut start = prefix.to_vec();
+        start.extend_from_slice(&cursor.map_or(0, |c| c.saturating_add(1)).to_be_bytes());
+
+        let mut trades = Vec::new();
+        for item in index.range(start..) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) || trades.len() == limit {
+                break;
+            }
+            let (security, time) = entry(value)?;
+            if filter.is_past(time) {
+                break;
+            }
+            if !filter.accepts(security, time) {
+                continue;
+            }
+            let mut match_key = [0; 8];
+            match_key.copy_from_slice(&key[prefix.len()..]);
+            let match_key = MatchKey(match_key);
+            let value: MatchValue = self
+                .matches_db
+                .get_typed(&match_key)?
+                .expect("Trade index without match");
+            trades.push((match_key, value));
+        }
+        Ok(trades)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::Liquidity;
+    use crate::offers::Side;
+
+    fn record(handler: &TradeHandler, id: u64, user: &str, security: Security, time: u64) {
+        let key = MatchKey::from(id);
+        let value = MatchValue {
+            reference: id.to_be_bytes(),
+            security,
+            side: Side::Buy,
+            price: Some(10),
+            amount: 1,
+            notional: Some(10),
+            liquidity: Liquidity::Taker,
+            fee: 0,
+            time,
+        };
+        handler.matches_db.insert_typed(&key, value).unwrap();
+        handler
+            .security_matches_db
+            .insert_typed(&SecurityMatchKey::new(security, &key), PrintEntry { time })
+            .unwrap();
+        handler
+            .user_matches_db
+            .insert_typed(
+                &UserMatchKey::new(user, &key),
+                UserMatchEntry { security, time },
+            )
+            .unwrap();
+    }
+
+    fn ids(trades: Vec<(MatchKey, MatchValue)>) -> Vec<u64> {
+        trades.into_iter().map(|(key, _)| key.into()).collect()
+    }
+
+    fn trade_handler() -> TradeHandler {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let handler = TradeHandler::new(db);
+        record(&handler, 1, "alice", Security(1), 100);
+        record(&handler, 2, "bob", Security(1), 110);
+        record(&handler, 3, "alice", Security(2), 120);
+        record(&handler, 4, "alice", Security(1), 130);
+        record(&handler, 5, "alice", Security(1), 140);
+        handler
+    }
+
+    #[test]
+    fn security_trade_pages() {
+        let handler = trade_handler();
+        let all = TradeFilter::default();
+        let trades = |cursor, limit| {
+            ids(handler
+                .security_trades(Security(1), &all, cursor, limit)
+                .unwrap())
+        };
+
+        assert_eq!(trades(None, 10), vec![1, 2, 4, 5]);
+        assert_eq!(trades(None, 2), vec![1, 2]);
+        assert_eq!(trades(Some(2), 2), vec![4, 5]);
+        assert_eq!(trades(Some(5), 2), vec![]);
+        assert_eq!(trades(Some(u64::MAX), 2), vec![]);
+        assert_eq!(
+            ids(handler
+                .security_trades(Security(3), &all, None, 10)
+                .unwrap()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn user_trade_pages() {
+        let handler = trade_handler();
+        let all = TradeFilter::default();
+        let trades = |user, filter: &TradeFilter, cursor, limit| {
+            ids(handler.user_trades(user, filter, cursor, limit).unwrap())
+        };
+
+        assert_eq!(trades("alice", &all, None, 10), vec![1, 3, 4, 5]);
+        assert_eq!(trades("bob", &all, None, 10), vec![2]);
+        assert_eq!(trades("alice", &all, None, 3), vec![1, 3, 4]);
+        assert_eq!(trades("alice", &all, Some(4), 3), vec![5]);
+
+        let btc = TradeFilter {
+            security: Some(Security(1)),
+            ..TradeFilter::default()
+        };
+        assert_eq!(trades("alice", &btc, None, 2), vec![1, 4]);
+        assert_eq!(trades("alice", &btc, Some(4), 2), vec![5]);
+    }
+
+    #[test]
+    fn time_bounds() {
+        let handler = trade_handler();
+        let between = |from, to| TradeFilter {
+            from,
+            to,
+            ..TradeFilter::default()
+        };
+        let trades = |filter: &TradeFilter, limit| {
+            ids(handler.user_trades("alice", filter, None, limit).unwrap())
+        };
+
+        assert_eq!(trades(&between(Some(120), None), 10), vec![3, 4, 5]);
+        assert_eq!(trades(&between(Some(121), None), 10), vec![4, 5]);
+        assert_eq!(trades(&between(None, Some(130)), 10), vec![1, 3, 4]);
+        assert_eq!(trades(&between(Some(100), Some(120)), 10), vec![1, 3]);
+        assert_eq!(trades(&between(Some(150), None), 10), vec![]);
+        assert_eq!(trades(&between(Some(120), Some(130)), 1), vec![3]);
+
+        // Scanning stops at the first entry past `to`, whatever follows it.
+        record(&handler, 6, "alice", Security(1), 125);
+        assert_eq!(trades(&between(None, Some(125)), 10), vec![1, 3]);
+    }
+}
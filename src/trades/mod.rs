@@ -0,0 +1,146 @@
+mod handler;
+mod model;
+
+use crate::{
+    api_keys::{self, Caller, Scope},
+    auth::{self, AuthorizeError},
+    matches::{MatchKey, MatchValue},
+    offers::Security,
+    with_ctx, Ctx,
+};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub use handler::TradeHandler;
+pub use model::{
+    PrintEntry, PublicTrade, SecurityMatchKey, TradeError, TradeFilter, TradePage, TradeQuery,
+    UserMatchEntry, UserMatchKey, UserTrade,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+pub fn routes(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    public_trades(ctx.clone()).or(user_trades(ctx))
+}
+
+fn public_trades(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("trades")
+        .and(warp::get())
+        .and(warp::query::<TradeQuery>())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |query: TradeQuery, ctx: Ctx| -> Result<Box<dyn Reply>, Infallible> {
+                let filter = match trade_filter(&ctx, &query) {
+                    Ok(filter) => filter,
+                    Err(e) => return Ok(Box::new(e)),
+                };
+                let security = match filter.security {
+                    Some(security) => security,
+                    None => {
+                        return Ok(Box::new(reply::with_status(
+                            reply::json(&TradeError::SecurityRequired),
+                            StatusCode::BAD_REQUEST,
+                        )))
+                    }
+                };
+                let limit = page_size(&query);
+                let trades = ctx
+                    .trade_handler
+                    .security_trades(security, &filter, query.cursor, limit)
+                    .unwrap();
+                let symbol = symbol(&ctx, security);
+                Ok(Box::new(reply::json(&page(trades, limit, |key, value| {
+                    PublicTrade::new(key, value, symbol.clone())
+                }))))
+            },
+        )
+}
+
+fn user_trades(ctx: Ctx) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("me" / "trades")
+        .and(warp::get())
+        .and(api_keys::with_bodyless_caller(ctx.clone(), Scope::Read))
+        .and(warp::query::<TradeQuery>())
+        .and(with_ctx(ctx))
+        .and_then(
+            async move |caller: Result<Caller, AuthorizeError>,
+                        query: TradeQuery,
+                        ctx: Ctx|
+                        -> Result<Box<dyn Reply>, Infallible> {
+                let caller = match caller {
+                    Ok(caller) => caller,
+                    Err(e) => return Ok(Box::new(auth::reply_authorize_error(e))),
+                };
+                let filter = match trade_filter(&ctx, &query) {
+                    Ok(filter) => filter,
+                    Err(e) => return Ok(Box::new(e)),
+                };
+                let limit = page_size(&query);
+                let trades = ctx
+                    .trade_handler
+                    .user_trades(caller.user_id(), &filter, query.cursor, limit)
+                    .unwrap();
+                Ok(Box::new(reply::json(&page(trades, limit, |key, value| {
+                    let symbol = symbol(&ctx, value.security);
+                    UserTrade::new(key, value, symbol)
+                }))))
+            },
+        )
+}
+
+fn trade_filter(
+    ctx: &Ctx,
+    query: &TradeQuery,
+) -> Result<TradeFilter, reply::WithStatus<reply::Json>> {
+    let security = match &query.security {
+        None => None,
+        Some(symbol) => match ctx.instrument_handler.get_by_symbol(symbol).unwrap() {
+            Some(instrument) => Some(instrument.security),
+            None => {
+                return Err(reply::with_status(
+                    reply::json(&TradeError::UnknownSecurity),
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+        },
+    };
+    Ok(TradeFilter {
+        security,
+        from: query.from,
+        to: query.to,
+    })
+}
+
+fn page_size(query: &TradeQuery) -> usize {
+    query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE)
+}
+
+fn page<T, F>(trades: Vec<(MatchKey, MatchValue)>, limit: usize, f: F) -> TradePage<T>
+where
+    F: Fn(MatchKey, MatchValue) -> T,
+{
+    let next_cursor = match trades.last() {
+        Some((key, _)) if trades.len() == limit => Some(key.clone().into()),
+        _ => None,
+    };
+    TradePage {
+        trades: trades
+            .into_iter()
+            .map(|(key, value)| f(key, value))
+            .collect(),
+        next_cursor,
+    }
+}
+
+fn symbol(ctx: &Ctx, security: Security) -> String {
+    ctx.instrument_handler
+        .get(security)
+        .unwrap()
+        .expect("Trade of unknown instrument")
+        .symbol
+}
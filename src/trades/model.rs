@@ -0,0 +1,163 @@
+use crate::{
+    bincode_des, bincode_ser, derive_key_of,
+    fees::Liquidity,
+    matches::{MatchKey, MatchValue},
+    offers::{Security, Side},
+    typed_tree::{user_key, KeyOf},
+};
+use serde::{Deserialize, Serialize};
+
+/// Index of the prints of an instrument: the security followed by the match
+/// key of the taker side of each fill.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct SecurityMatchKey([u8; 16]);
+
+impl SecurityMatchKey {
+    pub fn new(security: Security, key: &MatchKey) -> Self {
+        let mut index = [0; 16];
+        index[..8].copy_from_slice(&security.0.to_be_bytes());
+        index[8..].copy_from_slice(&key.0);
+        SecurityMatchKey(index)
+    }
+
+    pub fn prefix(security: Security) -> [u8; 8] {
+        security.0.to_be_bytes()
+    }
+}
+
+impl std::convert::AsRef<[u8]> for SecurityMatchKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct PrintEntry {
+    pub time: u64,
+}
+
+/// Index of the fills of a user: [`user_key`] of each match key.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserMatchKey(Vec<u8>);
+
+impl UserMatchKey {
+    pub fn new(user: &str, key: &MatchKey) -> Self {
+        UserMatchKey(user_key(user, &key.0))
+    }
+}
+
+impl std::convert::AsRef<[u8]> for UserMatchKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// What listings filter on, so they only load the matches they return.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct UserMatchEntry {
+    pub security: Security,
+    pub time: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TradeQuery {
+    /// Instrument symbol.
+    pub security: Option<String>,
+    /// Seconds since the epoch, both inclusive.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// Id of the last trade of the previous page.
+    pub cursor: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// [`TradeQuery`] with its symbol resolved.
+#[derive(Debug, Clone, Default)]
+pub struct TradeFilter {
+    pub security: Option<Security>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl TradeFilter {
+    pub fn accepts(&self, security: Security, time: u64) -> bool {
+        self.security.map_or(true, |s| s == security) && self.from.map_or(true, |from| time >= from)
+    }
+
+    /// Matches are recorded in time order, so nothing after `time` passes.
+    pub fn is_past(&self, time: u64) -> bool {
+        self.to.map_or(false, |to| time > to)
+    }
+}
+
+/// Execution as seen by everyone, `side` is the side of the taker.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PublicTrade {
+    pub id: u64,
+    pub security: String,
+    pub side: Side,
+    pub price: Option<u64>,
+    pub amount: u64,
+    pub time: u64,
+}
+
+impl PublicTrade {
+    pub fn new(key: MatchKey, value: MatchValue, symbol: String) -> Self {
+        PublicTrade {
+            id: key.into(),
+            security: symbol,
+            side: value.side,
+            price: value.price,
+            amount: value.amount,
+            time: value.time,
+        }
+    }
+}
+
+/// Fill of one of the caller's orders. Only their own order id is shown.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserTrade {
+    pub id: u64,
+    pub order: u64,
+    pub security: String,
+    pub side: Side,
+    pub price: Option<u64>,
+    pub amount: u64,
+    pub notional: Option<u64>,
+    pub liquidity: Liquidity,
+    pub fee: u64,
+    pub time: u64,
+}
+
+impl UserTrade {
+    pub fn new(key: MatchKey, value: MatchValue, symbol: String) -> Self {
+        UserTrade {
+            id: key.into(),
+            order: u64::from_be_bytes(value.reference),
+            security: symbol,
+            side: value.side,
+            price: value.price,
+            amount: value.amount,
+            notional: value.notional,
+            liquidity: value.liquidity,
+            fee: value.fee,
+            time: value.time,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TradePage<T> {
+    pub trades: Vec<T>,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error")]
+pub enum TradeError {
+    SecurityRequired,
+    UnknownSecurity,
+}
+
+derive_key_of!(SecurityMatchKey, PrintEntry, "SecurityMatch", 18);
+derive_key_of!(UserMatchKey, UserMatchEntry, "UserMatch", 19);
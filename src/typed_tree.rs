@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use sled::IVec;
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
 use std::convert::TryFrom;
 use std::sync::atomic::{AtomicU64, Ordering};
 
@@ -114,6 +114,70 @@ where
     }
 }
 
+/// Typed access to the trees of a sled transaction, generic over the error
+/// the transaction aborts with.
+pub trait TransactionalTypedTree<K>
+where
+    K: AsRef<[u8]> + KeyOf,
+    <ValOf<K> as TryFrom<IVec>>::Error: Into<sled::Error>,
+    ValOf<K>: TryFrom<IVec>,
+    IVec: From<ValOf<K>>,
+{
+    fn get_typed<E>(&self, key: &K) -> ConflictableTransactionResult<Option<ValOf<K>>, E>;
+    fn insert_typed<E>(
+        &self,
+        key: &K,
+        value: ValOf<K>,
+    ) -> ConflictableTransactionResult<Option<IVec>, E>;
+}
+
+impl<K> TransactionalTypedTree<K> for TransactionalTree
+where
+    <ValOf<K> as TryFrom<IVec>>::Error: Into<sled::Error>,
+    ValOf<K>: TryFrom<IVec>,
+    K: AsRef<[u8]> + KeyOf,
+    IVec: From<ValOf<K>>,
+{
+    fn get_typed<E>(&self, key: &K) -> ConflictableTransactionResult<Option<ValOf<K>>, E> {
+        match self.get(key.as_ref())? {
+            Some(v) => Ok(Some(
+                <ValOf<K>>::try_from(v).map_err(|e| -> sled::Error { e.into() })?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn insert_typed<E>(
+        &self,
+        key: &K,
+        value: ValOf<K>,
+    ) -> ConflictableTransactionResult<Option<IVec>, E> {
+        Ok(self.insert(key.as_ref(), value)?)
+    }
+}
+
+/// Whether `id` can name a user. Ids have no zero byte, which ends them in
+/// the keys of [`user_prefix`].
+pub fn valid_user_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains('\0')
+}
+
+/// Start of the keys of `user` in the trees indexed by user: their id and a
+/// zero byte, so no valid id is the prefix of another's.
+pub fn user_prefix(user: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(user.len() + 9);
+    prefix.extend_from_slice(user.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+/// Key of `suffix` among the keys of `user`, see [`user_prefix`].
+pub fn user_key(user: &str, suffix: &[u8]) -> Vec<u8> {
+    let mut key = user_prefix(user);
+    key.extend_from_slice(suffix);
+    key
+}
+
 #[macro_export]
 macro_rules! derive_key_of {
     ($key: ty, $value: ty, $NAME: literal, $PREFIX: literal) => {
@@ -143,7 +207,7 @@ macro_rules! derive_key_of {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::user::User;
+    use crate::user::{Role, User};
 
     #[test]
     fn typed() {
@@ -152,6 +216,7 @@ mod tests {
         let val = User {
             id: "dw".to_string(),
             password: "pass".to_string(),
+            role: Role::Trader,
         };
         let key = val.key();
         t.insert_typed(&key, val.clone()).unwrap();
@@ -1,4 +1,5 @@
 use crate::{bincode_des, bincode_ser, derive_key_of, typed_tree::KeyOf};
+use ring::digest;
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
@@ -8,6 +9,22 @@ pub struct UserKey<'a>(pub &'a str);
 pub struct User {
     pub id: String,
     pub password: String,
+    pub role: Role,
+}
+
+/// What a user may do, each role allows what the lower ones do.
+#[derive(Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Role {
+    Trader,
+    /// Reads the counters of the server and swaps its deserializer.
+    Operator,
+    Admin,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::Trader
+    }
 }
 
 impl<'a> std::convert::AsRef<[u8]> for UserKey<'a> {
@@ -38,3 +55,201 @@ pub struct BlackListed {
 }
 
 derive_key_of!(BlackListedKey<'_>, BlackListed, "BlackListed", 1);
+
+/// Key of a refresh token, its SHA-256 digest so the stored keys can't be
+/// exchanged.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct RefreshTokenKey(Vec<u8>);
+
+impl RefreshTokenKey {
+    pub fn new(token: &str) -> Self {
+        RefreshTokenKey(
+            digest::digest(&digest::SHA256, token.as_bytes())
+                .as_ref()
+                .to_vec(),
+        )
+    }
+}
+
+impl std::convert::AsRef<[u8]> for RefreshTokenKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Refresh token of a family, it can be exchanged once.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct RefreshToken {
+    pub family: String,
+    pub exp: u64,
+    pub used: bool,
+}
+
+derive_key_of!(RefreshTokenKey, RefreshToken, "RefreshToken", 21);
+
+/// Key of a token family, the session id of the login that started it.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct TokenFamilyKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for TokenFamilyKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Tokens issued from one login. Revoking it ends the session.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct TokenFamily {
+    pub user_id: String,
+    pub ip: String,
+    pub cancel_on_disconnect: bool,
+    pub revoked: bool,
+    /// Expiry of its last refresh token.
+    pub exp: u64,
+    /// Token generation of the user when it started.
+    pub generation: u64,
+    pub role: Role,
+    /// Whether the login passed a second factor.
+    pub two_factor: bool,
+}
+
+derive_key_of!(TokenFamilyKey<'_>, TokenFamily, "TokenFamily", 22);
+
+/// Key of the token generation of a user, their id.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct TokenGenerationKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for TokenGenerationKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Bumped to log a user out of every session, tokens of an older
+/// generation are refused.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct TokenGeneration {
+    pub generation: u64,
+}
+
+derive_key_of!(
+    TokenGenerationKey<'_>,
+    TokenGeneration,
+    "TokenGeneration",
+    23
+);
+
+/// Key of the failed logins of a user or from an IP.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct LoginAttemptsKey(Vec<u8>);
+
+impl LoginAttemptsKey {
+    pub fn user(id: &str) -> Self {
+        LoginAttemptsKey([&b"user\0"[..], id.as_bytes()].concat())
+    }
+
+    pub fn ip(ip: &str) -> Self {
+        LoginAttemptsKey([&b"ip\0"[..], ip.as_bytes()].concat())
+    }
+}
+
+impl std::convert::AsRef<[u8]> for LoginAttemptsKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Failed logins since the last lockout. Times are seconds since the epoch.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct LoginAttempts {
+    pub failures: u32,
+    /// No login is tried before it, to back off.
+    pub next_attempt: u64,
+    pub locked_until: u64,
+    /// When the failures are forgotten.
+    pub exp: u64,
+}
+
+impl LoginAttempts {
+    /// Seconds until another login can be tried, none when it can now.
+    pub fn retry_after(&self, now: u64) -> Option<u64> {
+        let until = self.next_attempt.max(self.locked_until);
+        if until > now {
+            Some(until - now)
+        } else {
+            None
+        }
+    }
+}
+
+derive_key_of!(LoginAttemptsKey, LoginAttempts, "LoginAttempts", 26);
+
+/// Key of the pending password reset of a user, their id.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct PasswordResetKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for PasswordResetKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// One-time token issued by an admin to set a password without the
+/// current one. Only its SHA-256 digest is kept.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct PasswordReset {
+    pub token_hash: Vec<u8>,
+    /// Seconds since the epoch.
+    pub exp: u64,
+}
+
+derive_key_of!(PasswordResetKey<'_>, PasswordReset, "PasswordReset", 27);
+
+/// Key of the second factor of a user, their id.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct TwoFactorKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for TwoFactorKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// TOTP secret of a user, asked for on login once a code made from it is
+/// confirmed.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct TwoFactor {
+    pub secret: Vec<u8>,
+    pub enabled: bool,
+    /// SHA-256 digests of the unused recovery codes.
+    pub recovery_codes: Vec<Vec<u8>>,
+    /// Step of the last code accepted, codes can't be used twice.
+    pub last_step: u64,
+}
+
+derive_key_of!(TwoFactorKey<'_>, TwoFactor, "TwoFactor", 28);
+
+/// Key of a login waiting for its second factor, a random token.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct LoginChallengeKey<'a>(pub &'a str);
+
+impl<'a> std::convert::AsRef<[u8]> for LoginChallengeKey<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Login whose password was right, the session starts once a code of the
+/// user is given.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct LoginChallenge {
+    pub user_id: String,
+    pub ip: String,
+    pub cancel_on_disconnect: bool,
+    pub role: Role,
+    pub failures: u32,
+    /// Seconds since the epoch.
+    pub exp: u64,
+}
+
+derive_key_of!(LoginChallengeKey<'_>, LoginChallenge, "LoginChallenge", 29);
@@ -1,22 +1,30 @@
 use warp::Filter;
 use serde::Deserialize;
+use std::time;
 
-const F64_TO_U64_FACTOR: f64 = 10_000.0;
-#[allow(dead_code)]
-pub fn f64_to_u64(price: f64) -> u64 {
-    (price * F64_TO_U64_FACTOR) as u64
+/// Converts a decimal price into an integer with `scale` decimal places.
+pub fn f64_to_u64(price: f64, scale: u32) -> u64 {
+    (price * 10u64.pow(scale) as f64) as u64
 }
 #[allow(dead_code)]
-pub fn f64_to_i64(price: f64) -> i64 {
-    (price * F64_TO_U64_FACTOR) as i64
+pub fn f64_to_i64(price: f64, scale: u32) -> i64 {
+    (price * 10u64.pow(scale) as f64) as i64
 }
-#[allow(dead_code)]
-pub fn u64_to_f64(price: u64) -> f64 {
-    price as f64 / F64_TO_U64_FACTOR
+/// Converts an integer with `scale` decimal places back into a decimal.
+pub fn u64_to_f64(price: u64, scale: u32) -> f64 {
+    price as f64 / 10u64.pow(scale) as f64
 }
 #[allow(dead_code)]
-pub fn i64_to_f64(price: i64) -> f64 {
-    price as f64 / F64_TO_U64_FACTOR
+pub fn i64_to_f64(price: i64, scale: u32) -> f64 {
+    price as f64 / 10u64.pow(scale) as f64
+}
+
+pub fn now_in_secs() -> u64 {
+    let start = time::SystemTime::now();
+    start
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
 }
 
 /// Returns the mantissa, exponent and sign as integers.